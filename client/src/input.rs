@@ -0,0 +1,322 @@
+use std::str::FromStr;
+
+use chess::{Board, Color, Square};
+use regex::Regex;
+
+use common::{Message, Command, Chat, ChatChannel, SearchQuery};
+use common::chess_utils::parse_move;
+use common::pgn::legal_moves_san;
+
+lazy_static! {
+    static ref LONG_SAN_MOVE_RE: Regex = Regex::new(r"^[a-h][1-8][a-h][1-8][qrbn]?$").unwrap();
+    static ref SAN_MOVE_RE: Regex = Regex::new(
+        r"(?x)
+        ^(
+            (
+                ([RNBQK])?                # Optional piece indicator (Rook, kNight, Bishop, Queen, King)
+                ([a-h1-8])?               # Optional file or rank specifier for disambiguation
+                (x)?                      # Optional capture indicator
+                ([a-h][1-8])              # Destination square
+                (=[RNBQ])?                # Optional promotion indicator
+                ([+\#])?                  # Optional check/checkmate indicator, `#` has to be escaped in verbose mode
+            )
+            | ([O0]-[O0](-[O0])?[+\#]?)  # Castling (Kingside or Queenside)
+        )$").unwrap();
+}
+
+// Offered by tab completion
+pub const COMMANDS: &[&str] = &["/help", "/log in ", "/play", "/stats", "/concede", "/claim win", "/claim draw", "/moves ",
+    "/msg ", "/chat ", "/search ", "/kick ", "/ban ", "/unban ", "/mute ", "/unmute ", "/end ", "/quit"];
+
+pub const HELP: &str = "Available commands:
+`/help` - see this message
+`/log in %username%` - attempt to log in with your username (without percent symbols)
+`/play` - start a chess game
+`/stats` - view your statistics
+`/concede` - give up on the game (your opponent wins)
+`/claim win` or `/claim draw` - finish the game if your opponent has disconnected and did not come back in time
+`/kick`, `/ban`, `/unban`, `/mute`, `/unmute`, `/end` followed by a username - moderation, `/ban` and `/mute` take an optional number of minutes
+`:` - start your message with a semicolon to send a chat message to your opponent
+`/msg %username% %text%` - send a direct message
+`/chat [lobby|game|%username%]` - chat mode: every line goes to that channel (the lobby by default), `/chat` again to leave
+`/moves [square]` - list your legal moves, all of them or those of the piece on a square like `e2`
+`/search [player=..] [opponent=..] [result=win|loss|draw|1-0|..] [from=YYYY-MM-DD] [to=..] [eco=C6] [variant=..] [tc=..] [page=2] [pgn]` - find finished games
+`/quit` - leave, as do Esc and Ctrl+C
+`e2e4` or `e4` - send your chess move in long algebraic notation or SAN. `O-O` or `O-O-O` for castle.
+Tab completes moves and commands, Up and Down go through what you typed before, PageUp and PageDown scroll the chat.";
+
+// What a line typed by the user turns into
+pub enum Input {
+    Send(Message),
+    Feedback(String), // shown to the user, nothing is sent
+    Nothing,
+    Quit,
+}
+
+// `chat_mode` is updated by `/chat`, while set plain lines are chat messages.
+// Moves are checked against `board`, the game in progress if any, and against `side` once the server has told us which one is ours.
+pub fn parse_line(line: &str, chat_mode: &mut Option<ChatChannel>, board: Option<&Board>, side: Option<Color>) -> Input {
+    let trimmed = line.trim();
+
+    if trimmed.starts_with("/help") {
+        return Input::Feedback(HELP.to_string());
+    }
+    if trimmed.starts_with("/quit") {
+        return Input::Quit;
+    }
+    if trimmed.starts_with("/moves") {
+        return Input::Feedback(list_moves(trimmed.split_whitespace().nth(1), board));
+    }
+
+    let message = if trimmed.starts_with("/") {
+        if trimmed.starts_with("/log") {
+            let parts: Vec<&str> = trimmed.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Input::Feedback("Please log in with you username like this: /log in your_username.".to_string());
+            }
+            let username = parts[parts.len()-1];
+            Message::Command(Command::LogIn(username.to_string()))
+        } else if trimmed.starts_with("/play") {
+            Message::Command(Command::Play)
+        } else if trimmed.starts_with("/stat") {
+            Message::Command(Command::Stats)
+        } else if trimmed.starts_with("/concede") {
+            Message::Command(Command::Concede)
+        } else if trimmed.starts_with("/msg") {
+            let parts: Vec<&str> = trimmed.splitn(3, ' ').collect();
+            if parts.len() != 3 || parts[2].trim().is_empty() {
+                return Input::Feedback("Please send a direct message like this: /msg username your message.".to_string());
+            }
+            chat_message(ChatChannel::Direct(parts[1].to_string()), parts[2].trim())
+        } else if trimmed.starts_with("/chat") {
+            *chat_mode = match (trimmed.split_whitespace().nth(1), &*chat_mode) {
+                (None, Some(_)) => None,
+                (None, None) | (Some("lobby"), _) => Some(ChatChannel::Lobby),
+                (Some("game"), _) => Some(ChatChannel::Game),
+                (Some(username), _) => Some(ChatChannel::Direct(username.to_string())),
+            };
+            return Input::Feedback(if chat_mode.is_none() {
+                "Left chat mode, moves are accepted again.".to_string()
+            } else {
+                "Chat mode: everything you type is sent as a message, commands still start with `/`. Type /chat to leave.".to_string()
+            });
+        } else if trimmed.starts_with("/claim") {
+            match trimmed.split_whitespace().nth(1) {
+                Some("win") => Message::Command(Command::ClaimWin),
+                Some("draw") => Message::Command(Command::ClaimDraw),
+                _ => return Input::Feedback("Please claim either a win or a draw: /claim win, /claim draw.".to_string()),
+            }
+        } else if trimmed.starts_with("/search") {
+            match parse_search(trimmed) {
+                Ok(query) => Message::Command(Command::Search(query)),
+                Err(usage) => return Input::Feedback(usage),
+            }
+        } else if let Some(command) = parse_moderation_command(trimmed) {
+            match command {
+                Ok(command) => Message::Command(command),
+                Err(usage) => return Input::Feedback(usage),
+            }
+        } else {
+            return Input::Feedback("Unrecognized command. Please use /help to see the list of available commands.".to_string());
+        }
+    } else if let Some(channel) = chat_mode {
+        if trimmed.is_empty() {
+            return Input::Nothing;
+        }
+        chat_message(channel.clone(), trimmed)
+    } else if let Some(text) = trimmed.strip_prefix(":") {
+        chat_message(ChatChannel::Game, text)
+    } else if let Some(board) = board {
+        if side.is_some_and(|side| side != board.side_to_move()) {
+            return Input::Feedback("It is not your turn.".to_string());
+        }
+        // Always sent in the coordinate notation the server understands
+        match parse_move(board, trimmed) {
+            Ok(mov) => Message::Move(mov.to_string()),
+            Err(_) => return Input::Feedback(format!("{} is not a legal move here, /moves lists them.", trimmed)),
+        }
+    } else if LONG_SAN_MOVE_RE.is_match(trimmed) || SAN_MOVE_RE.is_match(trimmed) {
+        return Input::Feedback("You are not in a game, use /play to start one.".to_string());
+    } else {
+        return Input::Feedback("Please enter a valid chess move in algebraic notation, e.g. `e2e4`".to_string());
+    };
+
+    Input::Send(message)
+}
+
+// `/moves e2` -> `e2: e3, e4`
+pub fn list_moves(square: Option<&str>, board: Option<&Board>) -> String {
+    let board = match board {
+        Some(board) => board,
+        None => return "You are not in a game, use /play to start one.".to_string(),
+    };
+    let from = match square.map(Square::from_str) {
+        Some(Ok(square)) => Some(square),
+        Some(Err(_)) => return "Usage: /moves [square], e.g. /moves e2".to_string(),
+        None => None,
+    };
+
+    let moves: Vec<String> = legal_moves_san(board).into_iter()
+        .filter(|(mov, _)| from.is_none_or(|from| mov.get_source() == from))
+        .map(|(_, san)| san)
+        .collect();
+    let label = from.map_or("Legal moves".to_string(), |from| from.to_string());
+    if moves.is_empty() {
+        format!("{}: no legal moves", label)
+    } else {
+        format!("{}: {}", label, moves.join(", "))
+    }
+}
+
+// `/ban Hoare 30` -> `Ban("Hoare", Some(30))`, None if it isn't a moderation command at all
+fn parse_moderation_command(line: &str) -> Option<Result<Command, String>> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let name = parts[0];
+    let takes_minutes = name == "/ban" || name == "/mute";
+    if !["/kick", "/ban", "/unban", "/mute", "/unmute", "/end"].contains(&name) {
+        return None;
+    }
+
+    let usage = if takes_minutes { format!("Usage: {} username [minutes]", name) } else { format!("Usage: {} username", name) };
+    let username = match parts.get(1) {
+        Some(username) if parts.len() <= if takes_minutes { 3 } else { 2 } => username.to_string(),
+        _ => return Some(Err(usage)),
+    };
+    let minutes = match parts.get(2).map(|minutes| minutes.parse::<u64>()) {
+        Some(Ok(minutes)) => Some(minutes),
+        Some(Err(_)) => return Some(Err(usage)),
+        None => None,
+    };
+
+    Some(Ok(match name {
+        "/kick" => Command::Kick(username),
+        "/ban" => Command::Ban(username, minutes),
+        "/unban" => Command::Unban(username),
+        "/mute" => Command::Mute(username, minutes),
+        "/unmute" => Command::Unmute(username),
+        _ => Command::EndGame(username),
+    }))
+}
+
+// `/search player=Hoare result=win page=2 pgn`, pages count from 1 here and from 0 on the wire
+fn parse_search(line: &str) -> Result<SearchQuery, String> {
+    let usage = || "Usage: /search [player=..] [opponent=..] [result=..] [from=YYYY-MM-DD] [to=YYYY-MM-DD] [eco=..] [variant=..] [tc=..] [page=..] [pgn]".to_string();
+    let mut query = SearchQuery::default();
+    for part in line.split_whitespace().skip(1) {
+        if part == "pgn" {
+            query.pgn = true;
+            continue;
+        }
+        let (key, value) = part.split_once('=').ok_or_else(usage)?;
+        let value = value.to_string();
+        match key {
+            "player" => query.player = Some(value),
+            "opponent" => query.opponent = Some(value),
+            "result" => query.result = Some(value),
+            "from" => query.from = Some(value),
+            "to" => query.to = Some(value),
+            "eco" => query.eco = Some(value),
+            "variant" => query.variant = Some(value),
+            "tc" => query.time_control = Some(value),
+            "page" => query.page = value.parse::<u32>().ok().filter(|&page| page > 0).ok_or_else(usage)? - 1,
+            _ => return Err(usage()),
+        }
+    }
+    Ok(query)
+}
+
+// The server fills in who sent it and when
+fn chat_message(channel: ChatChannel, text: &str) -> Message {
+    Message::Chat(Chat { channel, sender: String::new(), text: text.to_string(), sent_at: 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str, chat_mode: &mut Option<ChatChannel>) -> Input {
+        parse_line(line, chat_mode, None, None)
+    }
+
+    fn sent(input: Input) -> Message {
+        match input {
+            Input::Send(message) => message,
+            Input::Feedback(text) => panic!("expected a message, got feedback {}", text),
+            Input::Nothing | Input::Quit => panic!("expected a message"),
+        }
+    }
+
+    fn chat_to(message: Message) -> (ChatChannel, String) {
+        match message {
+            Message::Chat(chat) => (chat.channel, chat.text),
+            other => panic!("expected a chat message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chat_mode_turns_lines_into_messages() {
+        let mut chat_mode = None;
+        assert!(matches!(parse("/chat", &mut chat_mode), Input::Feedback(_)));
+        assert_eq!(chat_mode, Some(ChatChannel::Lobby));
+        assert_eq!(chat_to(sent(parse("e4 is best", &mut chat_mode))), (ChatChannel::Lobby, "e4 is best".to_string()));
+        assert!(matches!(parse("  ", &mut chat_mode), Input::Nothing));
+        // Commands still work
+        assert!(matches!(sent(parse("/play", &mut chat_mode)), Message::Command(Command::Play)));
+
+        parse("/chat bob", &mut chat_mode);
+        assert_eq!(chat_mode, Some(ChatChannel::Direct("bob".to_string())));
+        parse("/chat", &mut chat_mode);
+        assert_eq!(chat_mode, None);
+    }
+
+    #[test]
+    fn game_chat_and_direct_messages() {
+        let mut chat_mode = None;
+        assert_eq!(chat_to(sent(parse(":good luck", &mut chat_mode))), (ChatChannel::Game, "good luck".to_string()));
+        assert_eq!(chat_to(sent(parse("/msg bob see you", &mut chat_mode))), (ChatChannel::Direct("bob".to_string()), "see you".to_string()));
+        assert!(matches!(parse("/msg bob", &mut chat_mode), Input::Feedback(_)));
+    }
+
+    #[test]
+    fn commands() {
+        let mut chat_mode = None;
+        assert!(matches!(sent(parse("/log in alice", &mut chat_mode)), Message::Command(Command::LogIn(name)) if name == "alice"));
+        assert!(matches!(parse("/log in", &mut chat_mode), Input::Feedback(_)));
+        assert!(matches!(sent(parse("/claim draw", &mut chat_mode)), Message::Command(Command::ClaimDraw)));
+        assert!(matches!(parse("/claim victory", &mut chat_mode), Input::Feedback(_)));
+        assert!(matches!(parse("/quit", &mut chat_mode), Input::Quit));
+        assert!(matches!(parse("/dance", &mut chat_mode), Input::Feedback(_)));
+    }
+
+    #[test]
+    fn moves_need_a_game() {
+        let mut chat_mode = None;
+        assert!(matches!(parse("e2e4", &mut chat_mode), Input::Feedback(text) if text.contains("/play")));
+        assert!(matches!(parse("Nf3", &mut chat_mode), Input::Feedback(text) if text.contains("/play")));
+        assert!(matches!(parse("hello", &mut chat_mode), Input::Feedback(text) if text.contains("valid chess move")));
+    }
+
+    #[test]
+    fn moves_are_checked_and_sent_in_coordinates() {
+        let board = Board::default();
+        let mut chat_mode = None;
+        let sent_move = |line: &str, chat_mode: &mut Option<ChatChannel>| match parse_line(line, chat_mode, Some(&board), Some(Color::White)) {
+            Input::Send(Message::Move(mov)) => mov,
+            _ => panic!("expected {} to be sent as a move", line),
+        };
+        assert_eq!(sent_move("Nf3", &mut chat_mode), "g1f3");
+        assert_eq!(sent_move("e2e4", &mut chat_mode), "e2e4");
+        assert!(matches!(parse_line("e5", &mut chat_mode, Some(&board), Some(Color::White)), Input::Feedback(text) if text.contains("/moves")));
+        assert!(matches!(parse_line("e4", &mut chat_mode, Some(&board), Some(Color::Black)), Input::Feedback(text) if text == "It is not your turn."));
+    }
+
+    #[test]
+    fn lists_moves() {
+        let board = Board::default();
+        assert_eq!(list_moves(Some("g1"), Some(&board)), "g1: Nf3, Nh3");
+        assert_eq!(list_moves(Some("e1"), Some(&board)), "e1: no legal moves");
+        assert!(list_moves(None, Some(&board)).starts_with("Legal moves: Na3, Nc3, Nf3"));
+        assert!(list_moves(Some("z9"), Some(&board)).starts_with("Usage"));
+        assert!(list_moves(None, None).contains("/play"));
+    }
+}
@@ -2,210 +2,235 @@
 extern crate lazy_static;
 extern crate regex;
 
-use std::io::{self, Write};
-
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::io::AsyncWriteExt;
+mod input;
+mod offline;
+mod replay;
+mod script;
+mod tui;
+
+use std::sync::Arc;
+
+use chess::Color;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::pki_types::ServerName;
 use log::{info, error};
-use regex::Regex;
-
-use common::{Message, Command, DEFAULT_HOST, DEFAULT_PORT, ChessError, listen_to_messages};
-use common::chess_utils::{print_board, board_from_string};
-
-lazy_static! {
-    static ref LONG_SAN_MOVE_RE: Regex = Regex::new(r"[a-h][1-8][a-h][1-8]").unwrap();
-    static ref SAN_MOVE_RE: Regex = Regex::new(
-        r"(?x)
-        (
-            ([RNBQK])?                    # Optional piece indicator (Rook, kNight, Bishop, Queen, King)
-            ([a-h1-8])?                   # Optional file or rank specifier for disambiguation
-            (x)?                          # Optional capture indicator
-            ([a-h][1-8])                  # Destination square
-            (=[RNBQ])?                    # Optional promotion indicator
-            ([+#])?                       # Optional check/checkmate indicator
-        )
-        | (O-O(-O)?)                     # Castling (Kingside or Queenside)
-        ").unwrap();
-}
 
-struct GameState {
-    //my_username: String,
-    //my_elo: u32, // not used for now
-    //in_game: bool, // not used for now
-    //my_turn: bool, // not used for now
-    opponent_username: String,
-}
+use common::{Message, Envelope, Capability, Hello, Welcome, DEFAULT_HOST, DEFAULT_HTTP_PORT, DEFAULT_PORT, PROTOCOL_VERSION, ChessError, make_io_error};
+use common::chess_utils::Glyphs;
+use common::codec::{Codec, FrameReader, FrameWriter};
 
-impl GameState {
-    fn new() -> Self {
-        Self {
-            //my_username: "".to_string(),
-            //in_game: false,
-            //my_turn: false,
-            opponent_username: "opponent".to_string(),
-        }
-    }
-}
+// Plain TCP or TLS
+type ServerReader = FrameReader<Box<dyn AsyncRead + Send + Unpin>>;
+type ServerWriter = FrameWriter<Box<dyn AsyncWrite + Send + Unpin>>;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let args: Vec<String> = std::env::args().collect();
-    let host = args.get(1).unwrap_or(&DEFAULT_HOST.to_string()).to_string();
-    let port = args.get(2).unwrap_or(&DEFAULT_PORT.to_string()).to_string();
-
-    start_client(&host, &port).await;
-}
-
-async fn start_client(host: &str, port: &str) {
-    match tokio::net::TcpStream::connect(format!("{}:{}", host, port)).await {
-        Ok(stream) => {
-            info!("Successfully connected to server in port {}", port);
-
-            let game_state = GameState::new();
-
-            let (mut reader, mut writer) = stream.into_split();
-            let read_task = tokio::spawn(async move {
-                listen_to_server_messages(&mut reader, &game_state).await;
-            });
+    // Positional host and port, plus `--tls`, `--ca-cert <path>` to trust a self-signed CA and `--ascii` for letters instead of chess symbols.
+    // `--script <file>` (`-` for stdin) runs without the UI, see `script::run`.
+    // `--offline` plays on this machine without a server, `--engine <white|black>` against the built-in engine playing that side.
+    // `--replay <file|game id>` steps through a PGN file or a game fetched from the server's HTTP API on `--http-port`
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut tls = false;
+    let mut glyphs = if unicode_locale() { Glyphs::Unicode } else { Glyphs::Ascii };
+    let mut ca_cert = None;
+    let mut script = None;
+    let mut offline = false;
+    let mut engine_side = None;
+    let mut replay = None;
+    let mut http_port = DEFAULT_HTTP_PORT.to_string();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls" => tls = true,
+            "--ascii" => glyphs = Glyphs::Ascii,
+            "--ca-cert" => {
+                tls = true;
+                ca_cert = args.next();
+            }
+            "--script" => script = args.next(),
+            "--offline" => offline = true,
+            "--replay" => replay = args.next(),
+            "--http-port" => http_port = args.next().unwrap_or(http_port),
+            "--engine" => {
+                offline = true;
+                engine_side = match args.next().as_deref() {
+                    Some("white") => Some(Color::White),
+                    Some("black") => Some(Color::Black),
+                    _ => {
+                        eprintln!("Usage: client --engine white|black");
+                        std::process::exit(script::EXIT_FAILED);
+                    }
+                };
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if offline {
+        offline::run(engine_side, glyphs);
+        return;
+    }
 
-            let write_task = tokio::spawn(async move {
-                let _  = get_input(&mut writer).await;
-            });
+    let host = positional.get(0).unwrap_or(&DEFAULT_HOST.to_string()).to_string();
+    let port = positional.get(1).unwrap_or(&DEFAULT_PORT.to_string()).to_string();
 
-            tokio::try_join!(read_task, write_task).unwrap();
-        }
-        Err(e) => {
-            error!("Failed to connect: {}", e);
+    if let Some(source) = replay {
+        if let Err(e) = replay::run(&source, &host, &http_port, glyphs).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+        return;
     }
-}
 
-async fn listen_to_server_messages(reader: &mut OwnedReadHalf, game_state: &GameState) {
-    loop {
-        match listen_to_messages(reader).await {
-            Ok(message) => process_message(message, game_state).await,
+    let tls_connector = if tls {
+        match tls_connector(ca_cert.as_deref()) {
+            Ok(tls_connector) => Some(tls_connector),
             Err(e) => {
-                error!("Error while listening to messages: {}", e);
-                break;
+                error!("Failed to set up TLS: {}", e);
+                std::process::exit(script::EXIT_FAILED);
             }
         }
-    }
+    } else {
+        None
+    };
+
+    let code = start_client(&host, &port, tls_connector, glyphs, script.as_deref()).await;
+    std::process::exit(code);
 }
 
-async fn get_input(writer: &mut OwnedWriteHalf) -> Result<(), ChessError> {
-    println!("Please enter your command, chat message, or chess move.");
-    
-    loop {
-        print!("> ");
-        if let Err(e) = std::io::stdout().flush() {
-            error!("Failed to flush stdout: {}", e);
-            continue;
-        }
+// The first locale variable that is set decides, like in the C library; unset means the "C" locale, which is ASCII
+fn unicode_locale() -> bool {
+    ["LC_ALL", "LC_CTYPE", "LANG"].iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+        .is_some_and(|locale| {
+            let locale = locale.to_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        })
+}
 
-        let mut line = String::new();
+fn tls_connector(ca_cert: Option<&str>) -> Result<TlsConnector, ChessError> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
 
-        if let Err(e) = std::io::stdin().read_line(&mut line) {
-            error!("Failed to read line: {}", e);
-            continue;
+    if let Some(ca_path) = ca_cert {
+        let file = std::fs::File::open(ca_path)
+            .map_err(|e| make_io_error(e, &format!("Failed to open CA certificate {}", ca_path)))?;
+        for cert in rustls_pemfile::certs(&mut std::io::BufReader::new(file)) {
+            let cert = cert.map_err(|e| make_io_error(e, &format!("Failed to parse CA certificate {}", ca_path)))?;
+            roots.add(cert).map_err(|e| ChessError::TlsError(e.to_string()))?;
         }
+    }
 
-        let trimmed = line.trim();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
 
-        if trimmed.starts_with("/help") {
-            println!("Available commands: \n`/help` - see this message \n`/log in %username%` - attempt to log in with your username (without percent symbols) \n`/play` - start a chess game \n`/stats` - view your statistics \n`/concede` - give up on the game (your opponent wins) \n`:` - start your message with a semicolon to send a chat message to your opponent\n`e2e4` - send your chess move in long algebraic notation. `O-O` or `O-O-O` for castle.");          
-            continue;
+async fn connect(host: &str, port: &str, tls_connector: Option<TlsConnector>) -> Result<(ServerReader, ServerWriter), ChessError> {
+    let stream = TcpStream::connect(format!("{}:{}", host, port)).await
+        .map_err(|e| make_io_error(e, "Failed to connect"))?;
+
+    let (reader, writer): (Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) = match tls_connector {
+        Some(tls_connector) => {
+            let server_name = ServerName::try_from(host.to_string())
+                .map_err(|e| ChessError::TlsError(e.to_string()))?;
+            let stream = tls_connector.connect(server_name, stream).await
+                .map_err(|e| ChessError::TlsError(format!("TLS handshake failed: {}", e)))?;
+            let (reader, writer) = tokio::io::split(stream);
+            (Box::new(reader), Box::new(writer))
+        }
+        None => {
+            let (reader, writer) = stream.into_split();
+            (Box::new(reader), Box::new(writer))
         }
+    };
+
+    Ok((FrameReader::new(reader, Codec::Cbor), FrameWriter::new(writer, Codec::Cbor)))
+}
 
-        let message = if trimmed.starts_with("/") {
-            if trimmed.starts_with("/log") {
-                let parts: Vec<&str> = trimmed.split_whitespace().collect();
-                if parts.len() != 3 {
-                    println!("Please log in with you username like this: /log in your_username.");
-                    continue;
+// The exit code, only meaningful with a script
+async fn start_client(host: &str, port: &str, tls_connector: Option<TlsConnector>, glyphs: Glyphs, script: Option<&str>) -> i32 {
+    match connect(host, port, tls_connector).await {
+        Ok((mut reader, mut writer)) => {
+            info!("Successfully connected to server in port {}", port);
+
+            match handshake(&mut reader, &mut writer).await {
+                Ok(welcome) => info!("Connected to server version {}, protocol version {}", welcome.server_version, welcome.protocol_version),
+                Err(e) => {
+                    error!("Handshake failed: {}", e);
+                    return script::EXIT_FAILED;
                 }
-                let username = parts[parts.len()-1];
-                Message::Command(Command::LogIn(username.to_string())) 
-            } else if trimmed.starts_with("/play") {
-                Message::Command(Command::Play)
-            } else if trimmed.starts_with("/stat") {
-                Message::Command(Command::Stats)
-            } else if trimmed.starts_with("/concede") {
-                Message::Command(Command::Concede)
-            } else {
-                println!("Unrecognized command. Please use /help to see the list of available commands.");
-                continue;
             }
 
-        } else if trimmed.starts_with(":") {
-            Message::Text(trimmed[1..].to_string())
-        } else if LONG_SAN_MOVE_RE.is_match(trimmed) || SAN_MOVE_RE.is_match(trimmed) {
-            Message::Move(trimmed.to_string())
-        } else {
-            println!("Please enter a valid chess move in algebraic notation, e.g. `e2e4`");
-            continue;
-        };
+            // Shared between the UI and the reader, which answers heartbeats
+            let writer = Arc::new(Mutex::new(writer));
+            let pong_writer = Arc::clone(&writer);
+            let (envelope_sender, envelope_receiver) = mpsc::channel(100);
 
-        match send_message(writer, &message).await {
-            Ok(()) => info!("Message {:?} sent successfully!", message),
-            Err(e) => {
-                error!("Failed to send message: {}", e);
-                return Err(e);
-            },
+            let read_task = tokio::spawn(async move {
+                listen_to_server_messages(&mut reader, pong_writer, envelope_sender).await;
+            });
+
+            let code = match script {
+                Some(source) => script::run(source, envelope_receiver, writer).await,
+                None => match tui::run(envelope_receiver, writer, glyphs).await {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        error!("{}", e);
+                        script::EXIT_FAILED
+                    }
+                },
+            };
+            read_task.abort();
+            code
+        }
+        Err(e) => {
+            error!("Failed to connect: {}", e);
+            script::EXIT_FAILED
         }
-        
     }
 }
 
-
-
-async fn process_message(message: Message, game_state: &GameState) {
-    match message {
-        Message::Command(_) => panic!("Expected Board, Text, Log, received Command"),
-        Message::Move(_) => panic!("Expected Board, Text, Log, received Move"),
-        Message::Text(text) => display_chat_message(text, game_state),
-        Message::Board(board_string) => display_board(board_string),
-        Message::Error(e) => display_error_message(e),
-        Message::Log(message) => display_log_message(message),
+async fn handshake(reader: &mut ServerReader, writer: &mut ServerWriter) -> Result<Welcome, ChessError> {
+    let hello = Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: env!("CARGO_PKG_NAME").to_string(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: vec![Capability::Heartbeat],
+    };
+    writer.write(&Message::Hello(hello)).await?;
+
+    match reader.read().await? {
+        Message::Welcome(welcome) => Ok(welcome),
+        Message::Error(e) => {
+            // stderr, stdout is the JSON-lines stream in script mode
+            eprintln!("[SERVER ERROR] {e}");
+            Err(ChessError::ProtocolError(e))
+        }
+        other => Err(ChessError::ProtocolError(format!("Expected Welcome, received {:?}", other))),
     }
 }
 
-async fn send_message(writer: &mut OwnedWriteHalf, message: &Message) -> Result<(), ChessError> {
-    let serialized_message = serde_cbor::to_vec(message)
-        .map_err(|e| ChessError::IoError { 
-            main: io::Error::new(io::ErrorKind::Other, e),
-            context: "Failed to serialize message".to_string(),
-        })?;
-    let len = serialized_message.len() as u32;
-    let len_bytes = len.to_be_bytes();
-
-    writer.write_all(&len_bytes).await.map_err(|e| ChessError::IoError {
-        main: e,
-        context: "Failed to send message length".to_string(),
-    })?;
-    writer.write_all(&serialized_message).await.map_err(|e| ChessError::IoError {
-        main: e,
-        context: "Failed to send message".to_string(),
-    })?;
-
-    Ok(())
-}
-
-
-fn display_board(board_string: String) {
-    print_board(&board_from_string(board_string).expect("Unexpected board format."));
-}
-
-fn display_log_message(message: String) {
-    println!("[SERVER] {message}");
-}
-
-fn display_error_message(message: String) {
-    println!("[SERVER ERROR] {message}");
+// Everything but heartbeats goes to the UI, a read error is passed on as well and ends the loop
+async fn listen_to_server_messages(reader: &mut ServerReader, writer: Arc<Mutex<ServerWriter>>, envelopes: mpsc::Sender<Result<Envelope, ChessError>>) {
+    loop {
+        let envelope = reader.read().await;
+        let failed = envelope.is_err();
+        if let Ok(Envelope::Ping(timestamp)) = envelope {
+            if let Err(e) = writer.lock().await.write(&Envelope::Pong(timestamp)).await {
+                let _ = envelopes.send(Err(e)).await;
+                break;
+            }
+            continue;
+        }
+        if envelopes.send(envelope).await.is_err() || failed {
+            break;
+        }
+    }
 }
-
-fn display_chat_message(message: String, game_state: &GameState) {
-    let opponent = &game_state.opponent_username;
-    println!("[{opponent}]: {message}");
-}
\ No newline at end of file
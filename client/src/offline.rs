@@ -0,0 +1,345 @@
+use std::io::{self, BufRead, Write};
+
+use chess::{Board, ChessMove, Color, GameResult};
+
+use common::make_io_error;
+use common::chess_utils::{BoardView, Glyphs, parse_move, print_board};
+use common::engine::Engine;
+use common::pgn::{move_to_san, parse_pgn, write_pgn};
+use common::rules::{board_result, end_reason, pgn_result, result_from_pgn};
+
+use crate::input::list_moves;
+
+const HELP: &str = "Offline commands:
+`/help` - see this message
+`e2e4` or `e4` - play a move in long algebraic notation or SAN
+`/moves [square]` - list the legal moves, all of them or those of the piece on a square like `e2`
+`/undo` - take back the last move, against the engine your last move and its reply
+`/concede` - the side to move gives up, against the engine that's you
+`/draw` - agree to a draw, two player games only
+`/save %file%` - write the game as PGN
+`/load %file%` - continue a game from a PGN file
+`/new` - start over
+`/quit` - leave";
+
+// A game on this machine: two players at one keyboard, or one against the built-in engine
+struct Offline {
+    boards: Vec<Board>, // every position so far, the starting one first
+    moves: Vec<ChessMove>,
+    result: Option<GameResult>,
+    engine: Option<(Color, Engine)>, // the side the engine plays
+    glyphs: Glyphs,
+}
+
+pub fn run(engine_side: Option<Color>, glyphs: Glyphs) {
+    let mut game = Offline::new(engine_side, glyphs);
+    println!("Offline game, /help lists the commands.");
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        if game.result.is_none() && game.engine_to_move() {
+            game.play_engine_move();
+            continue;
+        }
+        game.show();
+
+        print!("{}> ", if game.board().side_to_move() == Color::White { "White" } else { "Black" });
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if trimmed.starts_with("/quit") {
+            break;
+        }
+        if let Some(feedback) = game.command(trimmed) {
+            println!("{}", feedback);
+        }
+    }
+}
+
+impl Offline {
+    fn new(engine_side: Option<Color>, glyphs: Glyphs) -> Self {
+        Self {
+            boards: vec![Board::default()],
+            moves: Vec::new(),
+            result: None,
+            engine: engine_side.map(|side| (side, Engine::default())),
+            glyphs,
+        }
+    }
+
+    fn board(&self) -> Board {
+        *self.boards.last().expect("the starting position is never removed")
+    }
+
+    fn engine_to_move(&self) -> bool {
+        self.engine.as_ref().is_some_and(|(side, _)| *side == self.board().side_to_move())
+    }
+
+    // Feedback for the user, if any
+    fn command(&mut self, line: &str) -> Option<String> {
+        let argument = line.split_once(' ').map(|(_, argument)| argument.trim());
+        if line.starts_with("/help") {
+            Some(HELP.to_string())
+        } else if line.starts_with("/moves") {
+            Some(list_moves(argument, Some(&self.board())))
+        } else if line.starts_with("/undo") {
+            Some(self.undo())
+        } else if line.starts_with("/concede") {
+            if self.result.is_some() {
+                return Some("The game is over, /new starts another one.".to_string());
+            }
+            self.result = Some(if self.board().side_to_move() == Color::White { GameResult::WhiteResigns } else { GameResult::BlackResigns });
+            None
+        } else if line.starts_with("/draw") {
+            if self.engine.is_some() {
+                return Some("The engine plays on.".to_string());
+            }
+            if self.result.is_some() {
+                return Some("The game is over, /new starts another one.".to_string());
+            }
+            self.result = Some(GameResult::DrawDeclared);
+            None
+        } else if line.starts_with("/save") {
+            Some(match argument {
+                Some(path) => self.save(path),
+                None => "Usage: /save game.pgn".to_string(),
+            })
+        } else if line.starts_with("/load") {
+            Some(match argument {
+                Some(path) => self.load(path),
+                None => "Usage: /load game.pgn".to_string(),
+            })
+        } else if line.starts_with("/new") {
+            self.boards.truncate(1);
+            self.moves.clear();
+            self.result = None;
+            None
+        } else if line.starts_with('/') {
+            Some("Unrecognized command. Please use /help to see the list of available commands.".to_string())
+        } else if self.result.is_some() {
+            Some("The game is over, /new starts another one or /undo takes moves back.".to_string())
+        } else {
+            match parse_move(&self.board(), line) {
+                Ok(mov) => {
+                    self.play(mov);
+                    None
+                }
+                Err(_) => Some(format!("{} is not a legal move here, /moves lists them.", line)),
+            }
+        }
+    }
+
+    // Same rules as online games: the board decides checkmate and stalemate
+    fn play(&mut self, mov: ChessMove) {
+        let board = self.board().make_move_new(mov);
+        self.boards.push(board);
+        self.moves.push(mov);
+        self.result = board_result(&board);
+    }
+
+    fn play_engine_move(&mut self) {
+        let board = self.board();
+        let Some(mov) = self.engine.as_ref().and_then(|(_, engine)| engine.best_move(&board)) else {
+            return;
+        };
+        println!("The engine plays {}.", move_to_san(&board, mov));
+        self.play(mov);
+    }
+
+    fn undo(&mut self) -> String {
+        // A resignation or a draw is taken back on its own, the board hasn't changed
+        if self.result.is_some() && board_result(&self.board()).is_none() {
+            self.result = None;
+            return "The game goes on.".to_string();
+        }
+        if self.moves.is_empty() {
+            return "There is nothing to take back.".to_string();
+        }
+        self.boards.pop();
+        self.moves.pop();
+        // Back to a position where it's the player's move, not the engine's
+        while self.engine_to_move() && !self.moves.is_empty() {
+            self.boards.pop();
+            self.moves.pop();
+        }
+        self.result = None;
+        "Move taken back.".to_string()
+    }
+
+    fn save(&self, path: &str) -> String {
+        let player = |color: Color| match &self.engine {
+            Some((side, _)) if *side == color => "Engine".to_string(),
+            _ => if color == Color::White { "White".to_string() } else { "Black".to_string() },
+        };
+        let tags = [
+            ("Event", "chess-rs offline game".to_string()),
+            ("Site", "chess-rs".to_string()),
+            ("Date", "????.??.??".to_string()),
+            ("Round", "-".to_string()),
+            ("White", player(Color::White)),
+            ("Black", player(Color::Black)),
+            ("Result", pgn_result(self.result).to_string()),
+        ];
+        let moves: Vec<String> = self.moves.iter().map(|mov| mov.to_string()).collect();
+        let saved = write_pgn(&tags, &moves, pgn_result(self.result))
+            .and_then(|pgn| std::fs::write(path, pgn).map_err(|e| make_io_error(e, &format!("Failed to write {}", path))));
+        match saved {
+            Ok(()) => format!("Saved to {}.", path),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn load(&mut self, path: &str) -> String {
+        let games = std::fs::read_to_string(path)
+            .map_err(|e| make_io_error(e, &format!("Failed to read {}", path)))
+            .and_then(|pgn| parse_pgn(&pgn));
+        let game = match games {
+            Ok(mut games) if !games.is_empty() => games.remove(0),
+            Ok(_) => return format!("There is no game in {}.", path),
+            Err(e) => return e.to_string(),
+        };
+        // Saving replays the moves from the starting position
+        if game.start != Board::default() {
+            return "Only games from the starting position can be continued, use --replay to look at this one.".to_string();
+        }
+        self.boards.truncate(1);
+        self.moves.clear();
+        self.result = None;
+        for pgn_move in game.moves {
+            self.play(pgn_move.mov);
+        }
+        // A resignation or a draw isn't on the board, the file says so
+        self.result = result_from_pgn(&game.result, &self.board());
+        format!("Loaded {} moves from {}.", self.moves.len(), path)
+    }
+
+    fn show(&self) {
+        let board = self.board();
+        // From the player's side against the engine, from the side to move otherwise
+        let perspective = match &self.engine {
+            Some((side, _)) => !*side,
+            None => board.side_to_move(),
+        };
+        let view = BoardView { perspective, last_move: self.moves.last().copied(), glyphs: self.glyphs };
+        println!();
+        print_board(&board, &view);
+
+        if !self.moves.is_empty() {
+            let mut movetext = Vec::new();
+            for (i, (mov, before)) in self.moves.iter().zip(&self.boards).enumerate() {
+                if i % 2 == 0 {
+                    movetext.push(format!("{}.", i / 2 + 1));
+                }
+                movetext.push(move_to_san(before, *mov));
+            }
+            println!("{}", movetext.join(" "));
+        }
+
+        match self.result {
+            Some(result) => println!("Game over: {} by {}. /new, /undo, /save or /quit.", pgn_result(Some(result)), end_reason(Some(result))),
+            None if board.checkers().popcnt() > 0 => println!("Check!"),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotseat() -> Offline {
+        Offline::new(None, Glyphs::Ascii)
+    }
+
+    fn play(game: &mut Offline, moves: &[&str]) {
+        for mov in moves {
+            assert_eq!(game.command(mov), None, "{} should be legal", mov);
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir().join(format!("chess-offline-test-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn undo_takes_back_one_move() {
+        let mut game = hotseat();
+        play(&mut game, &["e4", "e5"]);
+        game.command("/undo");
+        assert_eq!(game.moves.len(), 1);
+        assert_eq!(game.board().side_to_move(), Color::Black);
+        game.command("/undo");
+        assert_eq!(game.board(), Board::default());
+        assert_eq!(game.command("/undo").as_deref(), Some("There is nothing to take back."));
+    }
+
+    #[test]
+    fn undo_against_the_engine_takes_back_its_reply_too() {
+        let mut game = Offline::new(Some(Color::Black), Glyphs::Ascii);
+        play(&mut game, &["e4"]);
+        assert!(game.engine_to_move());
+        game.play_engine_move();
+        assert_eq!(game.moves.len(), 2);
+
+        game.command("/undo");
+        assert!(game.moves.is_empty());
+        assert!(!game.engine_to_move());
+        assert_eq!(game.command("/draw").as_deref(), Some("The engine plays on."));
+    }
+
+    #[test]
+    fn resignations_are_taken_back_on_their_own() {
+        let mut game = hotseat();
+        play(&mut game, &["e4"]);
+        game.command("/concede");
+        assert_eq!(game.result, Some(GameResult::BlackResigns));
+        assert!(game.command("e5").is_some(), "no moves after the game is over");
+
+        assert_eq!(game.command("/undo").as_deref(), Some("The game goes on."));
+        assert_eq!(game.result, None);
+        assert_eq!(game.moves.len(), 1);
+    }
+
+    #[test]
+    fn checkmate_ends_the_game() {
+        let mut game = hotseat();
+        play(&mut game, &["f3", "e5", "g4", "Qh4"]);
+        assert_eq!(game.result, Some(GameResult::BlackCheckmates));
+        game.command("/undo");
+        assert_eq!(game.result, None);
+        assert_eq!(game.moves.len(), 3);
+    }
+
+    #[test]
+    fn saved_games_load_with_their_result() {
+        let path = temp_file("saved.pgn");
+        let mut game = hotseat();
+        play(&mut game, &["e4", "e5", "Nf3"]);
+        game.command("/concede");
+        assert_eq!(game.command(&format!("/save {}", path)), Some(format!("Saved to {}.", path)));
+
+        let mut loaded = hotseat();
+        assert_eq!(loaded.command(&format!("/load {}", path)), Some(format!("Loaded 3 moves from {}.", path)));
+        assert_eq!(loaded.moves, game.moves);
+        assert_eq!(loaded.board(), game.board());
+        assert_eq!(loaded.result, Some(GameResult::BlackResigns));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn only_games_from_the_start_load() {
+        let path = temp_file("fen.pgn");
+        std::fs::write(&path, "[FEN \"4k3/8/8/8/8/8/8/4K2R w K - 0 1\"]\n[SetUp \"1\"]\n\nO-O *\n").unwrap();
+        let mut game = hotseat();
+        assert!(game.command(&format!("/load {}", path)).unwrap().contains("--replay"));
+        assert!(game.moves.is_empty());
+        assert!(game.command("/load no-such-file.pgn").unwrap().contains("no-such-file.pgn"));
+        let _ = std::fs::remove_file(path);
+    }
+}
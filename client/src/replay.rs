@@ -0,0 +1,157 @@
+use std::io::{self, Write};
+
+use chess::Color;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use common::{ChessError, make_io_error};
+use common::chess_utils::{BoardView, Glyphs, print_board};
+use common::pgn::{PgnGame, PgnMove, nag_symbol, parse_pgn};
+
+const HELP: &str = "Replay commands:
+Enter or `n` - next move, `p` - previous move
+`s` - start, `e` - end
+`12` - jump to move 12, after White's move
+`f` - flip the board
+`g 2` - go to the second game in the file
+`q` - leave";
+
+// A PGN file, or the id of a game on the server whose PGN is fetched from the HTTP API
+pub async fn run(source: &str, host: &str, http_port: &str, glyphs: Glyphs) -> Result<(), ChessError> {
+    let pgn = match (std::path::Path::new(source).exists(), source.parse::<u32>()) {
+        (false, Ok(game_id)) => fetch_pgn(host, http_port, game_id).await?,
+        _ => tokio::fs::read_to_string(source).await.map_err(|e| make_io_error(e, &format!("Failed to read {}", source)))?,
+    };
+    let games = parse_pgn(&pgn)?;
+    if games.is_empty() {
+        return Err(ChessError::GameStateError(format!("There is no game in {}", source)));
+    }
+
+    let mut game_index = 0;
+    let mut ply = 0; // moves played on the board shown
+    let mut perspective = Color::White;
+    // Tokio's stdin, a blocking read here would hold up the runtime thread
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let game = &games[game_index];
+        show(game, ply, BoardView { perspective, last_move: ply.checked_sub(1).map(|i| game.moves[i].mov), glyphs });
+        if games.len() > 1 {
+            println!("Game {} of {}", game_index + 1, games.len());
+        }
+
+        print!("> ");
+        let _ = io::stdout().flush();
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            _ => return Ok(()),
+        };
+        match line.trim() {
+            "" | "n" => ply = (ply + 1).min(game.moves.len()),
+            "p" => ply = ply.saturating_sub(1),
+            "s" => ply = 0,
+            "e" => ply = game.moves.len(),
+            "f" => perspective = !perspective,
+            "q" => return Ok(()),
+            "h" | "/help" => println!("{}", HELP),
+            other => {
+                if let Some(number) = other.strip_prefix('g').and_then(|number| number.trim().parse::<usize>().ok()) {
+                    if (1..=games.len()).contains(&number) {
+                        game_index = number - 1;
+                        ply = 0;
+                    } else {
+                        println!("There are {} games.", games.len());
+                    }
+                } else if let Ok(number) = other.parse::<usize>() {
+                    // Move `number` by White is ply `2 * number - 1` counted from a game that starts with White to move
+                    let offset = if game.start.side_to_move() == Color::Black { 1 } else { 0 };
+                    ply = (2 * number.max(1) - 1).saturating_sub(offset).min(game.moves.len());
+                } else {
+                    println!("{}", HELP);
+                }
+            }
+        }
+    }
+}
+
+fn show(game: &PgnGame, ply: usize, view: BoardView) {
+    let boards = game.boards();
+    println!();
+    println!("{} - {}  {}  {}",
+        game.tag("White").unwrap_or("?"), game.tag("Black").unwrap_or("?"), game.result, game.tag("Event").unwrap_or(""));
+    print_board(&boards[ply], &view);
+
+    let first_ply = if game.start.side_to_move() == Color::Black { 1 } else { 0 };
+    match ply.checked_sub(1).map(|i| &game.moves[i]) {
+        Some(last) => {
+            println!("{}", move_text(last, first_ply + ply - 1, true));
+            if let Some(comment) = &last.comment {
+                println!("{}", comment);
+            }
+            for variation in &last.variations {
+                println!("Instead: {}", line_text(variation, first_ply + ply - 1));
+            }
+        }
+        None => {
+            if let Some(comment) = &game.comment {
+                println!("{}", comment);
+            }
+        }
+    }
+    if ply == game.moves.len() {
+        println!("End of the game: {}", game.result);
+    } else {
+        println!("Move {} of {}", ply, game.moves.len());
+    }
+}
+
+// `12. e4!` or `12... e5`, `number` forces the move number on Black's moves too
+fn move_text(pgn_move: &PgnMove, ply: usize, number: bool) -> String {
+    let mut text = match (ply % 2, number) {
+        (0, _) => format!("{}. ", ply / 2 + 1),
+        (_, true) => format!("{}... ", ply / 2 + 1),
+        _ => String::new(),
+    };
+    text.push_str(&pgn_move.san);
+    for &nag in &pgn_move.nags {
+        match nag_symbol(nag) {
+            Some(symbol) => text.push_str(symbol),
+            None => text.push_str(&format!(" ${}", nag)),
+        }
+    }
+    text
+}
+
+// A variation in PGN style, with the variations inside it in parentheses
+fn line_text(moves: &[PgnMove], first_ply: usize) -> String {
+    let mut parts = Vec::new();
+    for (i, pgn_move) in moves.iter().enumerate() {
+        parts.push(move_text(pgn_move, first_ply + i, i == 0));
+        if let Some(comment) = &pgn_move.comment {
+            parts.push(format!("{{{}}}", comment));
+        }
+        for variation in &pgn_move.variations {
+            parts.push(format!("({})", line_text(variation, first_ply + i)));
+        }
+    }
+    parts.join(" ")
+}
+
+// `GET /api/games/<id>/pgn`, over HTTP/1.0 so the body comes whole rather than chunked
+async fn fetch_pgn(host: &str, http_port: &str, game_id: u32) -> Result<String, ChessError> {
+    let mut stream = TcpStream::connect(format!("{}:{}", host, http_port)).await
+        .map_err(|e| make_io_error(e, "Failed to connect to the HTTP API"))?;
+    let request = format!("GET /api/games/{}/pgn HTTP/1.0\r\nHost: {}\r\n\r\n", game_id, host);
+    stream.write_all(request.as_bytes()).await
+        .map_err(|e| make_io_error(e, "Failed to send the HTTP request"))?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await
+        .map_err(|e| make_io_error(e, "Failed to read the HTTP response"))?;
+
+    let (head, body) = response.split_once("\r\n\r\n")
+        .ok_or(ChessError::NetworkError("Malformed HTTP response".to_string()))?;
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(ChessError::NetworkError(format!("Game {}: {} {}", game_id, status, body.trim())));
+    }
+    Ok(body.to_string())
+}
@@ -0,0 +1,247 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chess::{Board, Color};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+use log::info;
+
+use common::{ChatChannel, ChessError, Command, Envelope, GameEnd, Message, RequestId};
+use common::chess_utils::board_from_string;
+
+use crate::ServerWriter;
+use crate::input::{Input, parse_line};
+
+// Exit codes, so shell scripts can tell how it went
+pub const EXIT_DONE: i32 = 0; // the script ran out of lines before the game ended
+pub const EXIT_FAILED: i32 = 1; // couldn't connect, or the connection was lost
+pub const EXIT_WIN: i32 = 10;
+pub const EXIT_LOSS: i32 = 11;
+pub const EXIT_DRAW: i32 = 12;
+pub const EXIT_ABORTED: i32 = 13;
+pub const EXIT_UNKNOWN_SIDE: i32 = 14; // decisive result or `/wait turn`, but the server never said which side we played
+pub const EXIT_TIMEOUT: i32 = 15; // a `/wait game|turn|end` took longer than its timeout
+
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10 * 60); // for `/wait game|turn|end` without one of their own
+
+// What the script is waiting for before its next line
+enum Wait {
+    Game, // `/wait game`: a game has started
+    Turn, // `/wait turn`: it's our move
+    End, // `/wait end`: the game is over, the exit code tells how
+    Until(Instant), // `/wait <seconds>`
+}
+
+struct Script {
+    username: Option<String>, // from the last `/log in`
+    side: Option<Color>,
+    board: Option<Board>,
+    in_game: bool,
+    chat_mode: Option<ChatChannel>,
+    wait: Option<Wait>,
+    timeout: Option<Instant>, // when a `/wait game|turn|end` gives up
+    next_request_id: RequestId,
+}
+
+// Lines from `source` (a file, or stdin for `-`) are what the user would type in the terminal UI, plus the `/wait` directives above.
+// Every server message goes to stdout as one JSON line, feedback on the lines goes to stderr.
+pub async fn run(source: &str, mut envelopes: mpsc::Receiver<Result<Envelope, ChessError>>, writer: Arc<Mutex<ServerWriter>>) -> i32 {
+    let input: Box<dyn AsyncBufRead + Unpin + Send> = if source == "-" {
+        Box::new(BufReader::new(tokio::io::stdin()))
+    } else {
+        match tokio::fs::File::open(source).await {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", source, e);
+                return EXIT_FAILED;
+            }
+        }
+    };
+    let mut lines = input.lines();
+    let mut script = Script { username: None, side: None, board: None, in_game: false, chat_mode: None, wait: None, timeout: None, next_request_id: 1 };
+    let mut script_done = false;
+
+    loop {
+        let deadline = match script.wait {
+            Some(Wait::Until(deadline)) => Some(deadline),
+            Some(_) => script.timeout,
+            None => None,
+        };
+        let ready = !script_done && match script.wait {
+            Some(Wait::Until(deadline)) => deadline <= Instant::now(),
+            Some(_) => false,
+            None => true,
+        };
+
+        tokio::select! {
+            envelope = envelopes.recv() => match envelope {
+                Some(Ok(envelope)) => {
+                    print_json(&envelope);
+                    if let Some(code) = script.on_envelope(envelope) {
+                        return code;
+                    }
+                }
+                Some(Err(e)) => {
+                    eprintln!("Disconnected from the server: {}", e);
+                    return EXIT_FAILED;
+                }
+                None => return EXIT_FAILED,
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() && !ready => {
+                if !matches!(script.wait, Some(Wait::Until(_))) {
+                    eprintln!("Timed out waiting");
+                    return EXIT_TIMEOUT;
+                }
+            }
+            line = lines.next_line(), if ready => match line {
+                Ok(Some(line)) => {
+                    script.wait = None;
+                    match script.on_line(&line) {
+                        Input::Send(message) => {
+                            let request = Envelope::Request { id: script.next_request_id, message };
+                            script.next_request_id += 1;
+                            if let Err(e) = writer.lock().await.write(&request).await {
+                                eprintln!("Failed to send message: {}", e);
+                                return EXIT_FAILED;
+                            }
+                            info!("Request {:?} sent successfully!", request);
+                        }
+                        Input::Quit => return EXIT_DONE,
+                        Input::Feedback(_) | Input::Nothing => {}
+                    }
+                    if script.turn_unknowable() {
+                        return EXIT_UNKNOWN_SIDE;
+                    }
+                }
+                Ok(None) => script_done = true,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", source, e);
+                    return EXIT_FAILED;
+                }
+            },
+        }
+
+        if script_done && script.wait.is_none() {
+            return EXIT_DONE;
+        }
+    }
+}
+
+impl Script {
+    // The exit code once the game is over
+    fn on_envelope(&mut self, envelope: Envelope) -> Option<i32> {
+        let message = match envelope {
+            Envelope::Response { message, .. } | Envelope::Event(message) => message,
+            _ => return None,
+        };
+        match message {
+            Message::GameStart(game_start) => {
+                self.side = match &self.username {
+                    Some(username) if *username == game_start.black => Some(Color::Black),
+                    Some(username) if *username == game_start.white => Some(Color::White),
+                    _ => None,
+                };
+                self.in_game = true;
+                if matches!(self.wait, Some(Wait::Game)) {
+                    self.wait = None;
+                }
+            }
+            Message::Board(board_string) => {
+                self.board = board_from_string(board_string).ok();
+                if self.turn_unknowable() {
+                    return Some(EXIT_UNKNOWN_SIDE);
+                }
+                let our_turn = self.in_game && self.board.is_some_and(|board| Some(board.side_to_move()) == self.side);
+                if our_turn && matches!(self.wait, Some(Wait::Turn)) {
+                    self.wait = None;
+                }
+            }
+            Message::GameEnd(game_end) => return Some(self.exit_code(&game_end)),
+            _ => {}
+        }
+        None
+    }
+
+    // Waiting for our turn in a game whose side we weren't told, e.g. by a server without `GameStart`: it would never come
+    fn turn_unknowable(&self) -> bool {
+        let unknowable = matches!(self.wait, Some(Wait::Turn)) && self.side.is_none() && self.board.is_some();
+        if unknowable {
+            eprintln!("Can't wait for our turn, the server didn't say which side we play");
+        }
+        unknowable
+    }
+
+    fn exit_code(&self, game_end: &GameEnd) -> i32 {
+        match (game_end.result.as_str(), self.side) {
+            ("1/2-1/2", _) => EXIT_DRAW,
+            ("1-0", Some(Color::White)) | ("0-1", Some(Color::Black)) => EXIT_WIN,
+            ("1-0", Some(Color::Black)) | ("0-1", Some(Color::White)) => EXIT_LOSS,
+            ("1-0", None) | ("0-1", None) => EXIT_UNKNOWN_SIDE,
+            _ => EXIT_ABORTED,
+        }
+    }
+
+    // Only `Send` and `Quit` are left for the caller, feedback is printed here
+    fn on_line(&mut self, line: &str) -> Input {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Input::Nothing;
+        }
+        if let Some(what) = trimmed.strip_prefix("/wait") {
+            let words: Vec<&str> = what.split_whitespace().collect();
+            let seconds = |word: &str| word.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+            let wait = match words[..] {
+                ["game", ..] => Some(Wait::Game),
+                ["turn", ..] => Some(Wait::Turn),
+                ["end", ..] => Some(Wait::End),
+                [word] => seconds(word).map(|seconds| Wait::Until(Instant::now() + seconds)),
+                _ => None,
+            };
+            let timeout = match words.get(1) {
+                Some(word) => seconds(word),
+                None => Some(WAIT_TIMEOUT),
+            };
+            match (wait, timeout) {
+                (Some(wait), Some(timeout)) if words.len() <= 2 => {
+                    self.wait = Some(wait);
+                    self.timeout = Some(Instant::now() + timeout);
+                }
+                _ => eprintln!("Usage: /wait game|turn|end [timeout seconds] or /wait <seconds>"),
+            }
+            // Already there, e.g. the board arrived before the `/wait turn` was read
+            if (matches!(self.wait, Some(Wait::Game)) && self.in_game)
+                || (matches!(self.wait, Some(Wait::Turn)) && self.in_game && self.board.is_some_and(|board| Some(board.side_to_move()) == self.side)) {
+                self.wait = None;
+            }
+            return Input::Nothing;
+        }
+
+        let board = self.board.filter(|_| self.in_game);
+        match parse_line(trimmed, &mut self.chat_mode, board.as_ref(), self.side) {
+            Input::Send(message) => {
+                if let Message::Command(Command::LogIn(username)) = &message {
+                    self.username = Some(username.clone());
+                }
+                Input::Send(message)
+            }
+            Input::Feedback(text) => {
+                eprintln!("{}: {}", trimmed, text);
+                Input::Nothing
+            }
+            other => other,
+        }
+    }
+}
+
+fn print_json(envelope: &Envelope) {
+    match serde_json::to_string(envelope) {
+        Ok(json) => {
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{}", json);
+            let _ = stdout.flush();
+        }
+        Err(e) => eprintln!("Failed to serialize {:?}: {}", envelope, e),
+    }
+}
@@ -0,0 +1,689 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chess::{Board, BoardStatus, Color, MoveGen};
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color as TermColor, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use tokio::sync::{mpsc, Mutex};
+use log::{info, warn};
+
+use common::{Chat, ChatChannel, ChessError, Command, Envelope, ErrorCode, GameStart, Message, RequestId, SearchResults, civil_date, make_io_error};
+use common::chess_utils::{BoardView, Glyphs, Highlight, board_from_string, is_light_square};
+use common::pgn::{legal_moves_san, move_to_san};
+
+use crate::ServerWriter;
+use crate::input::{COMMANDS, Input, parse_line};
+
+const BOARD_WIDTH: u16 = 2 + 8 * 3 + 2; // rank labels, three columns per square, borders
+const BOARD_HEIGHT: u16 = 8 + 1 + 2; // ranks, file labels, borders
+const MOVES_WIDTH: u16 = 20;
+const LIGHT_SQUARE: TermColor = TermColor::Rgb(240, 217, 181);
+const DARK_SQUARE: TermColor = TermColor::Rgb(181, 136, 99);
+const LAST_MOVE_SQUARE: TermColor = TermColor::Rgb(205, 210, 106);
+const CHECK_SQUARE: TermColor = TermColor::Rgb(220, 70, 60);
+
+// A line in the chat pane
+enum Entry {
+    Chat(Chat),
+    Text(String, String), // sender and text, from servers before `Message::Chat` it's always the opponent
+    Log(String),
+    Error(String),
+    Local(String), // typed lines and client feedback, never sent by the server
+}
+
+pub struct App {
+    username: Option<String>, // from the last `/log in`
+    opponent: Option<String>,
+    connected: bool,
+    board: Option<Board>,
+    view: BoardView, // our side at the bottom, once the server tells us which one it is
+    moves: Vec<String>, // SAN, worked out by comparing consecutive boards
+    joined_midgame: bool, // the moves before the first board we got are unknown
+    clocks: [Duration; 2], // time each side has spent on its moves, measured here since the server has no clocks
+    turn_started: Option<Instant>, // None while no game is running
+    entries: Vec<Entry>,
+    scroll: usize, // lines scrolled up from the bottom of the chat pane
+    status: String,
+    input: String,
+    cursor: usize, // in chars, not bytes
+    history: Vec<String>,
+    history_index: Option<usize>, // while going through the history with Up and Down
+    completions: Vec<String>, // while pressing Tab, empty otherwise
+    completion_index: usize,
+    chat_mode: Option<ChatChannel>, // while set, plain lines are chat messages
+    next_request_id: RequestId,
+    quit: bool,
+}
+
+// Full screen until the user quits; redrawn on every server message, key press and once a second for the clocks
+pub async fn run(mut envelopes: mpsc::Receiver<Result<Envelope, ChessError>>, writer: Arc<Mutex<ServerWriter>>, glyphs: Glyphs) -> Result<(), ChessError> {
+    let mut terminal = ratatui::try_init()
+        .map_err(|e| make_io_error(e, "Failed to set up the terminal"))?;
+    let result = event_loop(&mut terminal, &mut envelopes, &writer, glyphs).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(terminal: &mut DefaultTerminal, envelopes: &mut mpsc::Receiver<Result<Envelope, ChessError>>, writer: &Arc<Mutex<ServerWriter>>, glyphs: Glyphs) -> Result<(), ChessError> {
+    let mut app = App::new(glyphs);
+    let mut terminal_events = spawn_terminal_events();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+
+    while !app.quit {
+        terminal.draw(|frame| app.draw(frame))
+            .map_err(|e| make_io_error(e, "Failed to draw"))?;
+
+        tokio::select! {
+            Some(envelope) = envelopes.recv() => app.on_envelope(envelope),
+            Some(event) = terminal_events.recv() => {
+                if let Some(message) = app.on_terminal_event(event) {
+                    let request = Envelope::Request { id: app.next_request_id, message };
+                    app.next_request_id += 1;
+                    match writer.lock().await.write(&request).await {
+                        Ok(()) => info!("Request {:?} sent successfully!", request),
+                        Err(e) => app.push(Entry::Error(format!("Failed to send message: {}", e))),
+                    }
+                }
+            }
+            _ = tick.tick() => {}
+        }
+    }
+    Ok(())
+}
+
+// crossterm only has a blocking reader without its `event-stream` feature, so it gets a thread of its own
+fn spawn_terminal_events() -> mpsc::Receiver<Event> {
+    let (sender, receiver) = mpsc::channel(64);
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if sender.blocking_send(event).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+impl App {
+    fn new(glyphs: Glyphs) -> Self {
+        Self {
+            username: None,
+            opponent: None,
+            connected: true,
+            board: None,
+            view: BoardView { glyphs, ..BoardView::default() },
+            moves: Vec::new(),
+            joined_midgame: false,
+            clocks: [Duration::ZERO; 2],
+            turn_started: None,
+            entries: vec![Entry::Local("Please enter your command, chat message, or chess move. /help lists the commands.".to_string())],
+            scroll: 0,
+            status: "Connected".to_string(),
+            input: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_index: None,
+            completions: Vec::new(),
+            completion_index: 0,
+            chat_mode: None,
+            next_request_id: 1,
+            quit: false,
+        }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        if let Entry::Log(text) | Entry::Error(text) = &entry {
+            self.status = text.clone();
+        }
+        self.entries.push(entry);
+    }
+
+    fn on_envelope(&mut self, envelope: Result<Envelope, ChessError>) {
+        match envelope {
+            Ok(Envelope::Response { message, .. }) | Ok(Envelope::Event(message)) => self.on_message(message),
+            Ok(Envelope::Ack { id, error: None, .. }) => info!("Request {id} succeeded"),
+            // Mutes are already explained by the server's own message
+            Ok(Envelope::Ack { id, error: Some(e), code: Some(ErrorCode::RateLimited) }) => {
+                info!("Request {id} failed: {e}");
+                self.push(Entry::Error("You are sending too fast, that was dropped.".to_string()));
+            }
+            Ok(Envelope::Ack { id, error: Some(e), code: Some(ErrorCode::Flooding) }) => {
+                info!("Request {id} failed: {e}");
+                self.push(Entry::Error("The server is closing the connection for flooding.".to_string()));
+            }
+            Ok(Envelope::Ack { id, error: Some(e), .. }) => info!("Request {id} failed: {e}"),
+            Ok(Envelope::Request { .. }) => warn!("Expected Response, Ack, Event or Ping, received Request"),
+            Ok(Envelope::Pong(_)) => warn!("Expected Response, Ack, Event or Ping, received Pong"),
+            Ok(Envelope::Ping(_)) => {} // answered by the reader
+            Err(e) => {
+                self.connected = false;
+                self.turn_started = None;
+                self.push(Entry::Error(format!("Disconnected from the server: {}. Press Esc to quit.", e)));
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: Message) {
+        match message {
+            Message::Command(_) => warn!("Expected Board, Text, Log, received Command"),
+            Message::Move(_) => warn!("Expected Board, Text, Log, received Move"),
+            Message::Text(text) => {
+                let opponent = self.opponent.clone().unwrap_or_else(|| "opponent".to_string());
+                self.push(Entry::Text(opponent, text));
+            }
+            Message::Chat(chat) => self.push(Entry::Chat(chat)),
+            Message::Board(board_string) => match board_from_string(board_string) {
+                Ok(board) => self.update_board(board),
+                Err(e) => self.push(Entry::Error(format!("Unexpected board format: {}", e))),
+            },
+            Message::GameStart(game_start) => self.start_game(game_start),
+            Message::GameEnd(_) => self.stop_clock(), // the `Log` that comes with it tells the user
+            Message::SearchResults(results) => self.show_search_results(results),
+            Message::OpponentGone(_) => {} // the `Log` that comes with it tells the user
+            Message::Error(e) => self.push(Entry::Error(e)),
+            Message::Log(text) => {
+                // Servers before `GameEnd` only announce it here
+                if text.starts_with("Game is finished") || text.starts_with("The game was aborted") || text.starts_with("Your game was ended") {
+                    self.stop_clock();
+                }
+                self.push(Entry::Log(text));
+            }
+            Message::Hello(_) => warn!("Expected Board, Text, Log, received Hello"),
+            Message::Welcome(_) => warn!("Expected Board, Text, Log, received Welcome"),
+        }
+    }
+
+    fn show_search_results(&mut self, results: SearchResults) {
+        let pages = results.total.div_ceil(results.page_size.max(1) as usize).max(1);
+        self.push(Entry::Local(format!("{} games found, page {} of {}", results.total, results.page + 1, pages)));
+        for game in results.games {
+            self.push(Entry::Local(format!("#{} {} - {} {} by {}, {}, {} {}, {} moves",
+                game.game_id, game.white, game.black, game.result, game.reason, civil_date(game.finished_at), game.eco, game.opening, game.moves.div_ceil(2))));
+            if let Some(pgn) = game.pgn {
+                self.push(Entry::Local(pgn));
+            }
+        }
+    }
+
+    fn start_game(&mut self, game_start: GameStart) {
+        let playing_black = self.username.as_ref() == Some(&game_start.black);
+        self.view.perspective = if playing_black { Color::Black } else { Color::White };
+        self.opponent = Some(if playing_black { game_start.white } else { game_start.black });
+        self.view.last_move = None;
+        self.board = None; // the board that follows is a new game, not a move
+    }
+
+    // The server only sends positions, the move is the legal one that leads from the previous board to this one
+    fn update_board(&mut self, board: Board) {
+        let played = self.board.and_then(|previous| MoveGen::new_legal(&previous)
+            .find(|&mov| previous.make_move_new(mov) == board)
+            .map(|mov| (previous, mov)));
+
+        match played {
+            Some((previous, mov)) => {
+                self.moves.push(move_to_san(&previous, mov));
+                self.view.last_move = Some(mov);
+                self.stop_clock();
+                if board.status() == BoardStatus::Ongoing {
+                    self.turn_started = Some(Instant::now());
+                }
+            }
+            None if self.board == Some(board) => {} // sent again, e.g. after reconnecting
+            None => {
+                // A new game, or one we came back to
+                self.moves.clear();
+                self.view.last_move = None;
+                self.joined_midgame = board != Board::default();
+                self.clocks = [Duration::ZERO; 2];
+                self.turn_started = Some(Instant::now());
+            }
+        }
+        self.board = Some(board);
+    }
+
+    fn stop_clock(&mut self) {
+        if let (Some(started), Some(board)) = (self.turn_started.take(), self.board) {
+            self.clocks[board.side_to_move().to_index()] += started.elapsed();
+        }
+    }
+
+    // The board of a game that is still being played
+    fn live_board(&self) -> Option<Board> {
+        self.board.filter(|_| self.turn_started.is_some())
+    }
+
+    fn time_used(&self, color: Color) -> Duration {
+        let running = match (self.turn_started, self.board) {
+            (Some(started), Some(board)) if board.side_to_move() == color => started.elapsed(),
+            _ => Duration::ZERO,
+        };
+        self.clocks[color.to_index()] + running
+    }
+
+    fn on_terminal_event(&mut self, event: Event) -> Option<Message> {
+        match event {
+            Event::Key(key) => self.on_key(key),
+            _ => None, // resizes are picked up by the next draw
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Option<Message> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        if key.code != KeyCode::Tab {
+            self.completions.clear();
+        }
+
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Tab => self.complete(),
+            KeyCode::Char(c) => {
+                let index = self.byte_index();
+                self.input.insert(index, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let index = self.byte_index();
+                self.input.remove(index);
+            }
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                let index = self.byte_index();
+                self.input.remove(index);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Up => self.history_up(),
+            KeyCode::Down => self.history_down(),
+            KeyCode::PageUp => self.scroll += 5,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(5),
+            _ => {}
+        }
+        None
+    }
+
+    fn submit(&mut self) -> Option<Message> {
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.history_index = None;
+        self.scroll = 0;
+        if line.trim().is_empty() {
+            return None;
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        self.push(Entry::Local(format!("> {}", line)));
+
+        let board = self.live_board();
+        let side = self.opponent.is_some().then_some(self.view.perspective);
+        match parse_line(&line, &mut self.chat_mode, board.as_ref(), side) {
+            Input::Send(message) => {
+                if !self.connected {
+                    self.push(Entry::Error("Not connected to the server.".to_string()));
+                    return None;
+                }
+                if let Message::Command(Command::LogIn(username)) = &message {
+                    self.username = Some(username.clone());
+                }
+                Some(message)
+            }
+            Input::Feedback(text) => {
+                for line in text.lines() {
+                    self.push(Entry::Local(line.to_string()));
+                }
+                None
+            }
+            Input::Nothing => None,
+            Input::Quit => {
+                self.quit = true;
+                None
+            }
+        }
+    }
+
+    // The first Tab fills in the first match and lists all of them in the status line, the next ones cycle through them
+    fn complete(&mut self) {
+        if self.completions.is_empty() {
+            self.completions = self.completion_candidates(self.input.trim_start());
+            self.completion_index = 0;
+            if self.completions.is_empty() {
+                return;
+            }
+            if self.completions.len() > 1 {
+                self.status = self.completions.join(" ");
+            }
+        } else {
+            self.completion_index = (self.completion_index + 1) % self.completions.len();
+        }
+        self.input = self.completions[self.completion_index].clone();
+        self.cursor = self.input.chars().count();
+    }
+
+    // Commands after a `/`, otherwise the legal moves in SAN; nothing in chat
+    fn completion_candidates(&self, prefix: &str) -> Vec<String> {
+        if prefix.starts_with('/') {
+            return COMMANDS.iter().filter(|command| command.starts_with(prefix)).map(|command| command.to_string()).collect();
+        }
+        match self.live_board() {
+            Some(board) if self.chat_mode.is_none() && !prefix.starts_with(':') => legal_moves_san(&board).into_iter()
+                .map(|(_, san)| san)
+                .filter(|san| san.starts_with(prefix))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn history_up(&mut self) {
+        let index = match self.history_index {
+            Some(index) => index.saturating_sub(1),
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        self.recall(Some(index));
+    }
+
+    fn history_down(&mut self) {
+        match self.history_index {
+            Some(index) if index + 1 < self.history.len() => self.recall(Some(index + 1)),
+            Some(_) => self.recall(None),
+            None => {}
+        }
+    }
+
+    // None goes back to an empty line below the history
+    fn recall(&mut self, index: Option<usize>) {
+        self.history_index = index;
+        self.input = index.map(|index| self.history[index].clone()).unwrap_or_default();
+        self.cursor = self.input.chars().count();
+    }
+
+    fn byte_index(&self) -> usize {
+        self.input.char_indices().nth(self.cursor).map_or(self.input.len(), |(index, _)| index)
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, status, input] = Layout::vertical([Constraint::Min(0), Constraint::Length(1), Constraint::Length(3)]).areas(frame.area());
+        let [left, moves, chat] = Layout::horizontal([Constraint::Length(BOARD_WIDTH), Constraint::Length(MOVES_WIDTH), Constraint::Min(20)]).areas(main);
+        let [board, clocks, _] = Layout::vertical([Constraint::Length(BOARD_HEIGHT), Constraint::Length(4), Constraint::Min(0)]).areas(left);
+
+        self.draw_board(frame, board);
+        self.draw_clocks(frame, clocks);
+        self.draw_moves(frame, moves);
+        self.draw_chat(frame, chat);
+        self.draw_status(frame, status);
+        self.draw_input(frame, input);
+    }
+
+    fn draw_board(&self, frame: &mut Frame, area: Rect) {
+        let title = match (&self.opponent, self.view.perspective) {
+            (Some(opponent), Color::White) => format!(" White vs {} ", opponent),
+            (Some(opponent), Color::Black) => format!(" Black vs {} ", opponent),
+            (None, _) => " Board ".to_string(),
+        };
+        let block = Block::bordered().title(title);
+        let board = match &self.board {
+            Some(board) => board,
+            None => {
+                frame.render_widget(Paragraph::new("No game yet, /play to start one.").wrap(Wrap { trim: true }).block(block), area);
+                return;
+            }
+        };
+
+        let mut lines = Vec::new();
+        for row in self.view.rows() {
+            let mut spans = vec![Span::raw(format!("{} ", row[0].get_rank().to_index() + 1))];
+            for square in row {
+                let background = match self.view.highlight(board, square) {
+                    Some(Highlight::Check) => CHECK_SQUARE,
+                    Some(Highlight::LastMove) => LAST_MOVE_SQUARE,
+                    None if is_light_square(square) => LIGHT_SQUARE,
+                    None => DARK_SQUARE,
+                };
+                let piece = board.piece_on(square).zip(board.color_on(square));
+                spans.push(Span::styled(format!(" {} ", self.view.glyph(piece)), Style::new().fg(TermColor::Black).bg(background)));
+            }
+            lines.push(Line::from(spans));
+        }
+        let labels: String = self.view.file_labels().iter().map(|label| format!(" {} ", label)).collect();
+        lines.push(Line::from(format!("  {}", labels)));
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_clocks(&self, frame: &mut Frame, area: Rect) {
+        let to_move = self.turn_started.and(self.board).map(|board| board.side_to_move());
+        let lines: Vec<Line> = [(Color::White, "White"), (Color::Black, "Black")].into_iter()
+            .map(|(color, name)| {
+                let seconds = self.time_used(color).as_secs();
+                let text = format!("{}  {:02}:{:02}", name, seconds / 60, seconds % 60);
+                if to_move == Some(color) {
+                    let marker = if self.view.glyphs == Glyphs::Unicode { "◀" } else { "<" };
+                    Line::styled(format!("{} {}", text, marker), Style::new().add_modifier(Modifier::BOLD))
+                } else {
+                    Line::raw(text)
+                }
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Time used ")), area);
+    }
+
+    fn draw_moves(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = Vec::new();
+        if self.joined_midgame {
+            lines.push(Line::styled("(earlier moves)", Style::new().fg(TermColor::DarkGray)));
+        }
+        for (number, pair) in self.moves.chunks(2).enumerate() {
+            lines.push(Line::raw(format!("{:>3}. {:<7} {}", number + 1, pair[0], pair.get(1).map(String::as_str).unwrap_or(""))));
+        }
+
+        // Keep the latest move in view
+        let visible = area.height.saturating_sub(2) as usize;
+        let offset = lines.len().saturating_sub(visible) as u16;
+        frame.render_widget(Paragraph::new(lines).scroll((offset, 0)).block(Block::bordered().title(" Moves ")), area);
+    }
+
+    fn draw_chat(&self, frame: &mut Frame, area: Rect) {
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let height = area.height.saturating_sub(2) as usize;
+
+        // Newest at the bottom: take entries from the end until the pane is full, counting wrapped lines
+        let lines: Vec<Line> = self.entries.iter().map(|entry| self.entry_line(entry)).collect();
+        let heights: Vec<usize> = lines.iter().map(|line| line.width().max(1).div_ceil(width)).collect();
+        let total: usize = heights.iter().sum();
+        let scroll = self.scroll.min(total.saturating_sub(height));
+        let bottom = total - scroll;
+        let top = bottom.saturating_sub(height);
+
+        let mut skipped = 0;
+        let mut first = 0;
+        while first < lines.len() && skipped + heights[first] <= top {
+            skipped += heights[first];
+            first += 1;
+        }
+        let title = if scroll > 0 { format!(" Chat (scrolled up {} lines) ", scroll) } else { " Chat ".to_string() };
+        let paragraph = Paragraph::new(lines[first..].to_vec())
+            .wrap(Wrap { trim: false })
+            .scroll(((top - skipped) as u16, 0))
+            .block(Block::bordered().title(title));
+        frame.render_widget(paragraph, area);
+    }
+
+    fn entry_line(&self, entry: &Entry) -> Line<'static> {
+        let dim = Style::new().fg(TermColor::DarkGray);
+        match entry {
+            Entry::Chat(chat) => {
+                let (prefix, color) = match &chat.channel {
+                    ChatChannel::Lobby => ("[lobby] ", TermColor::Cyan),
+                    ChatChannel::Game => ("", TermColor::Yellow),
+                    ChatChannel::Direct(_) => ("[DM] ", TermColor::Magenta),
+                };
+                Line::from(vec![
+                    Span::styled(prefix, dim),
+                    Span::styled(format!("{}: ", chat.sender), Style::new().fg(color).add_modifier(Modifier::BOLD)),
+                    Span::raw(chat.text.clone()),
+                ])
+            }
+            Entry::Text(sender, text) => Line::from(vec![
+                Span::styled(format!("{}: ", sender), Style::new().fg(TermColor::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw(text.clone()),
+            ]),
+            Entry::Log(text) => Line::styled(format!("[SERVER] {}", text), Style::new().fg(TermColor::Green)),
+            Entry::Error(text) => Line::styled(format!("[SERVER ERROR] {}", text), Style::new().fg(TermColor::Red)),
+            Entry::Local(text) => Line::styled(text.clone(), dim),
+        }
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let user = match (&self.username, self.connected) {
+            (_, false) => "disconnected".to_string(),
+            (Some(username), true) => username.clone(),
+            (None, true) => "not logged in".to_string(),
+        };
+        let turn = match (self.turn_started, self.board) {
+            (Some(_), Some(board)) if board.side_to_move() == Color::White => "White to move",
+            (Some(_), Some(_)) => "Black to move",
+            _ => "No game running",
+        };
+        let text = format!(" {} | {} | {}", user, turn, self.status);
+        frame.render_widget(Paragraph::new(text).style(Style::new().add_modifier(Modifier::REVERSED)), area);
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.chat_mode {
+            Some(ChatChannel::Lobby) => " [lobby] ".to_string(),
+            Some(ChatChannel::Game) => " [game] ".to_string(),
+            Some(ChatChannel::Direct(username)) => format!(" [@{}] ", username),
+            None => " Move, :chat or /command ".to_string(),
+        };
+
+        // Scroll sideways so the cursor stays visible on long lines
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let offset = self.cursor.saturating_sub(width - 1);
+        let visible: String = self.input.chars().skip(offset).take(width).collect();
+        frame.render_widget(Paragraph::new(visible).block(Block::bordered().title(title)), area);
+        frame.set_cursor_position((area.x + 1 + (self.cursor - offset) as u16, area.y + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        App::new(Glyphs::Ascii)
+    }
+
+    fn press(app: &mut App, code: KeyCode) -> Option<Message> {
+        app.on_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_line(app: &mut App, line: &str) -> Option<Message> {
+        for c in line.chars() {
+            press(app, KeyCode::Char(c));
+        }
+        press(app, KeyCode::Enter)
+    }
+
+    #[test]
+    fn editing_counts_chars_not_bytes() {
+        let mut app = app();
+        for c in "héllo".chars() {
+            press(&mut app, KeyCode::Char(c));
+        }
+        press(&mut app, KeyCode::Home);
+        press(&mut app, KeyCode::Right);
+        press(&mut app, KeyCode::Delete);
+        press(&mut app, KeyCode::Char('a'));
+        press(&mut app, KeyCode::End);
+        press(&mut app, KeyCode::Backspace);
+        assert_eq!(app.input, "hall");
+        assert_eq!(app.cursor, 4);
+    }
+
+    #[test]
+    fn history_skips_repeats_and_ends_on_an_empty_line() {
+        let mut app = app();
+        type_line(&mut app, "/help");
+        type_line(&mut app, "/help");
+        type_line(&mut app, "/moves");
+
+        press(&mut app, KeyCode::Up);
+        assert_eq!(app.input, "/moves");
+        press(&mut app, KeyCode::Up);
+        assert_eq!(app.input, "/help");
+        press(&mut app, KeyCode::Up);
+        assert_eq!(app.input, "/help", "stays on the oldest line");
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Down);
+        assert_eq!(app.input, "");
+        assert_eq!(app.history, ["/help", "/moves"]);
+    }
+
+    #[test]
+    fn tab_cycles_through_commands() {
+        let mut app = app();
+        press(&mut app, KeyCode::Char('/'));
+        press(&mut app, KeyCode::Char('c'));
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.input, "/concede");
+        assert_eq!(app.status, "/concede /claim win /claim draw /chat ");
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.input, "/claim win");
+
+        // Typing starts a new completion
+        press(&mut app, KeyCode::Backspace);
+        press(&mut app, KeyCode::Backspace);
+        press(&mut app, KeyCode::Backspace);
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.input, "/claim win");
+        assert_eq!(app.cursor, "/claim win".chars().count());
+    }
+
+    #[test]
+    fn no_moves_are_offered_outside_a_game() {
+        let mut app = app();
+        press(&mut app, KeyCode::Char('e'));
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.input, "e");
+    }
+
+    #[test]
+    fn submitting_sends_commands_and_remembers_the_username() {
+        let mut app = app();
+        assert!(matches!(type_line(&mut app, "/play"), Some(Message::Command(Command::Play))));
+        assert!(matches!(type_line(&mut app, "/log in alice"), Some(Message::Command(Command::LogIn(_)))));
+        assert_eq!(app.username.as_deref(), Some("alice"));
+        assert!(type_line(&mut app, "   ").is_none());
+        assert!(type_line(&mut app, "/help").is_none());
+    }
+
+    #[test]
+    fn nothing_is_sent_after_a_disconnect() {
+        let mut app = app();
+        app.on_envelope(Err(ChessError::UserNotFoundError));
+        assert!(!app.connected);
+        assert!(type_line(&mut app, "/play").is_none());
+        assert!(matches!(app.entries.last(), Some(Entry::Error(_))));
+    }
+
+    #[test]
+    fn quitting() {
+        let mut typed = app();
+        type_line(&mut typed, "/quit");
+        assert!(typed.quit);
+
+        let mut app = app();
+        app.on_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL));
+        assert!(app.quit);
+        assert!(app.input.is_empty());
+    }
+}
@@ -0,0 +1,46 @@
+// `cargo run --example greedy_bot -- <username> [host] [port] [games]`
+use chess::{Board, ChessMove, MoveGen, Piece};
+
+use common::bot::{Bot, BotConfig, Clock, run_bot};
+
+// Takes the most valuable piece it can, otherwise any legal move
+struct GreedyBot;
+
+impl Bot for GreedyBot {
+    fn choose_move(&mut self, board: &Board, _clock: &Clock) -> Option<ChessMove> {
+        MoveGen::new_legal(board)
+            .max_by_key(|mov| board.piece_on(mov.get_dest()).map_or(0, value))
+    }
+}
+
+fn value(piece: Piece) -> u32 {
+    match piece {
+        Piece::Pawn => 1,
+        Piece::Knight | Piece::Bishop => 3,
+        Piece::Rook => 5,
+        Piece::Queen => 9,
+        Piece::King => 0,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(username) = args.first() else {
+        eprintln!("Usage: greedy_bot <username> [host] [port] [games]");
+        std::process::exit(2);
+    };
+    let mut config = BotConfig::new(username);
+    if let Some(host) = args.get(1) {
+        config.host = host.clone();
+    }
+    if let Some(port) = args.get(2) {
+        config.port = port.clone();
+    }
+    config.games = args.get(3).and_then(|games| games.parse().ok());
+
+    if let Err(e) = run_bot(&config, &mut GreedyBot).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use chess::{Board, BoardStatus, ChessMove, Color, MoveGen};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use log::{info, warn, error};
+
+use crate::{Capability, ChessError, Command, Envelope, GameEnd, GameStart, Hello, Message, RequestId, DEFAULT_HOST, DEFAULT_PORT, PROTOCOL_VERSION, make_io_error};
+use crate::chess_utils::board_from_string;
+use crate::codec::{Codec, FrameReader, FrameWriter};
+
+// Time each side has spent on its moves, measured by the bot since the server has no clocks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock {
+    pub white: Duration,
+    pub black: Duration,
+}
+
+impl Clock {
+    pub fn used(&self, color: Color) -> Duration {
+        match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        }
+    }
+
+    fn add(&mut self, color: Color, elapsed: Duration) {
+        match color {
+            Color::White => self.white += elapsed,
+            Color::Black => self.black += elapsed,
+        }
+    }
+}
+
+// Implemented by the bot author, `run_bot` does the rest.
+// `choose_move` runs on the connection's task: a bot that thinks longer than the server's heartbeat timeout is disconnected, reconnects and is asked again.
+pub trait Bot {
+    // `None` gives the move back to `run_bot`, which plays any legal one
+    fn choose_move(&mut self, board: &Board, clock: &Clock) -> Option<ChessMove>;
+
+    fn game_started(&mut self, _game_start: &GameStart, _side: Color) {}
+
+    fn game_ended(&mut self, _game_end: &GameEnd) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub host: String,
+    pub port: String,
+    pub username: String,
+    pub games: Option<u32>, // stop after this many games, play forever without
+    pub reconnect_delay: Duration,
+    pub max_reconnects: u32, // consecutive failed connections before `run_bot` gives up
+}
+
+impl BotConfig {
+    pub fn new(username: &str) -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT.to_string(),
+            username: username.to_string(),
+            games: None,
+            reconnect_delay: Duration::from_secs(5),
+            max_reconnects: 5,
+        }
+    }
+}
+
+// What happened to a connection
+enum Outcome {
+    Done, // played all the games that were asked for
+    Lost(ChessError), // worth reconnecting
+}
+
+// Per connection, the game part is reset by `GameStart`
+struct Session {
+    side: Option<Color>,
+    board: Option<Board>,
+    answered: Option<Board>, // the position we last sent a move for, the server repeats boards
+    clock: Clock,
+    turn_started: Instant,
+    next_request_id: RequestId,
+    login_request: RequestId,
+}
+
+// Connects, logs in, asks for games and plays them until `config.games` are done.
+// Lost connections are retried after `config.reconnect_delay`, the server puts the bot back into its game; a refused login is returned as is.
+pub async fn run_bot<B: Bot>(config: &BotConfig, bot: &mut B) -> Result<(), ChessError> {
+    let mut games_played = 0;
+    let mut failures = 0;
+    loop {
+        let outcome = match connect(&config.host, &config.port).await {
+            Ok((reader, writer)) => play(reader, writer, config, bot, &mut games_played, &mut failures).await?,
+            Err(e) => Outcome::Lost(e),
+        };
+        match outcome {
+            Outcome::Done => return Ok(()),
+            Outcome::Lost(e) => {
+                failures += 1;
+                if failures > config.max_reconnects {
+                    return Err(e);
+                }
+                warn!("{}: connection lost ({}), reconnecting in {:?}", config.username, e, config.reconnect_delay);
+                tokio::time::sleep(config.reconnect_delay).await;
+            }
+        }
+    }
+}
+
+// Plain TCP with the CBOR codec, like the terminal client without `--tls`
+async fn connect(host: &str, port: &str) -> Result<(FrameReader<tokio::net::tcp::OwnedReadHalf>, FrameWriter<tokio::net::tcp::OwnedWriteHalf>), ChessError> {
+    let stream = TcpStream::connect(format!("{}:{}", host, port)).await
+        .map_err(|e| make_io_error(e, "Failed to connect"))?;
+    let (reader, writer) = stream.into_split();
+    Ok((FrameReader::new(reader, Codec::Cbor), FrameWriter::new(writer, Codec::Cbor)))
+}
+
+// One connection: handshake, log in, then answer the server until the games are done or the connection breaks
+async fn play<R, W, B>(mut reader: FrameReader<R>, mut writer: FrameWriter<W>, config: &BotConfig, bot: &mut B, games_played: &mut u32, failures: &mut u32) -> Result<Outcome, ChessError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    B: Bot,
+{
+    if let Err(e) = handshake(&mut reader, &mut writer).await {
+        return Ok(Outcome::Lost(e));
+    }
+
+    let mut session = Session {
+        side: None,
+        board: None,
+        answered: None,
+        clock: Clock::default(),
+        turn_started: Instant::now(),
+        next_request_id: 1,
+        login_request: 1,
+    };
+    let login = session.request(Message::Command(Command::LogIn(config.username.clone())));
+    if let Err(e) = writer.write(&login).await {
+        return Ok(Outcome::Lost(e));
+    }
+
+    loop {
+        let envelope = match reader.read::<Envelope>().await {
+            Ok(envelope) => envelope,
+            Err(e) => return Ok(Outcome::Lost(e)),
+        };
+        let reply = match envelope {
+            Envelope::Ping(timestamp) => Some(Envelope::Pong(timestamp)),
+            Envelope::Ack { id, error: Some(error), .. } if id == session.login_request => {
+                return Err(ChessError::AuthenticationError(error));
+            }
+            // Logged in, ask for a game unless the server put us back into one
+            Envelope::Ack { id, error: None, .. } if id == session.login_request => {
+                *failures = 0;
+                match session.side {
+                    Some(_) => None,
+                    None => Some(session.request(Message::Command(Command::Play))),
+                }
+            }
+            Envelope::Ack { id, error: Some(error), .. } => {
+                error!("{}: request {} failed: {}", config.username, id, error);
+                None
+            }
+            Envelope::Response { message, .. } | Envelope::Event(message) => {
+                match session.on_message(message, config, bot) {
+                    Some(Message::GameEnd(_)) => {
+                        *games_played += 1;
+                        if config.games.is_some_and(|games| *games_played >= games) {
+                            return Ok(Outcome::Done);
+                        }
+                        Some(session.request(Message::Command(Command::Play)))
+                    }
+                    Some(message) => Some(session.request(message)),
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = writer.write(&reply).await {
+                return Ok(Outcome::Lost(e));
+            }
+        }
+    }
+}
+
+impl Session {
+    fn request(&mut self, message: Message) -> Envelope {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        Envelope::Request { id, message }
+    }
+
+    // What to send back, if anything; a `GameEnd` is handed back as is so the caller can decide whether to play on
+    fn on_message<B: Bot>(&mut self, message: Message, config: &BotConfig, bot: &mut B) -> Option<Message> {
+        match message {
+            Message::GameStart(game_start) => {
+                let side = if game_start.white == config.username { Color::White } else { Color::Black };
+                info!("{}: game {} started, playing {:?}", config.username, game_start.game_id, side);
+                self.side = Some(side);
+                self.board = None;
+                self.answered = None;
+                self.clock = Clock::default();
+                self.turn_started = Instant::now();
+                bot.game_started(&game_start, side);
+                None
+            }
+            Message::Board(board_string) => {
+                let board = match board_from_string(board_string) {
+                    Ok(board) => board,
+                    Err(e) => {
+                        error!("{}: failed to parse the board: {}", config.username, e);
+                        return None;
+                    }
+                };
+                if let Some(previous) = self.board.filter(|previous| *previous != board) {
+                    self.clock.add(previous.side_to_move(), self.turn_started.elapsed());
+                    self.turn_started = Instant::now();
+                }
+                self.board = Some(board);
+
+                let our_turn = self.side == Some(board.side_to_move()) && board.status() == BoardStatus::Ongoing;
+                if !our_turn || self.answered == Some(board) {
+                    return None;
+                }
+                self.answered = Some(board);
+                // An illegal move would leave the game stuck, play something rather than nothing
+                let mov = match bot.choose_move(&board, &self.clock) {
+                    Some(chosen) if board.legal(chosen) => chosen,
+                    Some(chosen) => {
+                        error!("{}: the bot chose an illegal move {}", config.username, chosen);
+                        MoveGen::new_legal(&board).next()?
+                    }
+                    None => {
+                        error!("{}: the bot chose no move", config.username);
+                        MoveGen::new_legal(&board).next()?
+                    }
+                };
+                Some(Message::Move(mov.to_string()))
+            }
+            Message::GameEnd(game_end) => {
+                info!("{}: game over, {} by {}", config.username, game_end.result, game_end.reason);
+                self.side = None;
+                bot.game_ended(&game_end);
+                Some(Message::GameEnd(game_end))
+            }
+            Message::OpponentGone(opponent) if self.side.is_some() => {
+                info!("{}: {} did not come back, claiming the win", config.username, opponent);
+                Some(Message::Command(Command::ClaimWin))
+            }
+            Message::Error(text) => {
+                error!("{}: {}", config.username, text);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+async fn handshake<R, W>(reader: &mut FrameReader<R>, writer: &mut FrameWriter<W>) -> Result<(), ChessError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let hello = Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: "bot".to_string(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: vec![Capability::Heartbeat],
+    };
+    writer.write(&Message::Hello(hello)).await?;
+
+    match reader.read().await? {
+        Message::Welcome(welcome) => {
+            info!("Connected to server version {}, protocol version {}", welcome.server_version, welcome.protocol_version);
+            Ok(())
+        }
+        Message::Error(e) => Err(ChessError::ProtocolError(e)),
+        other => Err(ChessError::ProtocolError(format!("Expected Welcome, received {:?}", other))),
+    }
+}
@@ -1,43 +1,253 @@
-use std::str::FromStr;
-
-use chess::{Board, Color, Piece, Square};
-
-pub fn piece_to_unicode(piece: Option<(Piece, Color)>) -> char {
-    match piece {
-        Some((Piece::Pawn, Color::White)) => '♙',
-        Some((Piece::Knight, Color::White)) => '♘',
-        Some((Piece::Bishop, Color::White)) => '♗',
-        Some((Piece::Rook, Color::White)) => '♖',
-        Some((Piece::Queen, Color::White)) => '♕',
-        Some((Piece::King, Color::White)) => '♔',
-        Some((Piece::Pawn, Color::Black)) => '♟',
-        Some((Piece::Knight, Color::Black)) => '♞',
-        Some((Piece::Bishop, Color::Black)) => '♝',
-        Some((Piece::Rook, Color::Black)) => '♜',
-        Some((Piece::Queen, Color::Black)) => '♛',
-        Some((Piece::King, Color::Black)) => '♚',
-        None => ' ',
-    }
-}
-
-pub fn print_board(board: &Board) {
-    println!("     A  B  C  D  E  F  G  H ");
-    println!("   ┌──┬──┬──┬──┬──┬──┬──┬──┐");
-    for rank in (1..=8).rev() {
-        print!(" {} │", rank);
-        for file in 'a'..='h' {
-            let square = Square::from_str(&format!("{}{}", file, rank)).unwrap();
-            let piece = board.piece_on(square);
-            let color = board.color_on(square);
-            print!("{} │", piece_to_unicode(piece.zip(color)));
-        }
-        if rank > 1 {
-            println!("\n   ├──┼──┼──┼──┼──┼──┼──┼──┤"); 
-        }
-    }
-    println!("\n   └──┴──┴──┴──┴──┴──┴──┴──┘");
-}
-
-pub fn board_from_string(board_string: String) -> Result<Board, chess::Error> {
-    Board::from_str(&board_string)
-}
\ No newline at end of file
+use std::str::FromStr;
+
+use chess::{Board, ChessMove, Color, File, Piece, Rank, Square};
+
+use crate::ChessError;
+use crate::pgn::san_to_move;
+
+// ANSI background colours (256-colour palette) and resets for `print_board`
+const ANSI_LIGHT_SQUARE: &str = "\x1b[48;5;180m";
+const ANSI_DARK_SQUARE: &str = "\x1b[48;5;137m";
+const ANSI_LAST_MOVE: &str = "\x1b[48;5;143m";
+const ANSI_CHECK: &str = "\x1b[48;5;160m";
+const ANSI_PIECE: &str = "\x1b[38;5;16m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+// Terminals without the Unicode chess symbols get letters: upper case white, lower case black
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Glyphs {
+    Unicode,
+    Ascii,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    LastMove, // the squares the last move came from and went to
+    Check, // the king of the side to move, when in check
+}
+
+// How a board is drawn: from whose side, what to mark and with which symbols
+#[derive(Debug, Clone, Copy)]
+pub struct BoardView {
+    pub perspective: Color, // this side is at the bottom
+    pub last_move: Option<ChessMove>,
+    pub glyphs: Glyphs,
+}
+
+impl Default for BoardView {
+    fn default() -> Self {
+        Self {
+            perspective: Color::White,
+            last_move: None,
+            glyphs: Glyphs::Unicode,
+        }
+    }
+}
+
+impl BoardView {
+    // Top to bottom, left to right as seen by `perspective`
+    pub fn rows(&self) -> Vec<Vec<Square>> {
+        let mut ranks: Vec<usize> = (0..8).rev().collect();
+        let mut files: Vec<usize> = (0..8).collect();
+        if self.perspective == Color::Black {
+            ranks.reverse();
+            files.reverse();
+        }
+        ranks.iter()
+            .map(|&rank| files.iter().map(|&file| Square::make_square(Rank::from_index(rank), File::from_index(file))).collect())
+            .collect()
+    }
+
+    // File letters in the order they are drawn
+    pub fn file_labels(&self) -> Vec<char> {
+        let mut labels: Vec<char> = ('a'..='h').collect();
+        if self.perspective == Color::Black {
+            labels.reverse();
+        }
+        labels
+    }
+
+    pub fn highlight(&self, board: &Board, square: Square) -> Option<Highlight> {
+        if board.checkers().popcnt() > 0 && board.king_square(board.side_to_move()) == square {
+            Some(Highlight::Check)
+        } else if self.last_move.is_some_and(|mov| mov.get_source() == square || mov.get_dest() == square) {
+            Some(Highlight::LastMove)
+        } else {
+            None
+        }
+    }
+
+    pub fn glyph(&self, piece: Option<(Piece, Color)>) -> char {
+        match self.glyphs {
+            Glyphs::Unicode => piece_to_unicode(piece),
+            Glyphs::Ascii => piece_to_ascii(piece),
+        }
+    }
+}
+
+pub fn is_light_square(square: Square) -> bool {
+    (square.get_rank().to_index() + square.get_file().to_index()) % 2 == 1
+}
+
+pub fn piece_to_unicode(piece: Option<(Piece, Color)>) -> char {
+    match piece {
+        Some((Piece::Pawn, Color::White)) => '♙',
+        Some((Piece::Knight, Color::White)) => '♘',
+        Some((Piece::Bishop, Color::White)) => '♗',
+        Some((Piece::Rook, Color::White)) => '♖',
+        Some((Piece::Queen, Color::White)) => '♕',
+        Some((Piece::King, Color::White)) => '♔',
+        Some((Piece::Pawn, Color::Black)) => '♟',
+        Some((Piece::Knight, Color::Black)) => '♞',
+        Some((Piece::Bishop, Color::Black)) => '♝',
+        Some((Piece::Rook, Color::Black)) => '♜',
+        Some((Piece::Queen, Color::Black)) => '♛',
+        Some((Piece::King, Color::Black)) => '♚',
+        None => ' ',
+    }
+}
+
+pub fn piece_to_ascii(piece: Option<(Piece, Color)>) -> char {
+    let letter = match piece {
+        Some((Piece::Pawn, _)) => 'p',
+        Some((Piece::Knight, _)) => 'n',
+        Some((Piece::Bishop, _)) => 'b',
+        Some((Piece::Rook, _)) => 'r',
+        Some((Piece::Queen, _)) => 'q',
+        Some((Piece::King, _)) => 'k',
+        None => return ' ',
+    };
+    match piece {
+        Some((_, Color::White)) => letter.to_ascii_uppercase(),
+        _ => letter,
+    }
+}
+
+// Coloured squares with ANSI escape codes, plain `+---+` lines instead of box drawing in ASCII mode
+pub fn print_board(board: &Board, view: &BoardView) {
+    let labels: String = view.file_labels().iter().map(|label| format!(" {} ", label)).collect();
+    let (top, bottom) = match view.glyphs {
+        Glyphs::Unicode => ("┌────────────────────────┐", "└────────────────────────┘"),
+        Glyphs::Ascii => ("+------------------------+", "+------------------------+"),
+    };
+    let side = if view.glyphs == Glyphs::Unicode { '│' } else { '|' };
+
+    println!("    {}", labels);
+    println!("   {}", top);
+    for row in view.rows() {
+        print!(" {} {}", row[0].get_rank().to_index() + 1, side);
+        for square in row {
+            let background = match view.highlight(board, square) {
+                Some(Highlight::Check) => ANSI_CHECK,
+                Some(Highlight::LastMove) => ANSI_LAST_MOVE,
+                None if is_light_square(square) => ANSI_LIGHT_SQUARE,
+                None => ANSI_DARK_SQUARE,
+            };
+            let piece = board.piece_on(square).zip(board.color_on(square));
+            print!("{}{} {} {}", background, ANSI_PIECE, view.glyph(piece), ANSI_RESET);
+        }
+        println!("{}", side);
+    }
+    println!("   {}", bottom);
+}
+
+// Coordinate notation as the server takes it (`e2e4`, `e7e8q`) or SAN (`Nf3`, `exd5`, `O-O`), as long as it is legal on `board`
+pub fn parse_move(board: &Board, text: &str) -> Result<ChessMove, ChessError> {
+    let text = text.trim();
+    // Round trip, so only the exact `e2e4` form counts as coordinate notation
+    let coordinate = ChessMove::from_str(text).ok()
+        .filter(|mov| mov.to_string() == text);
+    match coordinate {
+        Some(mov) if board.legal(mov) => Ok(mov),
+        Some(_) => Err(ChessError::GameStateError(format!("{} is not a legal move.", text))),
+        None => san_to_move(board, text)
+            .ok_or(ChessError::GameStateError(format!("{} is not a legal move.", text))),
+    }
+}
+
+pub fn board_from_string(board_string: String) -> Result<Board, chess::Error> {
+    Board::from_str(&board_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(fen: &str) -> Board {
+        Board::from_str(fen).unwrap()
+    }
+
+    #[test]
+    fn white_sees_a8_top_left() {
+        let rows = BoardView::default().rows();
+        assert_eq!(rows[0][0], Square::A8);
+        assert_eq!(rows[7][7], Square::H1);
+        assert_eq!(BoardView::default().file_labels().first(), Some(&'a'));
+    }
+
+    #[test]
+    fn black_sees_h1_top_left() {
+        let view = BoardView { perspective: Color::Black, ..BoardView::default() };
+        let rows = view.rows();
+        assert_eq!(rows[0][0], Square::H1);
+        assert_eq!(rows[7][7], Square::A8);
+        assert_eq!(view.file_labels(), ['h', 'g', 'f', 'e', 'd', 'c', 'b', 'a']);
+    }
+
+    #[test]
+    fn square_colours() {
+        assert!(!is_light_square(Square::A1));
+        assert!(is_light_square(Square::H1));
+        assert!(is_light_square(Square::A8));
+        assert!(!is_light_square(Square::E5));
+    }
+
+    #[test]
+    fn glyphs() {
+        let unicode = BoardView::default();
+        let ascii = BoardView { glyphs: Glyphs::Ascii, ..BoardView::default() };
+        assert_eq!(unicode.glyph(Some((Piece::Knight, Color::White))), '♘');
+        assert_eq!(unicode.glyph(Some((Piece::Knight, Color::Black))), '♞');
+        assert_eq!(ascii.glyph(Some((Piece::Knight, Color::White))), 'N');
+        assert_eq!(ascii.glyph(Some((Piece::Knight, Color::Black))), 'n');
+        assert_eq!(ascii.glyph(None), ' ');
+    }
+
+    #[test]
+    fn highlights() {
+        let view = BoardView { last_move: Some(ChessMove::new(Square::E2, Square::E4, None)), ..BoardView::default() };
+        let after_e4 = board("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        assert_eq!(view.highlight(&after_e4, Square::E2), Some(Highlight::LastMove));
+        assert_eq!(view.highlight(&after_e4, Square::E4), Some(Highlight::LastMove));
+        assert_eq!(view.highlight(&after_e4, Square::D2), None);
+
+        // The checked king wins over the last move
+        let view = BoardView { last_move: Some(ChessMove::new(Square::H5, Square::E8, None)), ..BoardView::default() };
+        let check = board("4Q1k1/8/8/8/8/8/8/6K1 b - - 0 1");
+        assert_eq!(view.highlight(&check, Square::G8), Some(Highlight::Check));
+        assert_eq!(view.highlight(&check, Square::E8), Some(Highlight::LastMove));
+    }
+
+    #[test]
+    fn parses_coordinates_and_san() {
+        let start = Board::default();
+        assert_eq!(parse_move(&start, "e2e4").unwrap(), ChessMove::new(Square::E2, Square::E4, None));
+        assert_eq!(parse_move(&start, " Nf3 ").unwrap(), ChessMove::new(Square::G1, Square::F3, None));
+        assert_eq!(parse_move(&start, "e4").unwrap(), ChessMove::new(Square::E2, Square::E4, None));
+
+        let promotion = board("8/4P1k1/8/8/8/8/8/4K3 w - - 0 1");
+        let queen = ChessMove::new(Square::E7, Square::E8, Some(Piece::Queen));
+        assert_eq!(parse_move(&promotion, "e7e8q").unwrap(), queen);
+        assert_eq!(parse_move(&promotion, "e8=Q").unwrap(), queen);
+    }
+
+    #[test]
+    fn refuses_illegal_and_unknown_moves() {
+        let start = Board::default();
+        assert!(parse_move(&start, "e2e5").is_err());
+        assert!(parse_move(&start, "Nf6").is_err());
+        assert!(parse_move(&start, "O-O").is_err());
+        assert!(parse_move(&start, "hello").is_err());
+        assert!(parse_move(&start, "").is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::{info, error};
+
+use crate::{ChessError, MAX_MESSAGE_LEN, make_io_error};
+
+const LEN_PREFIX: usize = 4;
+const READ_CHUNK: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Cbor, // 4-byte big-endian length prefix followed by a CBOR document
+    JsonLines, // one JSON document per line, handy for netcat and bots in other languages
+}
+
+impl Codec {
+    pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ChessError> {
+        match self {
+            Codec::Cbor => serde_cbor::to_vec(message)
+                .map_err(|e| ChessError::SerializationError(e.to_string())),
+            Codec::JsonLines => serde_json::to_vec(message)
+                .map_err(|e| ChessError::SerializationError(e.to_string())),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, ChessError> {
+        match self {
+            Codec::Cbor => serde_cbor::from_slice(bytes)
+                .map_err(|e| ChessError::DeserializationError(e.to_string())),
+            Codec::JsonLines => serde_json::from_slice(bytes)
+                .map_err(|e| ChessError::DeserializationError(e.to_string())),
+        }
+    }
+}
+
+// Reads whole frames into an internal buffer, so a `read` future that is dropped halfway
+// (e.g. in `tokio::select!`) loses nothing: the next call picks up where the last one stopped.
+pub struct FrameReader<R> {
+    reader: R,
+    codec: Codec,
+    buffer: Vec<u8>,
+    max_frame_len: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, codec: Codec) -> Self {
+        Self::with_max_frame_len(reader, codec, MAX_MESSAGE_LEN)
+    }
+
+    pub fn with_max_frame_len(reader: R, codec: Codec, max_frame_len: usize) -> Self {
+        Self {
+            reader,
+            codec,
+            buffer: Vec::new(),
+            max_frame_len,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub async fn read<T: DeserializeOwned + fmt::Debug>(&mut self) -> Result<T, ChessError> {
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                return match self.codec.decode(&frame) {
+                    Ok(message) => {
+                        info!("Received message (len {}): {:?}", frame.len(), message);
+                        Ok(message)
+                    }
+                    Err(e) => {
+                        error!("Raw data: {:?}", frame);
+                        Err(e)
+                    }
+                };
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            let len = self.reader.read(&mut chunk).await
+                .map_err(|e| make_io_error(e, "Failed to read message"))?;
+            if len == 0 {
+                let context = if self.buffer.is_empty() { "Connection closed" } else { "Connection closed in the middle of a message" };
+                return Err(make_io_error(io::ErrorKind::UnexpectedEof.into(), context));
+            }
+            self.buffer.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    // Takes one complete frame out of the buffer, if there is one
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ChessError> {
+        match self.codec {
+            Codec::Cbor => {
+                if self.buffer.len() < LEN_PREFIX {
+                    return Ok(None);
+                }
+                let len_bytes = [self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]];
+                let len = u32::from_be_bytes(len_bytes) as usize;
+                if len > self.max_frame_len {
+                    return Err(ChessError::MessageHandlingError("Message length too large".to_string()));
+                }
+                if self.buffer.len() < LEN_PREFIX + len {
+                    return Ok(None);
+                }
+                let frame = self.buffer[LEN_PREFIX..LEN_PREFIX + len].to_vec();
+                self.buffer.drain(..LEN_PREFIX + len);
+                Ok(Some(frame))
+            }
+            Codec::JsonLines => loop {
+                let newline = match self.buffer.iter().position(|&b| b == b'\n') {
+                    Some(newline) => newline,
+                    None if self.buffer.len() > self.max_frame_len => {
+                        return Err(ChessError::MessageHandlingError("Message length too large".to_string()));
+                    }
+                    None => return Ok(None),
+                };
+                let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+                let line = line.trim_ascii();
+                if !line.is_empty() {
+                    return Ok(Some(line.to_vec()));
+                }
+            },
+        }
+    }
+}
+
+pub struct FrameWriter<W> {
+    writer: W,
+    codec: Codec,
+    max_frame_len: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(writer: W, codec: Codec) -> Self {
+        Self::with_max_frame_len(writer, codec, MAX_MESSAGE_LEN)
+    }
+
+    pub fn with_max_frame_len(writer: W, codec: Codec, max_frame_len: usize) -> Self {
+        Self {
+            writer,
+            codec,
+            max_frame_len,
+        }
+    }
+
+    // Not cancellation safe: a dropped write may leave half a frame on the wire,
+    // so keep writes in a dedicated task instead of racing them in `tokio::select!`
+    pub async fn write<T: Serialize>(&mut self, message: &T) -> Result<(), ChessError> {
+        let body = self.codec.encode(message)?;
+        if body.len() > self.max_frame_len {
+            return Err(ChessError::MessageHandlingError("Message length too large".to_string()));
+        }
+
+        let mut frame = Vec::with_capacity(body.len() + LEN_PREFIX);
+        match self.codec {
+            Codec::Cbor => {
+                frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&body);
+            }
+            Codec::JsonLines => {
+                frame.extend_from_slice(&body);
+                frame.push(b'\n');
+            }
+        }
+
+        self.writer.write_all(&frame).await
+            .map_err(|e| make_io_error(e, "Failed to write message"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ArchivedGame, Capability, Chat, ChatChannel, Command, Envelope, ErrorCode, GameEnd, GameStart, Hello, Message, SearchQuery, SearchResults, Welcome};
+
+    const CODECS: [Codec; 2] = [Codec::Cbor, Codec::JsonLines];
+
+    // One of every `Envelope`, with every `Message` and `Command` inside them
+    fn envelopes() -> Vec<Envelope> {
+        let commands = vec![
+            Command::LogIn("alice".to_string()),
+            Command::Play,
+            Command::Concede,
+            Command::ClaimWin,
+            Command::ClaimDraw,
+            Command::Stats,
+            Command::Kick("bob".to_string()),
+            Command::Ban("bob".to_string(), Some(10)),
+            Command::Ban("bob".to_string(), None),
+            Command::Unban("bob".to_string()),
+            Command::Mute("bob".to_string(), Some(5)),
+            Command::Unmute("bob".to_string()),
+            Command::EndGame("bob".to_string()),
+            Command::Search(SearchQuery { player: Some("alice".to_string()), result: Some("win".to_string()), from: Some("2024-01-01".to_string()), page: 2, pgn: true, ..SearchQuery::default() }),
+        ];
+        let mut messages: Vec<Message> = commands.into_iter().map(Message::Command).collect();
+        messages.extend([
+            Message::Move("e2e4".to_string()),
+            Message::Text("good luck".to_string()),
+            Message::Chat(Chat { channel: ChatChannel::Direct("bob".to_string()), sender: "alice".to_string(), text: "hi".to_string(), sent_at: 1_700_000_000_000 }),
+            Message::Chat(Chat { channel: ChatChannel::Lobby, sender: String::new(), text: "ünïcødé ♞".to_string(), sent_at: 0 }),
+            Message::Board("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1".to_string()),
+            Message::GameStart(GameStart { game_id: 7, white: "alice".to_string(), black: "bob".to_string() }),
+            Message::GameEnd(GameEnd { result: "1-0".to_string(), reason: "checkmate".to_string() }),
+            Message::SearchResults(SearchResults {
+                games: vec![ArchivedGame {
+                    game_id: 7,
+                    white: "alice".to_string(),
+                    black: "bob".to_string(),
+                    result: "1-0".to_string(),
+                    reason: "checkmate".to_string(),
+                    finished_at: 1_700_000_000,
+                    eco: "C60".to_string(),
+                    opening: "Ruy Lopez".to_string(),
+                    variant: "standard".to_string(),
+                    time_control: "-".to_string(),
+                    moves: 41,
+                    pgn: Some("1. e4 e5 *".to_string()),
+                }],
+                total: 21,
+                page: 1,
+                page_size: 20,
+            }),
+            Message::OpponentGone("bob".to_string()),
+            Message::Error("It's not your turn.".to_string()),
+            Message::Log("Check!".to_string()),
+            Message::Hello(Hello { protocol_version: 6, client_name: "test".to_string(), client_version: "0.1.0".to_string(), capabilities: vec![Capability::Heartbeat, Capability::Variants, Capability::Clocks, Capability::Compression] }),
+            Message::Welcome(Welcome { protocol_version: 6, server_version: "0.1.0".to_string(), capabilities: vec![Capability::Heartbeat] }),
+        ]);
+
+        let mut envelopes: Vec<Envelope> = messages.into_iter().enumerate().map(|(i, message)| match i % 3 {
+            0 => Envelope::Request { id: i as u32, message },
+            1 => Envelope::Response { id: i as u32, message },
+            _ => Envelope::Event(message),
+        }).collect();
+        envelopes.extend([
+            Envelope::Ack { id: 1, error: None, code: None },
+            Envelope::Ack { id: 2, error: Some("slow down".to_string()), code: Some(ErrorCode::RateLimited) },
+            Envelope::Ack { id: 3, error: Some("muted".to_string()), code: Some(ErrorCode::Muted) },
+            Envelope::Ack { id: 4, error: Some("bye".to_string()), code: Some(ErrorCode::Flooding) },
+            Envelope::Ping(u64::MAX),
+            Envelope::Pong(0),
+        ]);
+        envelopes
+    }
+
+    // The messages have no `PartialEq`, their `Debug` output covers every field
+    fn same(a: &Envelope, b: &Envelope) -> bool {
+        format!("{:?}", a) == format!("{:?}", b)
+    }
+
+    #[test]
+    fn round_trips_every_envelope() {
+        for codec in CODECS {
+            for envelope in envelopes() {
+                let bytes = codec.encode(&envelope).unwrap();
+                let decoded: Envelope = codec.decode(&bytes).unwrap();
+                assert!(same(&envelope, &decoded), "{:?}: {:?} came back as {:?}", codec, envelope, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn json_lines_encoding_has_no_newline() {
+        for envelope in envelopes() {
+            let bytes = Codec::JsonLines.encode(&envelope).unwrap();
+            assert!(!bytes.contains(&b'\n'), "{:?}", envelope);
+        }
+    }
+
+    #[test]
+    fn ack_without_code_decodes() {
+        // What servers before `ErrorCode` send
+        let decoded: Envelope = Codec::JsonLines.decode(br#"{"Ack":{"id":3,"error":"failed"}}"#).unwrap();
+        assert!(matches!(decoded, Envelope::Ack { id: 3, error: Some(_), code: None }));
+    }
+
+    #[test]
+    fn garbage_is_a_deserialization_error() {
+        for codec in CODECS {
+            let decoded: Result<Envelope, _> = codec.decode(b"{not a message");
+            assert!(matches!(decoded, Err(ChessError::DeserializationError(_))), "{:?}", codec);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_survive_a_duplex() {
+        for codec in CODECS {
+            // A 7-byte pipe splits every frame over several reads, and puts the end of one frame and the start of the next in the same read
+            let (client, server) = io::duplex(7);
+            let writer = tokio::spawn(async move {
+                let mut writer = FrameWriter::new(client, codec);
+                for envelope in envelopes() {
+                    writer.write(&envelope).await.unwrap();
+                }
+            });
+
+            let mut reader = FrameReader::new(server, codec);
+            for envelope in envelopes() {
+                let received: Envelope = reader.read().await.unwrap();
+                assert!(same(&envelope, &received), "{:?}: sent {:?}, received {:?}", codec, envelope, received);
+            }
+            writer.await.unwrap();
+            let closed: Result<Envelope, _> = reader.read().await;
+            assert!(closed.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_split_by_hand() {
+        for codec in CODECS {
+            let mut bytes = Vec::new();
+            let mut writer = FrameWriter::new(&mut bytes, codec);
+            for envelope in envelopes() {
+                writer.write(&envelope).await.unwrap();
+            }
+
+            let (mut client, server) = io::duplex(MAX_MESSAGE_LEN);
+            let mut reader = FrameReader::new(server, codec);
+            let mut received = Vec::new();
+            // Uneven pieces, each read call sees whatever arrived so far
+            for piece in bytes.chunks(5) {
+                client.write_all(piece).await.unwrap();
+                while let Ok(Ok(envelope)) = tokio::time::timeout(std::time::Duration::from_millis(1), reader.read::<Envelope>()).await {
+                    received.push(envelope);
+                }
+            }
+            // A read cancelled by the timeout must not have lost any bytes
+            assert_eq!(received.len(), envelopes().len(), "{:?}", codec);
+            for (sent, received) in envelopes().iter().zip(&received) {
+                assert!(same(sent, received), "{:?}: sent {:?}, received {:?}", codec, sent, received);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn wire_layout() {
+        let envelope = Envelope::Ping(1);
+
+        let mut bytes = Vec::new();
+        FrameWriter::new(&mut bytes, Codec::Cbor).write(&envelope).await.unwrap();
+        let body = Codec::Cbor.encode(&envelope).unwrap();
+        assert_eq!(bytes[..LEN_PREFIX], (body.len() as u32).to_be_bytes());
+        assert_eq!(bytes[LEN_PREFIX..], body[..]);
+
+        let mut bytes = Vec::new();
+        FrameWriter::new(&mut bytes, Codec::JsonLines).write(&envelope).await.unwrap();
+        assert_eq!(bytes, b"{\"Ping\":1}\n");
+    }
+
+    #[tokio::test]
+    async fn frames_longer_than_a_read_chunk() {
+        for codec in CODECS {
+            let long = Envelope::Event(Message::Log("x".repeat(3 * READ_CHUNK)));
+            let (client, server) = io::duplex(MAX_MESSAGE_LEN);
+            let mut writer = FrameWriter::new(client, codec);
+            writer.write(&long).await.unwrap();
+            writer.write(&Envelope::Ping(2)).await.unwrap();
+
+            let mut reader = FrameReader::new(server, codec);
+            assert!(same(&reader.read::<Envelope>().await.unwrap(), &long), "{:?}", codec);
+            assert!(matches!(reader.read::<Envelope>().await.unwrap(), Envelope::Ping(2)), "{:?}", codec);
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_mid_frame_is_an_error() {
+        for codec in CODECS {
+            let mut bytes = Vec::new();
+            FrameWriter::new(&mut bytes, codec).write(&Envelope::Ping(3)).await.unwrap();
+
+            let (mut client, server) = io::duplex(1024);
+            client.write_all(&bytes[..bytes.len() - 2]).await.unwrap();
+            drop(client);
+            let mut reader = FrameReader::new(server, codec);
+            match reader.read::<Envelope>().await {
+                Err(ChessError::IoError { main, context }) => {
+                    assert_eq!(main.kind(), io::ErrorKind::UnexpectedEof);
+                    assert!(context.contains("middle"), "{:?}: {}", codec, context);
+                }
+                other => panic!("{:?}: expected an I/O error, got {:?}", codec, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn json_lines_skips_blank_lines() {
+        let (mut client, server) = io::duplex(1024);
+        client.write_all(b"\r\n\n{\"Ping\":5}\r\n  \n{\"Pong\":6}\n").await.unwrap();
+        let mut reader = FrameReader::new(server, Codec::JsonLines);
+        assert!(matches!(reader.read::<Envelope>().await.unwrap(), Envelope::Ping(5)));
+        assert!(matches!(reader.read::<Envelope>().await.unwrap(), Envelope::Pong(6)));
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused() {
+        let (mut client, server) = io::duplex(1024);
+        client.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        let mut reader = FrameReader::with_max_frame_len(server, Codec::Cbor, 100);
+        assert!(matches!(reader.read::<Envelope>().await, Err(ChessError::MessageHandlingError(_))));
+
+        let (mut client, server) = io::duplex(1024);
+        client.write_all(&[b'{'; 200]).await.unwrap();
+        let mut reader = FrameReader::with_max_frame_len(server, Codec::JsonLines, 100);
+        assert!(matches!(reader.read::<Envelope>().await, Err(ChessError::MessageHandlingError(_))));
+
+        let mut writer = FrameWriter::with_max_frame_len(Vec::new(), Codec::Cbor, 4);
+        assert!(writer.write(&Envelope::Event(Message::Log("too long for four bytes".to_string()))).await.is_err());
+    }
+}
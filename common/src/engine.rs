@@ -0,0 +1,154 @@
+use chess::{Board, BoardStatus, ChessMove, MoveGen, Piece, ALL_SQUARES};
+
+use crate::bot::{Bot, Clock};
+
+const MATE: i32 = 100_000;
+const INFINITY: i32 = 1_000_000;
+const DEFAULT_DEPTH: u32 = 3;
+
+// Small alpha-beta searcher for offline games and as a ready-made `Bot`: material and a bonus for central pieces, nothing fancy
+pub struct Engine {
+    depth: u32, // plies searched
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEPTH)
+    }
+}
+
+impl Engine {
+    pub fn new(depth: u32) -> Self {
+        Self { depth: depth.max(1) }
+    }
+
+    // None when the game is already over
+    pub fn best_move(&self, board: &Board) -> Option<ChessMove> {
+        let mut best = None;
+        let mut alpha = -INFINITY;
+        for mov in ordered_moves(board) {
+            let score = -negamax(&board.make_move_new(mov), self.depth - 1, -INFINITY, -alpha, 1);
+            if best.is_none() || score > alpha {
+                alpha = score;
+                best = Some(mov);
+            }
+        }
+        best
+    }
+}
+
+impl Bot for Engine {
+    fn choose_move(&mut self, board: &Board, _clock: &Clock) -> Option<ChessMove> {
+        self.best_move(board)
+    }
+}
+
+// Score for the side to move; `ply` makes nearer mates count more
+fn negamax(board: &Board, depth: u32, mut alpha: i32, beta: i32, ply: i32) -> i32 {
+    match board.status() {
+        BoardStatus::Checkmate => return -MATE + ply,
+        BoardStatus::Stalemate => return 0,
+        BoardStatus::Ongoing => {}
+    }
+    if depth == 0 {
+        return evaluate(board);
+    }
+    for mov in ordered_moves(board) {
+        let score = -negamax(&board.make_move_new(mov), depth - 1, -beta, -alpha, ply + 1);
+        if score >= beta {
+            return beta;
+        }
+        alpha = alpha.max(score);
+    }
+    alpha
+}
+
+// Captures of the most valuable pieces first, so alpha-beta cuts early
+fn ordered_moves(board: &Board) -> Vec<ChessMove> {
+    let mut moves: Vec<ChessMove> = MoveGen::new_legal(board).collect();
+    moves.sort_by_key(|mov| -board.piece_on(mov.get_dest()).map_or(0, piece_value));
+    moves
+}
+
+fn evaluate(board: &Board) -> i32 {
+    let mut score = 0;
+    for square in ALL_SQUARES {
+        if let (Some(piece), Some(color)) = (board.piece_on(square), board.color_on(square)) {
+            // Distance from the four central squares, 0 in the centre to 3 in a corner
+            let file = square.get_file().to_index() as i32;
+            let rank = square.get_rank().to_index() as i32;
+            let distance = (2 * file - 7).abs().max((2 * rank - 7).abs()) / 2;
+            let centre = if piece == Piece::King { 0 } else { 3 - distance } * 5;
+            let value = piece_value(piece) + centre;
+            score += if color == board.side_to_move() { value } else { -value };
+        }
+    }
+    score
+}
+
+fn piece_value(piece: Piece) -> i32 {
+    match piece {
+        Piece::Pawn => 100,
+        Piece::Knight => 320,
+        Piece::Bishop => 330,
+        Piece::Rook => 500,
+        Piece::Queen => 900,
+        Piece::King => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Square;
+
+    use super::*;
+
+    fn board(fen: &str) -> Board {
+        Board::from_str(fen).unwrap()
+    }
+
+    #[test]
+    fn finds_mate_in_one() {
+        let back_rank = board("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+        assert_eq!(Engine::default().best_move(&back_rank), Some(ChessMove::new(Square::A1, Square::A8, None)));
+    }
+
+    #[test]
+    fn takes_a_free_queen() {
+        let hanging = board("4k3/8/8/3q4/8/8/8/3RK3 w - - 0 1");
+        for depth in 1..=3 {
+            assert_eq!(Engine::new(depth).best_move(&hanging), Some(ChessMove::new(Square::D1, Square::D5, None)), "depth {}", depth);
+        }
+    }
+
+    #[test]
+    fn avoids_losing_its_queen() {
+        // Qxb7 is answered by Rxb7
+        let poisoned = board("1r2k3/1p6/8/8/8/8/8/1Q2K3 w - - 0 1");
+        assert_ne!(Engine::new(2).best_move(&poisoned), Some(ChessMove::new(Square::B1, Square::B7, None)));
+    }
+
+    #[test]
+    fn no_move_once_the_game_is_over() {
+        let fools_mate = board("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3");
+        let stalemate = board("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        assert_eq!(Engine::default().best_move(&fools_mate), None);
+        assert_eq!(Engine::default().best_move(&stalemate), None);
+    }
+
+    #[test]
+    fn depth_zero_still_searches() {
+        assert!(Engine::new(0).best_move(&Board::default()).is_some());
+    }
+
+    #[test]
+    fn evaluation_is_symmetric() {
+        assert_eq!(evaluate(&Board::default()), 0);
+        let up_a_queen = board("4k3/8/8/8/8/8/8/3QK3 w - - 0 1");
+        let down_a_queen = board("4k3/8/8/8/8/8/8/3QK3 b - - 0 1");
+        assert!(evaluate(&up_a_queen) > 800);
+        assert_eq!(evaluate(&up_a_queen), -evaluate(&down_a_queen));
+    }
+}
@@ -1,24 +1,163 @@
+pub mod bot;
 pub mod chess_utils;
+pub mod codec;
+pub mod engine;
+pub mod pgn;
+pub mod rules;
 
 use std::fmt;
 
 use serde::{Serialize, Deserialize};
-use tokio::io::{self, AsyncReadExt};
-use tokio::net::tcp::OwnedReadHalf;
-use log::{info, error};
+use tokio::io;
 use thiserror::Error;
 
 pub const DEFAULT_HOST: &str = "127.0.0.1";
 pub const DEFAULT_PORT: &str = "11111";
+pub const DEFAULT_WS_PORT: &str = "11112";
+pub const DEFAULT_HTTP_PORT: &str = "11113";
+pub const MAX_MESSAGE_LEN: usize = 10 * 1024 * 1024;
+
+pub const PROTOCOL_VERSION: u16 = 7; // bump on every breaking change to `Message` or `Envelope`
+pub const MIN_PROTOCOL_VERSION: u16 = 2; // oldest client version the server still talks to
+pub const CHAT_PROTOCOL_VERSION: u16 = 3; // first version with `Message::Chat`, older clients get plain `Text`
+pub const GAME_START_PROTOCOL_VERSION: u16 = 4; // first version with `Message::GameStart`, older clients don't get it
+pub const GAME_END_PROTOCOL_VERSION: u16 = 5; // first version with `Message::GameEnd`, older clients only get the `Log`
+pub const SEARCH_PROTOCOL_VERSION: u16 = 6; // first version with `Command::Search` and `Message::SearchResults`
+pub const OPPONENT_GONE_PROTOCOL_VERSION: u16 = 7; // first version with `Message::OpponentGone`, older clients only get the `Log`
+
+pub type RequestId = u32;
+
+// Everything after the handshake travels inside an envelope
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Envelope {
+    Request { id: RequestId, message: Message }, // client to server, `id` is chosen by the client
+    Response { id: RequestId, message: Message }, // server reply to the request with the same `id`
+    Ack {
+        id: RequestId,
+        error: Option<String>, // sent once the request has been processed, `error` is set if it failed
+        #[serde(default)]
+        code: Option<ErrorCode>, // machine readable kind of `error`, missing from older servers
+    },
+    Event(Message), // unsolicited server push: opponent's moves, chat, game updates
+    Ping(u64), // heartbeat, carries the sender's timestamp in milliseconds since the Unix epoch
+    Pong(u64), // heartbeat reply, echoes the timestamp of the `Ping`
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     Command(Command), // technical client-server commands 
     Move(String), // chess move in algebraic notation like `e2e4`
-    Text(String), // chat messages
+    Text(String), // chat with the opponent, superseded by `Chat`
+    Chat(Chat), // chat in the lobby, in a game or directly between two users
     Board(String), // represents chess::Board and is parsed on the client
+    GameStart(GameStart), // who plays which side, sent before the first board of a game
+    GameEnd(GameEnd), // how the game ended, sent along with the usual `Log`
+    SearchResults(SearchResults), // reply to `Command::Search`
+    OpponentGone(String), // the opponent, by name, did not reconnect in time and the game can be claimed; sent along with the usual `Log`
     Error(String),
     Log(String), // other notifications from the server
+    Hello(Hello), // first message sent by the client, not wrapped in an `Envelope`
+    Welcome(Welcome), // server reply to an accepted `Hello`, not wrapped in an `Envelope`
+}
+
+// Sent to both players when the game starts, and again to a player who comes back to it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameStart {
+    pub game_id: u32,
+    pub white: String,
+    pub black: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameEnd {
+    pub result: String, // PGN notation: `1-0`, `0-1`, `1/2-1/2`, or `*` when the game was aborted
+    pub reason: String, // `checkmate`, `resignation`, `stalemate`, `draw`, `abandoned` or `aborted`
+}
+
+// Finished games to look for, every field left out matches everything
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SearchQuery {
+    pub player: Option<String>, // on either side
+    pub opponent: Option<String>, // on the other side from `player`, or on either side without it
+    pub result: Option<String>, // `1-0`, `0-1`, `1/2-1/2`, `*`, or `win`, `loss`, `draw` as seen by `player`
+    pub from: Option<String>, // `YYYY-MM-DD`, finished on or after that day (UTC)
+    pub to: Option<String>, // `YYYY-MM-DD`, finished on or before that day (UTC)
+    pub eco: Option<String>, // opening code like `C60`, or a prefix like `C6` or `C`
+    pub variant: Option<String>, // `standard`, the only one so far
+    pub time_control: Option<String>, // PGN notation, `-` for untimed, which all games are so far
+    pub page: u32, // from 0
+    pub page_size: u32, // 0 for the server's default, capped by the server
+    pub pgn: bool, // include each game's PGN
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResults {
+    pub games: Vec<ArchivedGame>, // most recently finished first
+    pub total: usize, // matches on all pages
+    pub page: u32,
+    pub page_size: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedGame {
+    pub game_id: u32,
+    pub white: String,
+    pub black: String,
+    pub result: String, // PGN notation
+    pub reason: String, // as in `GameEnd`
+    pub finished_at: u64, // seconds since the Unix epoch
+    pub eco: String,
+    pub opening: String,
+    pub variant: String,
+    pub time_control: String,
+    pub moves: usize, // half-moves played
+    pub pgn: Option<String>, // when asked for
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    RateLimited, // the request was dropped, slow down
+    Muted, // chat refused, either by a moderator or by flood protection
+    Flooding, // too many dropped requests, the server closes the connection after this
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chat {
+    pub channel: ChatChannel,
+    pub sender: String, // filled in by the server, whatever the client sends is ignored
+    pub text: String,
+    pub sent_at: u64, // milliseconds since the Unix epoch, set by the server
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChatChannel {
+    Lobby, // everyone who is logged in
+    Game, // both players of the current game
+    Direct(String), // one user, by name; the recipient when sending, the recipient as well when received
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Capability {
+    Heartbeat, // client answers `Ping`s, so the server can detect dead connections
+    Variants,
+    Clocks,
+    Compression,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub client_name: String,
+    pub client_version: String,
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    pub protocol_version: u16, // version both sides speak from now on, may be lower than the client asked for
+    pub server_version: String,
+    pub capabilities: Vec<Capability>, // capabilities supported by both sides
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -27,7 +166,17 @@ pub enum Command {
     //LogOut,   // `/log_out`
     Play,    // `/play`
     Concede, // `/concede`
+    ClaimWin,  // `/claim win`, after the opponent failed to reconnect in time
+    ClaimDraw, // `/claim draw`, same as above
     Stats,
+    // Moderation, only accepted from moderators and admins
+    Kick(String), // `/kick %username%`
+    Ban(String, Option<u64>), // `/ban %username% [minutes]`, bans the username and its current IP, permanently without minutes
+    Unban(String), // `/unban %username%`
+    Mute(String, Option<u64>), // `/mute %username% [minutes]`, no chat messages until unmuted
+    Unmute(String), // `/unmute %username%`
+    EndGame(String), // `/end %username%`, aborts the game that player is in
+    Search(SearchQuery), // `/search [player=..] [result=..] ...`, answered with `Message::SearchResults`
 }
 
 impl fmt::Display for Command {
@@ -36,8 +185,16 @@ impl fmt::Display for Command {
             Command::LogIn(username) => write!(f, "LogIn({})", username),
             Command::Play => write!(f, "Play"),
             Command::Concede => write!(f, "Concede"),
+            Command::ClaimWin => write!(f, "ClaimWin"),
+            Command::ClaimDraw => write!(f, "ClaimDraw"),
             Command::Stats => write!(f, "Stats"),
-            _ => unreachable!("Unexpected new command")
+            Command::Kick(username) => write!(f, "Kick({})", username),
+            Command::Ban(username, minutes) => write!(f, "Ban({}, {:?})", username, minutes),
+            Command::Unban(username) => write!(f, "Unban({})", username),
+            Command::Mute(username, minutes) => write!(f, "Mute({}, {:?})", username, minutes),
+            Command::Unmute(username) => write!(f, "Unmute({})", username),
+            Command::EndGame(username) => write!(f, "EndGame({})", username),
+            Command::Search(query) => write!(f, "Search({:?})", query),
         }
     }
 }
@@ -78,6 +235,24 @@ pub enum ChessError {
     UserNotFoundError,
 
 
+    #[error("protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    #[error("configuration error: {0}")]
+    ConfigError(String),
+
+    #[error("rate limit exceeded: {0}")]
+    RateLimited(String),
+
+    #[error("muted: {0}")]
+    Muted(String),
+
+    #[error("flooding: {0}")]
+    Flooding(String),
+
     #[error("sender not found for socket address: {0}")]
     SenderNotFoundError(String),
 
@@ -85,43 +260,46 @@ pub enum ChessError {
     Unknown,
 }
 
-pub fn make_io_error(e: io::Error, info: &str) -> ChessError {
-    ChessError::IoError {
-        main: e,
-        context: info.to_string(),
+impl ChessError {
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ChessError::RateLimited(_) => Some(ErrorCode::RateLimited),
+            ChessError::Muted(_) => Some(ErrorCode::Muted),
+            ChessError::Flooding(_) => Some(ErrorCode::Flooding),
+            _ => None,
+        }
     }
 }
 
-pub async fn listen_to_messages(reader: &mut OwnedReadHalf) -> Result<Message, ChessError> {
-    loop {
-        let mut len_bytes = [0u8; 4];
-
-        reader.read_exact(&mut len_bytes).await
-            .map_err(|e| make_io_error(e, "Failed to read message length"))?;
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
 
-        let len = u32::from_be_bytes(len_bytes) as usize;
-        //info!("Message length received: {}", len);
+pub fn unix_secs() -> u64 {
+    unix_millis() / 1000
+}
 
-        if len > 10 * 1024 * 1024 { 
-            return Err(ChessError::MessageHandlingError("Message length too large".to_string()));
-        }
+// `YYYY-MM-DD` in UTC, from seconds since the Unix epoch
+pub fn civil_date(unix_secs: u64) -> String {
+    // Howard Hinnant's `civil_from_days`
+    let days = (unix_secs / (24 * 60 * 60)) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153; // from March
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
 
-        let mut buffer = vec![0u8; len];
-        //info!("Buffer allocated with length: {}", buffer.len());
-
-        reader.read_exact(&mut buffer).await
-            .map_err(|e| make_io_error(e, "Failed to read message body"))?;
-
-        //info!("Message received, length: {}", buffer.len());
-        match serde_cbor::from_slice(&buffer) {
-            Ok(message) => {
-                info!("Received message (len {}): {:?}", buffer.len(), message);
-                return Ok(message)
-            }
-            Err(e) => {
-                error!("Raw data: {:?}", buffer);
-                return Err(ChessError::DeserializationError(e.to_string()))
-            }
-        }
+pub fn make_io_error(e: io::Error, info: &str) -> ChessError {
+    ChessError::IoError {
+        main: e,
+        context: info.to_string(),
     }
 }
\ No newline at end of file
@@ -0,0 +1,539 @@
+use std::str::FromStr;
+
+use chess::{Board, BoardStatus, ChessMove, Color, File, MoveGen, Piece};
+
+use crate::ChessError;
+
+const LINE_WIDTH: usize = 80; // PGN export format keeps lines under 80 characters
+const MAX_VARIATION_DEPTH: usize = 64; // variations inside variations, each level is a recursive call
+
+// Standard algebraic notation of a legal move, e.g. `Nbd7`, `exd5`, `O-O`, `e8=Q+`
+pub fn move_to_san(board: &Board, mov: ChessMove) -> String {
+    let source = mov.get_source();
+    let dest = mov.get_dest();
+    let piece = board.piece_on(source);
+    let mut san = String::new();
+
+    let is_castling = piece == Some(Piece::King) && source.get_file().to_index().abs_diff(dest.get_file().to_index()) == 2;
+    if is_castling {
+        san.push_str(if dest.get_file() == File::G { "O-O" } else { "O-O-O" });
+    } else {
+        // A pawn changing files always captures, en passant included
+        let is_capture = board.piece_on(dest).is_some() || (piece == Some(Piece::Pawn) && source.get_file() != dest.get_file());
+        match piece {
+            Some(Piece::Pawn) | None => {
+                if is_capture {
+                    san.push(file_char(source.get_file()));
+                }
+            }
+            Some(piece) => {
+                san.push_str(&piece.to_string(Color::White));
+
+                // Other pieces of the same kind that could go to the same square
+                let rivals: Vec<_> = MoveGen::new_legal(board)
+                    .filter(|m| m.get_dest() == dest && m.get_source() != source && board.piece_on(m.get_source()) == Some(piece))
+                    .map(|m| m.get_source())
+                    .collect();
+                if !rivals.is_empty() {
+                    if rivals.iter().all(|square| square.get_file() != source.get_file()) {
+                        san.push(file_char(source.get_file()));
+                    } else if rivals.iter().all(|square| square.get_rank() != source.get_rank()) {
+                        san.push_str(&(source.get_rank().to_index() + 1).to_string());
+                    } else {
+                        san.push_str(&source.to_string());
+                    }
+                }
+            }
+        }
+        if is_capture {
+            san.push('x');
+        }
+        san.push_str(&dest.to_string());
+        if let Some(promotion) = mov.get_promotion() {
+            san.push('=');
+            san.push_str(&promotion.to_string(Color::White));
+        }
+    }
+
+    let next = board.make_move_new(mov);
+    if next.status() == BoardStatus::Checkmate {
+        san.push('#');
+    } else if next.checkers().popcnt() > 0 {
+        san.push('+');
+    }
+    san
+}
+
+// The legal move that is written as `san`; check marks and annotations are optional, `0-0` is read as `O-O` and `e8Q` as `e8=Q`
+pub fn san_to_move(board: &Board, san: &str) -> Option<ChessMove> {
+    let wanted = normalize_san(san);
+    MoveGen::new_legal(board).find(|&mov| normalize_san(&move_to_san(board, mov)) == wanted)
+}
+
+// Every legal move with its SAN, sorted by SAN
+pub fn legal_moves_san(board: &Board) -> Vec<(ChessMove, String)> {
+    let mut moves: Vec<(ChessMove, String)> = MoveGen::new_legal(board)
+        .map(|mov| (mov, move_to_san(board, mov)))
+        .collect();
+    moves.sort_by(|a, b| a.1.cmp(&b.1));
+    moves
+}
+
+fn normalize_san(san: &str) -> String {
+    let san = san.trim().trim_end_matches(['+', '#', '!', '?']).replace('0', "O");
+    // A promotion with or without `=`, in either case
+    if let Some(piece) = san.chars().last().filter(|c| "QRBNqrbn".contains(*c)) {
+        let square = san[..san.len() - 1].trim_end_matches('=');
+        if square.ends_with(['1', '8']) {
+            return format!("{}={}", square, piece.to_ascii_uppercase());
+        }
+    }
+    san
+}
+
+// Tags come first in the given order, the seven tag roster (Event, Site, Date, Round, White, Black, Result) is up to the caller.
+// Moves are in coordinate notation (`e2e4`) and are replayed from the starting position to get their SAN.
+pub fn write_pgn(tags: &[(&str, String)], moves: &[String], result: &str) -> Result<String, ChessError> {
+    let mut pgn = String::new();
+    for (name, value) in tags {
+        pgn.push_str(&format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    pgn.push('\n');
+
+    let mut board = Board::default();
+    let mut tokens = Vec::new();
+    for (i, move_str) in moves.iter().enumerate() {
+        let mov = ChessMove::from_str(move_str)
+            .map_err(|_| ChessError::GameStateError(format!("Couldn't parse move {}", move_str)))?;
+        if !board.legal(mov) {
+            return Err(ChessError::GameStateError(format!("Illegal move {} at ply {}", move_str, i + 1)));
+        }
+        if i % 2 == 0 {
+            tokens.push(format!("{}.", i / 2 + 1));
+        }
+        tokens.push(move_to_san(&board, mov));
+        board = board.make_move_new(mov);
+    }
+    tokens.push(result.to_string());
+
+    let mut line_len = 0;
+    for token in tokens {
+        if line_len > 0 && line_len + 1 + token.len() > LINE_WIDTH {
+            pgn.push('\n');
+            line_len = 0;
+        } else if line_len > 0 {
+            pgn.push(' ');
+            line_len += 1;
+        }
+        line_len += token.len();
+        pgn.push_str(&token);
+    }
+    pgn.push('\n');
+    Ok(pgn)
+}
+
+// One game read from PGN
+#[derive(Debug, Clone)]
+pub struct PgnGame {
+    pub tags: Vec<(String, String)>, // in file order
+    pub start: Board, // from the `FEN` tag, the usual starting position without one
+    pub comment: Option<String>, // before the first move
+    pub moves: Vec<PgnMove>, // the main line
+    pub result: String, // `1-0`, `0-1`, `1/2-1/2` or `*`
+}
+
+#[derive(Debug, Clone)]
+pub struct PgnMove {
+    pub mov: ChessMove,
+    pub san: String, // as written in the file, without annotations
+    pub nags: Vec<u8>, // `$1`, or `!`, `?`, `!!`, `??`, `!?`, `?!` turned into their numbers
+    pub comment: Option<String>, // after the move
+    pub variations: Vec<Vec<PgnMove>>, // alternatives to this move, from the position before it
+}
+
+impl PgnGame {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str())
+    }
+
+    // `boards()[i]` is the position before `moves[i]`, the last one the position after the last move
+    pub fn boards(&self) -> Vec<Board> {
+        let mut boards = vec![self.start];
+        for mov in &self.moves {
+            let board = boards[boards.len() - 1].make_move_new(mov.mov);
+            boards.push(board);
+        }
+        boards
+    }
+}
+
+// Glyphs for the first six NAGs, the others are only shown as `$n`
+pub fn nag_symbol(nag: u8) -> Option<&'static str> {
+    match nag {
+        1 => Some("!"),
+        2 => Some("?"),
+        3 => Some("!!"),
+        4 => Some("??"),
+        5 => Some("!?"),
+        6 => Some("?!"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Tag(String, String),
+    Comment(String),
+    Nag(u8),
+    VariationStart,
+    VariationEnd,
+    Result(String),
+    San(String),
+}
+
+// Every game in `pgn`, each move checked against its position; `%` escape lines and `;` comments are skipped
+pub fn parse_pgn(pgn: &str) -> Result<Vec<PgnGame>, ChessError> {
+    let mut tokens = tokenize(pgn)?.into_iter().peekable();
+    let mut games = Vec::new();
+    while tokens.peek().is_some() {
+        let mut tags = Vec::new();
+        while let Some(Token::Tag(..)) = tokens.peek() {
+            if let Some(Token::Tag(name, value)) = tokens.next() {
+                tags.push((name, value));
+            }
+        }
+
+        let start = match tags.iter().find(|(name, _)| name == "FEN") {
+            Some((_, fen)) => Board::from_str(fen)
+                .map_err(|e| ChessError::GameStateError(format!("Invalid FEN tag {}: {}", fen, e)))?,
+            None => Board::default(),
+        };
+        let mut comment = None;
+        let (mut moves, result) = parse_line(&mut tokens, start, &mut comment, games.len() + 1, 0)?;
+        if tokens.peek() == Some(&Token::VariationEnd) {
+            return Err(ChessError::GameStateError(format!("Game {}: `)` without a variation", games.len() + 1)));
+        }
+        // Comments after the result still belong to this game, not to a next one
+        while let Some(Token::Comment(_)) = tokens.peek() {
+            if let Some(Token::Comment(text)) = tokens.next() {
+                let target = match moves.last_mut() {
+                    Some(last) => &mut last.comment,
+                    None => &mut comment,
+                };
+                append_comment(target, text);
+            }
+        }
+        let result = result
+            .or_else(|| tags.iter().find(|(name, _)| name == "Result").map(|(_, result)| result.clone()))
+            .unwrap_or_else(|| "*".to_string());
+        games.push(PgnGame { tags, start, comment, moves, result });
+    }
+    Ok(games)
+}
+
+fn append_comment(target: &mut Option<String>, text: String) {
+    *target = Some(match target.take() {
+        Some(previous) => format!("{} {}", previous, text),
+        None => text,
+    });
+}
+
+// Moves up to the end of the game or of the variation; `comment` gets what comes before the first move, `depth` counts the variations around this one
+fn parse_line<I: Iterator<Item = Token>>(tokens: &mut std::iter::Peekable<I>, start: Board, comment: &mut Option<String>, game: usize, depth: usize) -> Result<(Vec<PgnMove>, Option<String>), ChessError> {
+    let mut moves: Vec<PgnMove> = Vec::new();
+    let mut board = start;
+    let mut before = start; // the position before the last move, where its variations start
+    loop {
+        match tokens.peek() {
+            // The next game's tags, the result was left out
+            None | Some(Token::Tag(..)) => return Ok((moves, None)),
+            Some(Token::VariationEnd) => return Ok((moves, None)),
+            _ => {}
+        }
+        match tokens.next() {
+            Some(Token::Result(result)) => return Ok((moves, Some(result))),
+            Some(Token::Comment(text)) => {
+                let target = match moves.last_mut() {
+                    Some(last) => &mut last.comment,
+                    None => &mut *comment,
+                };
+                append_comment(target, text);
+            }
+            Some(Token::Nag(nag)) => match moves.last_mut() {
+                Some(last) => last.nags.push(nag),
+                None => return Err(ChessError::GameStateError(format!("Game {}: annotation ${} before any move", game, nag))),
+            },
+            Some(Token::VariationStart) => {
+                if moves.is_empty() {
+                    return Err(ChessError::GameStateError(format!("Game {}: variation before any move", game)));
+                }
+                if depth >= MAX_VARIATION_DEPTH {
+                    return Err(ChessError::GameStateError(format!("Game {}: variations nested more than {} deep", game, MAX_VARIATION_DEPTH)));
+                }
+                let mut variation_comment = None;
+                let (mut variation, _) = parse_line(tokens, before, &mut variation_comment, game, depth + 1)?;
+                if tokens.next() != Some(Token::VariationEnd) {
+                    return Err(ChessError::GameStateError(format!("Game {}: unclosed variation", game)));
+                }
+                // A comment right after `(` is about the first move of the variation
+                if let (Some(text), Some(first)) = (variation_comment, variation.first_mut()) {
+                    first.comment = Some(match first.comment.take() {
+                        Some(after) => format!("{} {}", text, after),
+                        None => text,
+                    });
+                }
+                if let Some(last) = moves.last_mut() {
+                    last.variations.push(variation);
+                }
+            }
+            Some(Token::San(token)) => {
+                let san = token.trim_end_matches(['!', '?']);
+                let nag = match &token[san.len()..] {
+                    "" => None,
+                    "!" => Some(1),
+                    "?" => Some(2),
+                    "!!" => Some(3),
+                    "??" => Some(4),
+                    "!?" => Some(5),
+                    "?!" => Some(6),
+                    other => return Err(ChessError::GameStateError(format!("Game {}: unknown annotation {}", game, other))),
+                };
+                let mov = san_to_move(&board, san)
+                    .ok_or(ChessError::GameStateError(format!("Game {}: illegal move {} after {} moves", game, san, moves.len())))?;
+                before = board;
+                board = board.make_move_new(mov);
+                moves.push(PgnMove { mov, san: san.to_string(), nags: nag.into_iter().collect(), comment: None, variations: Vec::new() });
+            }
+            Some(Token::VariationEnd) | Some(Token::Tag(..)) | None => unreachable!("handled above"),
+        }
+    }
+}
+
+fn tokenize(pgn: &str) -> Result<Vec<Token>, ChessError> {
+    let mut tokens = Vec::new();
+    let mut chars = pgn.chars().peekable();
+    let mut line_start = true;
+    while let Some(c) = chars.next() {
+        let at_line_start = line_start;
+        line_start = c == '\n';
+        match c {
+            '%' if at_line_start => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+                line_start = true;
+            }
+            ';' => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+                line_start = true;
+            }
+            '{' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => text.push(c),
+                        None => return Err(ChessError::GameStateError(format!("Unclosed comment {{{}", text.chars().take(20).collect::<String>()))),
+                    }
+                }
+                tokens.push(Token::Comment(text.split_whitespace().collect::<Vec<_>>().join(" ")));
+            }
+            '[' => {
+                // A tag is `[Name "value"]` on one line, stopping at the line end keeps a broken one from eating the games after it
+                let unclosed = |name: &str| ChessError::GameStateError(format!("Unclosed tag [{}", name.trim()));
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(']') | Some('\n') | None => return Err(unclosed(&name)),
+                        Some(c) => name.push(c),
+                    }
+                }
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next_if(|&c| c != '\n')),
+                        Some('"') => break,
+                        Some('\n') | None => return Err(unclosed(&name)),
+                        Some(c) => value.push(c),
+                    }
+                }
+                while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+                if chars.next() != Some(']') {
+                    return Err(unclosed(&name));
+                }
+                tokens.push(Token::Tag(name.trim().to_string(), value));
+            }
+            '(' => tokens.push(Token::VariationStart),
+            ')' => tokens.push(Token::VariationEnd),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{}()[];".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                if let Some(nag) = word.strip_prefix('$') {
+                    let nag = nag.parse().map_err(|_| ChessError::GameStateError(format!("Invalid annotation {}", word)))?;
+                    tokens.push(Token::Nag(nag));
+                } else if ["1-0", "0-1", "1/2-1/2", "*"].contains(&word.as_str()) {
+                    tokens.push(Token::Result(word));
+                } else {
+                    // `12.` and `12...` may be glued to the move, `0-0` has no dot
+                    let san = word.rsplit('.').next().unwrap_or("");
+                    if !san.is_empty() {
+                        tokens.push(Token::San(san.to_string()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn file_char(file: File) -> char {
+    (b'a' + file.to_index() as u8) as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(pgn: &str) -> PgnGame {
+        let mut games = parse_pgn(pgn).unwrap();
+        assert_eq!(games.len(), 1, "{}", pgn);
+        games.remove(0)
+    }
+
+    fn coordinates(moves: &[PgnMove]) -> Vec<String> {
+        moves.iter().map(|pgn_move| pgn_move.mov.to_string()).collect()
+    }
+
+    #[test]
+    fn round_trips_write_pgn() {
+        let moves: Vec<String> = ["e2e4", "e7e5", "g1f3", "b8c6", "f1b5", "a7a6", "b5c6", "d7c6", "e1g1", "f7f6", "d2d4", "e5d4", "f3d4", "c6c5"]
+            .iter().map(|mov| mov.to_string()).collect();
+        let tags = [
+            ("Event", "Test".to_string()),
+            ("White", "Hoare \"Tony\"".to_string()),
+            ("Black", "Dijkstra\\".to_string()),
+            ("Result", "1-0".to_string()),
+        ];
+        let pgn = write_pgn(&tags, &moves, "1-0").unwrap();
+
+        let game = parse_one(&pgn);
+        assert_eq!(coordinates(&game.moves), moves);
+        assert_eq!(game.result, "1-0");
+        assert_eq!(game.tag("White"), Some("Hoare \"Tony\""));
+        assert_eq!(game.tag("Black"), Some("Dijkstra\\"));
+        assert_eq!(game.moves[8].san, "O-O");
+        assert_eq!(game.boards().len(), moves.len() + 1);
+    }
+
+    #[test]
+    fn reads_comments_variations_and_nags() {
+        let game = parse_one("{Before} 1. e4! {Best by test} e5 $2 (1... c5 {Sicilian} 2. Nf3 (2. c3) 2... d6) 2. Nf3 *");
+        assert_eq!(game.comment.as_deref(), Some("Before"));
+        assert_eq!(coordinates(&game.moves), ["e2e4", "e7e5", "g1f3"]);
+        assert_eq!(game.moves[0].nags, [1]);
+        assert_eq!(game.moves[0].comment.as_deref(), Some("Best by test"));
+        assert_eq!(game.moves[1].nags, [2]);
+
+        let variation = &game.moves[1].variations[0];
+        assert_eq!(coordinates(variation), ["c7c5", "g1f3", "d7d6"]);
+        assert_eq!(variation[0].comment.as_deref(), Some("Sicilian"));
+        assert_eq!(coordinates(&variation[1].variations[0]), ["c2c3"]);
+        assert_eq!(game.result, "*");
+    }
+
+    #[test]
+    fn reads_several_games() {
+        let games = parse_pgn("[Event \"a\"]\n\n1. e4 1-0\n\n[Event \"b\"]\n\n1. d4 d5 0-1\n").unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[1].tag("Event"), Some("b"));
+        assert_eq!(coordinates(&games[1].moves), ["d2d4", "d7d5"]);
+    }
+
+    #[test]
+    fn deep_variations_are_refused_not_a_stack_overflow() {
+        let depth = 100_000;
+        let pgn = format!("1. e4 {}{} *", "(1. d4 ".repeat(depth), ")".repeat(depth));
+        assert!(matches!(parse_pgn(&pgn), Err(ChessError::GameStateError(_))));
+
+        // Up to the limit is fine
+        let pgn = format!("1. e4 {}{} *", "(1. d4 ".repeat(MAX_VARIATION_DEPTH), ")".repeat(MAX_VARIATION_DEPTH));
+        let game = parse_one(&pgn);
+        assert_eq!(coordinates(&game.moves[0].variations[0]), ["d2d4"]);
+    }
+
+    #[test]
+    fn unclosed_comment_is_an_error() {
+        assert!(matches!(parse_pgn("1. e4 {never closed e5 2. Nf3 *"), Err(ChessError::GameStateError(_))));
+    }
+
+    #[test]
+    fn unclosed_tags_are_errors() {
+        for pgn in ["[Event Casual]\n1. e4 *", "[Event \"Casual]\n1. e4 *", "[Event \"Casual\"\n1. e4 *", "[Event", "[Event \"Casual\" junk]\n*"] {
+            assert!(matches!(parse_pgn(pgn), Err(ChessError::GameStateError(_))), "{:?}", pgn);
+        }
+        // The games after a broken tag aren't swallowed into it
+        assert!(parse_pgn("[Event Casual]\n1. e4 *\n\n[Event \"Next\"]\n1. d4 *").is_err());
+    }
+
+    #[test]
+    fn tag_values_keep_escaped_quotes() {
+        let game = parse_one("[Event \"The \\\"Immortal\\\" Game\"]  \n[Site \"London\"]\n1. e4 *");
+        assert_eq!(game.tag("Event"), Some("The \"Immortal\" Game"));
+        assert_eq!(game.tag("Site"), Some("London"));
+    }
+
+    #[test]
+    fn comment_after_the_result_stays_with_the_game() {
+        let game = parse_one("1. e4 e5 1-0 {White won on time}\n");
+        assert_eq!(game.result, "1-0");
+        assert_eq!(game.moves[1].comment.as_deref(), Some("White won on time"));
+
+        let games = parse_pgn("[Event \"a\"]\n\n1. e4 1-0 {first}\n\n[Event \"b\"]\n\n1. d4 * {second}").unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].moves[0].comment.as_deref(), Some("first"));
+        assert_eq!(games[1].moves[0].comment.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn promotion_with_or_without_equals() {
+        let start = "[FEN \"8/4P3/8/8/8/8/k7/7K w - - 0 1\"]\n\n";
+        for san in ["e8=Q", "e8Q", "e8q", "e8=q", "e8Q+"] {
+            let game = parse_one(&format!("{}1. {} *", start, san));
+            assert_eq!(game.moves[0].mov.get_promotion(), Some(Piece::Queen), "{}", san);
+        }
+        let game = parse_one(&format!("{}1. e8N *", start));
+        assert_eq!(game.moves[0].mov.get_promotion(), Some(Piece::Knight));
+        assert_eq!(normalize_san("Qe8"), "Qe8");
+        assert_eq!(normalize_san("Rb1"), "Rb1");
+    }
+
+    #[test]
+    fn stray_variation_end_is_an_error() {
+        assert!(parse_pgn("1. e4 ) e5 *").is_err());
+    }
+
+    #[test]
+    fn legal_moves_are_sorted_by_san() {
+        let moves = legal_moves_san(&Board::default());
+        assert_eq!(moves.len(), 20);
+        let sans: Vec<&str> = moves.iter().map(|(_, san)| san.as_str()).collect();
+        assert_eq!(sans[..3], ["Na3", "Nc3", "Nf3"]);
+        assert!(sans.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn san_accepts_the_usual_variations() {
+        let castling = Board::from_str("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+        let short = san_to_move(&castling, "O-O").unwrap();
+        assert_eq!(san_to_move(&castling, "0-0"), Some(short));
+        assert_eq!(san_to_move(&castling, "O-O+"), Some(short));
+        assert_ne!(san_to_move(&castling, "O-O-O"), Some(short));
+        assert_eq!(san_to_move(&Board::default(), "e4!?"), san_to_move(&Board::default(), "e4"));
+    }
+}
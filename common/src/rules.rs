@@ -0,0 +1,90 @@
+use chess::{Board, BoardStatus, Color, GameResult};
+
+// How the game stands on the board after a move: checkmate and stalemate end it, resignations and draws are up to the players.
+// Shared by the server and offline games so both end the same way.
+pub fn board_result(board: &Board) -> Option<GameResult> {
+    match board.status() {
+        BoardStatus::Checkmate if board.side_to_move() == Color::White => Some(GameResult::BlackCheckmates),
+        BoardStatus::Checkmate => Some(GameResult::WhiteCheckmates),
+        BoardStatus::Stalemate => Some(GameResult::Stalemate),
+        BoardStatus::Ongoing => None,
+    }
+}
+
+pub fn pgn_result(result: Option<GameResult>) -> &'static str {
+    match result {
+        Some(GameResult::WhiteCheckmates) | Some(GameResult::BlackResigns) => "1-0",
+        Some(GameResult::BlackCheckmates) | Some(GameResult::WhiteResigns) => "0-1",
+        Some(GameResult::Stalemate) | Some(GameResult::DrawAccepted) | Some(GameResult::DrawDeclared) => "1/2-1/2",
+        None => "*",
+    }
+}
+
+// Back from a PGN result to how the game ended on `board`, its final position: a decisive result that isn't checkmate was a resignation,
+// a draw that isn't stalemate was agreed
+pub fn result_from_pgn(result: &str, board: &Board) -> Option<GameResult> {
+    board_result(board).or(match result {
+        "1-0" => Some(GameResult::BlackResigns),
+        "0-1" => Some(GameResult::WhiteResigns),
+        "1/2-1/2" => Some(GameResult::DrawDeclared),
+        _ => None,
+    })
+}
+
+// As in `Message::GameEnd`, claims of abandoned games say so themselves
+pub fn end_reason(result: Option<GameResult>) -> &'static str {
+    match result {
+        Some(GameResult::WhiteCheckmates) | Some(GameResult::BlackCheckmates) => "checkmate",
+        Some(GameResult::WhiteResigns) | Some(GameResult::BlackResigns) => "resignation",
+        Some(GameResult::Stalemate) => "stalemate",
+        Some(GameResult::DrawAccepted) | Some(GameResult::DrawDeclared) => "draw",
+        None => "aborted",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn board(fen: &str) -> Board {
+        Board::from_str(fen).unwrap()
+    }
+
+    fn fools_mate() -> Board {
+        board("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3")
+    }
+
+    fn stalemate() -> Board {
+        board("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1")
+    }
+
+    #[test]
+    fn the_board_decides_mate_and_stalemate() {
+        assert_eq!(board_result(&fools_mate()), Some(GameResult::BlackCheckmates));
+        assert_eq!(board_result(&stalemate()), Some(GameResult::Stalemate));
+        assert_eq!(board_result(&Board::default()), None);
+    }
+
+    #[test]
+    fn pgn_results_come_back_as_they_ended() {
+        assert_eq!(result_from_pgn("0-1", &fools_mate()), Some(GameResult::BlackCheckmates));
+        assert_eq!(result_from_pgn("1-0", &Board::default()), Some(GameResult::BlackResigns));
+        assert_eq!(result_from_pgn("0-1", &Board::default()), Some(GameResult::WhiteResigns));
+        assert_eq!(result_from_pgn("1/2-1/2", &stalemate()), Some(GameResult::Stalemate));
+        assert_eq!(result_from_pgn("1/2-1/2", &Board::default()), Some(GameResult::DrawDeclared));
+        assert_eq!(result_from_pgn("*", &Board::default()), None);
+    }
+
+    #[test]
+    fn every_result_round_trips_through_pgn() {
+        for result in [GameResult::WhiteResigns, GameResult::BlackResigns, GameResult::DrawDeclared] {
+            assert_eq!(result_from_pgn(pgn_result(Some(result)), &Board::default()), Some(result));
+        }
+        assert_eq!(pgn_result(None), "*");
+        assert_eq!(end_reason(Some(GameResult::DrawAccepted)), "draw");
+        assert_eq!(end_reason(Some(GameResult::WhiteCheckmates)), "checkmate");
+        assert_eq!(end_reason(None), "aborted");
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chess::{Board, MoveGen};
+
+// Known node counts, one position per line in the usual EPD perft format: `<fen> ;D1 20 ;D2 400 ...`
+pub const SUITE: &str = include_str!("../suite.epd");
+
+// What perft needs from a move generator. The `chess` crate's `Board` is the default;
+// a variant's own move generation implements this to be checked the same way, `perft::<MyVariant>`.
+pub trait Position: Sized {
+    type Move: fmt::Display;
+
+    fn from_fen(fen: &str) -> Result<Self, String>;
+
+    fn legal_moves(&self) -> Vec<Self::Move>;
+
+    fn play(&self, mov: &Self::Move) -> Self;
+
+    // The last ply is counted, not played; override when counting is cheaper than listing
+    fn count_legal_moves(&self) -> u64 {
+        self.legal_moves().len() as u64
+    }
+}
+
+impl Position for Board {
+    type Move = chess::ChessMove;
+
+    fn from_fen(fen: &str) -> Result<Self, String> {
+        Board::from_str(fen).map_err(|e| e.to_string())
+    }
+
+    fn legal_moves(&self) -> Vec<Self::Move> {
+        MoveGen::new_legal(self).collect()
+    }
+
+    fn play(&self, mov: &Self::Move) -> Self {
+        self.make_move_new(*mov)
+    }
+
+    fn count_legal_moves(&self) -> u64 {
+        MoveGen::new_legal(self).len() as u64
+    }
+}
+
+// Leaf nodes `depth` plies down
+pub fn perft<P: Position>(position: &P, depth: u32) -> u64 {
+    match depth {
+        0 => 1,
+        1 => position.count_legal_moves(),
+        _ => position.legal_moves().iter().map(|mov| perft(&position.play(mov), depth - 1)).sum(),
+    }
+}
+
+// The same count per first move, sorted by the moves' notation
+pub fn divide<P: Position>(position: &P, depth: u32) -> Vec<(P::Move, u64)> {
+    let mut counts: Vec<(P::Move, u64)> = position.legal_moves().into_iter()
+        .map(|mov| {
+            let nodes = perft(&position.play(&mov), depth.saturating_sub(1));
+            (mov, nodes)
+        })
+        .collect();
+    counts.sort_by_key(|(mov, _)| mov.to_string());
+    counts
+}
+
+// One `D<depth> <nodes>` field of a suite line
+pub fn expected_count(field: &str) -> Option<(u32, u64)> {
+    let (depth, nodes) = field.trim().strip_prefix('D')?.split_once(' ')?;
+    Some((depth.parse().ok()?, nodes.trim().parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suite_positions() -> impl Iterator<Item = (Board, Vec<(u32, u64)>)> {
+        SUITE.lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut fields = line.split(';');
+                let board = Board::from_fen(fields.next().unwrap().trim()).unwrap();
+                let counts = fields.map(|field| expected_count(field).unwrap()).collect();
+                (board, counts)
+            })
+    }
+
+    #[test]
+    fn bundled_suite_at_shallow_depths() {
+        for (board, counts) in suite_positions() {
+            for (depth, expected) in counts.into_iter().filter(|(depth, _)| *depth <= 2) {
+                assert_eq!(perft(&board, depth), expected, "{} at depth {}", board, depth);
+            }
+        }
+    }
+
+    #[test]
+    fn divide_adds_up_to_perft() {
+        for (board, _) in suite_positions() {
+            let counts = divide(&board, 2);
+            assert_eq!(counts.len() as u64, perft(&board, 1), "{}", board);
+            assert_eq!(counts.iter().map(|(_, nodes)| nodes).sum::<u64>(), perft(&board, 2), "{}", board);
+        }
+    }
+
+    #[test]
+    fn reads_suite_fields() {
+        assert_eq!(expected_count(" D3 8902 "), Some((3, 8902)));
+        assert_eq!(expected_count("D3"), None);
+        assert_eq!(expected_count("3 8902"), None);
+    }
+}
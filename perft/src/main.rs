@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use chess::Board;
+
+use perft::{Position, SUITE, divide, expected_count, perft};
+
+const DEFAULT_SUITE_DEPTH: u32 = 4; // deep enough to catch castling, en passant and promotion bugs in a few seconds
+
+const USAGE: &str = "Usage:
+  perft [--fen <fen>] <depth>           count the leaf nodes
+  perft divide [--fen <fen>] <depth>    count them per first move
+  perft suite [--file <epd>] [--depth <max>]  check the known positions, the bundled ones by default";
+
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    let mode = match args.peek().map(String::as_str) {
+        Some("divide") | Some("suite") => args.next(),
+        _ => None,
+    };
+
+    let mut fen = None;
+    let mut file = None;
+    let mut depth = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fen" => fen = args.next(),
+            "--file" => file = args.next(),
+            "--depth" => depth = args.next(),
+            _ if depth.is_none() => depth = Some(arg),
+            _ => usage(),
+        }
+    }
+    let depth = depth.map(|depth| depth.parse::<u32>().unwrap_or_else(|_| usage()));
+
+    // The `chess` crate's move generation; a variant's would be picked here
+    let ok = match mode.as_deref() {
+        Some("suite") => run_suite::<Board>(file.as_deref(), depth.unwrap_or(DEFAULT_SUITE_DEPTH)),
+        mode => {
+            let board = match fen {
+                Some(fen) => Board::from_fen(&fen).unwrap_or_else(|e| {
+                    eprintln!("Invalid FEN {}: {}", fen, e);
+                    std::process::exit(2);
+                }),
+                None => Board::default(),
+            };
+            let depth = depth.unwrap_or_else(|| usage());
+            if mode == Some("divide") {
+                run_divide(&board, depth);
+            } else {
+                run_perft(&board, depth);
+            }
+            true
+        }
+    };
+    if !ok {
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn run_perft<P: Position>(board: &P, depth: u32) {
+    let started = Instant::now();
+    let nodes = perft(board, depth);
+    let elapsed = started.elapsed();
+    println!("depth {}: {} nodes in {:.3}s, {} nodes/s", depth, nodes, elapsed.as_secs_f64(), nodes_per_second(nodes, elapsed));
+}
+
+// Same output as most engines' `go perft`, so a diff against one of them shows the first move that goes wrong
+fn run_divide<P: Position>(board: &P, depth: u32) {
+    let started = Instant::now();
+    let counts = divide(board, depth);
+    let elapsed = started.elapsed();
+    for (mov, nodes) in &counts {
+        println!("{}: {}", mov, nodes);
+    }
+    let nodes: u64 = counts.iter().map(|(_, nodes)| nodes).sum();
+    println!();
+    println!("Moves: {}", counts.len());
+    println!("Nodes: {} in {:.3}s, {} nodes/s", nodes, elapsed.as_secs_f64(), nodes_per_second(nodes, elapsed));
+}
+
+// True when every count matches
+fn run_suite<P: Position>(file: Option<&str>, max_depth: u32) -> bool {
+    let suite = match file {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read {}: {}", path, e);
+            std::process::exit(2);
+        }),
+        None => SUITE.to_string(),
+    };
+
+    let mut failures = 0;
+    let mut total_nodes = 0;
+    let mut total_time = Duration::ZERO;
+    for (line_number, line) in suite.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#')) {
+        let mut fields = line.split(';');
+        let fen = fields.next().unwrap_or("").trim();
+        let board = match P::from_fen(fen) {
+            Ok(board) => board,
+            Err(e) => {
+                println!("line {}: invalid FEN {}: {}", line_number + 1, fen, e);
+                failures += 1;
+                continue;
+            }
+        };
+        println!("{}", fen);
+
+        for field in fields {
+            let Some((depth, expected)) = expected_count(field) else {
+                println!("  line {}: can't read `{}`, expected `D<depth> <nodes>`", line_number + 1, field.trim());
+                failures += 1;
+                continue;
+            };
+            if depth > max_depth {
+                continue;
+            }
+
+            let started = Instant::now();
+            let nodes = perft(&board, depth);
+            let elapsed = started.elapsed();
+            total_nodes += nodes;
+            total_time += elapsed;
+            if nodes == expected {
+                println!("  depth {}: {} ok ({:.3}s)", depth, nodes, elapsed.as_secs_f64());
+            } else {
+                println!("  depth {}: {} FAILED, expected {}", depth, nodes, expected);
+                failures += 1;
+            }
+        }
+    }
+
+    println!();
+    println!("{} nodes in {:.3}s, {} nodes/s", total_nodes, total_time.as_secs_f64(), nodes_per_second(total_nodes, total_time));
+    if failures > 0 {
+        println!("{} FAILED", failures);
+    } else {
+        println!("All passed");
+    }
+    failures == 0
+}
+
+fn nodes_per_second(nodes: u64, elapsed: Duration) -> u64 {
+    (nodes as f64 / elapsed.as_secs_f64().max(1e-9)) as u64
+}
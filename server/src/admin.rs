@@ -0,0 +1,209 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::{Form, Router};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use log::{info, error};
+
+use common::ChessError;
+
+use crate::moderation::{kick_user, ban_user, unban_user, end_game};
+use crate::{ServerState, broadcast_notice};
+
+#[derive(Deserialize)]
+struct UserForm {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct GameForm {
+    game_id: u32,
+}
+
+#[derive(Deserialize)]
+struct AnnouncementForm {
+    text: String,
+}
+
+// Only mounted when `admin.password` is set
+pub fn router(server_state: Arc<ServerState>) -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/kick", post(kick))
+        .route("/ban", post(ban))
+        .route("/unban", post(unban))
+        .route("/abort", post(abort))
+        .route("/broadcast", post(broadcast))
+        .layer(middleware::from_fn_with_state(server_state, require_admin))
+}
+
+// HTTP basic auth, plus a same-origin check so other sites can't submit the forms with the browser's cached credentials
+async fn require_admin(State(server_state): State<Arc<ServerState>>, request: Request, next: Next) -> Response {
+    let admin = &server_state.config.admin;
+    let expected = format!("{}:{}", admin.username, admin.password.as_deref().unwrap_or_default());
+    let authorized = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| BASE64.decode(credentials).ok())
+        // Constant time, so response times don't give away how much of a guess was right
+        .is_some_and(|credentials| bool::from(credentials.ct_eq(expected.as_bytes())));
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"chess admin\"")]).into_response();
+    }
+
+    if request.method() != "GET" && !same_origin(request.headers()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+fn same_origin(headers: &HeaderMap) -> bool {
+    let host = headers.get(header::HOST).and_then(|value| value.to_str().ok());
+    match headers.get(header::ORIGIN).and_then(|value| value.to_str().ok()) {
+        Some(origin) => host.is_some_and(|host| origin.split("://").nth(1) == Some(host)),
+        None => true, // not sent by a browser
+    }
+}
+
+async fn dashboard(State(server_state): State<Arc<ServerState>>) -> Html<String> {
+    let mut page = String::new();
+    let _ = write!(page, "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>chess-rs admin</title>\
+        <style>body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; margin-bottom: 2em; }} \
+        td, th {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }} form {{ display: inline; }}</style></head><body>");
+
+    // Health
+    let uptime = server_state.started_at.elapsed().as_secs();
+    // Copied out, so the sessions aren't locked while taking the next lock
+    let (connections, round_trip_times): (usize, Vec<u128>) = {
+        let sessions = server_state.client_sessions.lock().await;
+        (sessions.len(), sessions.values().filter_map(|session| session.round_trip_time).map(|rtt| rtt.as_millis()).collect())
+    };
+    let anon_connections = server_state.anon_user_connections.lock().await.len();
+    let average_rtt = if round_trip_times.is_empty() { "n/a".to_string() } else { format!("{} ms", round_trip_times.iter().sum::<u128>() / round_trip_times.len() as u128) };
+    let _ = write!(page, "<h1>chess-rs {}</h1><h2>Health</h2><table>\
+        <tr><th>Uptime</th><td>{}h {}m {}s</td></tr>\
+        <tr><th>Connections</th><td>{}</td></tr>\
+        <tr><th>Anonymous connections</th><td>{}</td></tr>\
+        <tr><th>Average round-trip time</th><td>{}</td></tr>\
+        <tr><th>Shutting down</th><td>{}</td></tr></table>",
+        env!("CARGO_PKG_VERSION"), uptime / 3600, uptime / 60 % 60, uptime % 60, connections,
+        anon_connections, average_rtt,
+        server_state.shutting_down.load(std::sync::atomic::Ordering::SeqCst));
+
+    // Announcements
+    let _ = write!(page, "<h2>Announcement</h2><form method=\"post\" action=\"/admin/broadcast\">\
+        <input name=\"text\" size=\"60\" required> <button>Send to everyone</button></form>");
+
+    // Online users
+    // One lock at a time, other tasks take these two in either order
+    let usernames: Vec<String> = server_state.user_connections.lock().await.keys().cloned().collect();
+    let user_to_game = server_state.user_to_game.lock().await;
+    let mut users: Vec<(String, Option<u32>)> = usernames.into_iter()
+        .map(|username| {
+            let game_id = user_to_game.get(&username).cloned();
+            (username, game_id)
+        })
+        .collect();
+    drop(user_to_game);
+    users.sort();
+    let _ = write!(page, "<h2>Online users ({})</h2><table><tr><th>User</th><th>Game</th><th></th></tr>", users.len());
+    for (username, game_id) in users {
+        let username = escape(&username);
+        let _ = write!(page, "<tr><td>{}</td><td>{}</td><td>{}{}</td></tr>", username,
+            game_id.map(|id| id.to_string()).unwrap_or_default(),
+            user_button("kick", "Kick", &username), user_button("ban", "Ban", &username));
+    }
+    let _ = write!(page, "</table>");
+
+    // Banned users
+    let mut bans: Vec<_> = server_state.bans.lock().await.iter().filter(|ban| ban.is_active()).cloned().collect();
+    bans.sort_by(|a, b| a.username.cmp(&b.username));
+    let _ = write!(page, "<h2>Banned users ({})</h2><table><tr><th>User</th><th>IP</th><th>Duration</th><th>By</th><th></th></tr>", bans.len());
+    for ban in bans {
+        let username = escape(&ban.username);
+        let _ = write!(page, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>", username,
+            ban.ip.map(|ip| ip.to_string()).unwrap_or_default(), ban.describe(), escape(&ban.by),
+            user_button("unban", "Unban", &username));
+    }
+    let _ = write!(page, "</table>");
+
+    // Live games
+    let mut games: Vec<_> = server_state.games.lock().await.iter().map(|(&id, game)| (id, game.clone())).collect();
+    games.sort_by_key(|(id, _)| *id);
+    let _ = write!(page, "<h2>Live games ({})</h2><table><tr><th>ID</th><th>White</th><th>Black</th><th>Status</th><th>Moves</th><th>FEN</th><th></th></tr>", games.len());
+    for (game_id, game_arc) in games {
+        let game = game_arc.lock().await;
+        let _ = write!(page, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td><code>{}</code></td>\
+            <td><form method=\"post\" action=\"/admin/abort\"><input type=\"hidden\" name=\"game_id\" value=\"{}\"><button>Abort</button></form></td></tr>",
+            game_id, escape(game.white.as_deref().unwrap_or("-")), escape(game.black.as_deref().unwrap_or("-")),
+            game.status, game.moves.len(), game.board, game_id);
+    }
+    let _ = write!(page, "</table>");
+
+    // Finished games
+    let mut games: Vec<_> = server_state.finished_games.lock().await.iter().map(|(&id, game)| (id, game.clone())).collect();
+    games.sort_by_key(|(id, _)| std::cmp::Reverse(*id));
+    let _ = write!(page, "<h2>Finished games ({})</h2><table><tr><th>ID</th><th>White</th><th>Black</th><th>Status</th><th>Result</th><th>Moves</th></tr>", games.len());
+    for (game_id, game_arc) in games {
+        let game = game_arc.lock().await;
+        let _ = write!(page, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td></tr>",
+            game_id, escape(game.white.as_deref().unwrap_or("-")), escape(game.black.as_deref().unwrap_or("-")),
+            game.status, game.result.map(|result| format!("{:?}", result)).unwrap_or_default(), game.moves.len());
+    }
+    let _ = write!(page, "</table></body></html>");
+
+    Html(page)
+}
+
+// Panel actions show up in the audit log under the admin's name
+async fn kick(State(server_state): State<Arc<ServerState>>, Form(form): Form<UserForm>) -> Response {
+    respond(kick_user(&form.username, "You have been kicked by an administrator.", &server_state.config.admin.username, &server_state).await)
+}
+
+async fn ban(State(server_state): State<Arc<ServerState>>, Form(form): Form<UserForm>) -> Response {
+    respond(ban_user(&form.username, None, &server_state.config.admin.username, &server_state).await.map(|_| ()))
+}
+
+async fn unban(State(server_state): State<Arc<ServerState>>, Form(form): Form<UserForm>) -> Response {
+    respond(unban_user(&form.username, &server_state.config.admin.username, &server_state).await)
+}
+
+async fn abort(State(server_state): State<Arc<ServerState>>, Form(form): Form<GameForm>) -> Response {
+    respond(end_game(form.game_id, &server_state.config.admin.username, &server_state).await)
+}
+
+async fn broadcast(State(server_state): State<Arc<ServerState>>, Form(form): Form<AnnouncementForm>) -> Response {
+    let senders = broadcast_notice(&format!("Announcement: {}", form.text), &server_state).await;
+    info!("Announcement sent to {} clients: {}", senders.len(), form.text);
+    Redirect::to("/admin").into_response()
+}
+
+fn respond(result: Result<(), ChessError>) -> Response {
+    match result {
+        Ok(()) => Redirect::to("/admin").into_response(),
+        Err(e) => {
+            error!("Admin action failed: {}", e);
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
+
+fn user_button(action: &str, label: &str, username: &str) -> String {
+    format!("<form method=\"post\" action=\"/admin/{}\"><input type=\"hidden\" name=\"username\" value=\"{}\"><button>{}</button></form>", action, username, label)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use axum::{Json, Router};
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use chess::Color;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use log::error;
+
+use common::{ChessError, SearchQuery};
+
+use crate::ServerState;
+use crate::chess_game::Game;
+use crate::moderation::find_ban;
+use crate::search::search;
+
+#[derive(Serialize)]
+struct Stats {
+    version: &'static str,
+    uptime_secs: u64,
+    registered_users: usize,
+    online_users: usize,
+    anonymous_connections: usize,
+    live_games: usize,
+    finished_games: usize,
+}
+
+#[derive(Serialize)]
+struct UserSummary {
+    username: String,
+    online: bool,
+    game_id: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct UserDetails {
+    username: String,
+    online: bool,
+    game_id: Option<u32>,
+    banned: bool,
+    games_played: u32, // finished games since the server started
+    wins: u32,
+    losses: u32,
+    draws: u32,
+}
+
+#[derive(Serialize)]
+struct GameSummary {
+    id: u32,
+    white: Option<String>,
+    black: Option<String>,
+    status: String,
+    turn: &'static str,
+    fen: String,
+    moves: Vec<String>,
+    result: String, // PGN notation: `1-0`, `0-1`, `1/2-1/2` or `*`
+}
+
+// Read-only, for dashboards and other tools that don't speak the game protocol
+pub fn router() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/stats", get(stats))
+        .route("/users", get(users))
+        .route("/users/:username", get(user))
+        .route("/games", get(live_games))
+        .route("/games/finished", get(finished_games))
+        .route("/games/search", get(search_games))
+        .route("/games/:id", get(game))
+        .route("/games/:id/pgn", get(game_pgn))
+}
+
+async fn stats(State(server_state): State<Arc<ServerState>>) -> Response {
+    let registered_users = server_state.registered_users.lock().await.len();
+    Json(Stats {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: server_state.started_at.elapsed().as_secs(),
+        registered_users,
+        online_users: server_state.user_connections.lock().await.len(),
+        anonymous_connections: server_state.anon_user_connections.lock().await.len(),
+        live_games: server_state.games.lock().await.len(),
+        finished_games: server_state.finished_games.lock().await.len(),
+    }).into_response()
+}
+
+async fn users(State(server_state): State<Arc<ServerState>>) -> Response {
+    let usernames: Vec<String> = server_state.registered_users.lock().await.iter().cloned().collect();
+    // One lock at a time, other tasks take these two in either order
+    let online: HashSet<String> = server_state.user_connections.lock().await.keys().cloned().collect();
+    let user_to_game = server_state.user_to_game.lock().await;
+    let users: Vec<UserSummary> = usernames.into_iter()
+        .map(|username| UserSummary {
+            online: online.contains(&username),
+            game_id: user_to_game.get(&username).cloned(),
+            username,
+        })
+        .collect();
+    Json(users).into_response()
+}
+
+async fn user(State(server_state): State<Arc<ServerState>>, Path(username): Path<String>) -> Response {
+    if !server_state.registered_users.lock().await.contains(&username) {
+        return not_found(&format!("User {} not found", username));
+    }
+
+    let online = server_state.user_connections.lock().await.contains_key(&username);
+    let game_id = server_state.user_to_game.lock().await.get(&username).cloned();
+    let mut details = UserDetails {
+        online,
+        game_id,
+        banned: find_ban(&username, None, &server_state).await.is_some(),
+        games_played: 0,
+        wins: 0,
+        losses: 0,
+        draws: 0,
+        username,
+    };
+
+    let finished_games: Vec<Arc<Mutex<Game>>> = server_state.finished_games.lock().await.values().cloned().collect();
+    for game_arc in finished_games {
+        let game = game_arc.lock().await;
+        let color = if game.white.as_ref() == Some(&details.username) {
+            Color::White
+        } else if game.black.as_ref() == Some(&details.username) {
+            Color::Black
+        } else {
+            continue;
+        };
+        match (game.pgn_result(), color) {
+            ("1-0", Color::White) | ("0-1", Color::Black) => details.wins += 1,
+            ("1-0", Color::Black) | ("0-1", Color::White) => details.losses += 1,
+            ("1/2-1/2", _) => details.draws += 1,
+            _ => continue, // aborted, doesn't count
+        }
+        details.games_played += 1;
+    }
+
+    Json(details).into_response()
+}
+
+async fn live_games(State(server_state): State<Arc<ServerState>>) -> Response {
+    let games = server_state.games.lock().await.iter().map(|(&id, game)| (id, game.clone())).collect();
+    Json(summarize(games).await).into_response()
+}
+
+async fn finished_games(State(server_state): State<Arc<ServerState>>) -> Response {
+    let games = server_state.finished_games.lock().await.iter().map(|(&id, game)| (id, game.clone())).collect();
+    Json(summarize(games).await).into_response()
+}
+
+// Same query as `/search`: `/api/games/search?player=Hoare&result=win&from=2024-01-01&page=1&pgn=true`
+async fn search_games(State(server_state): State<Arc<ServerState>>, Query(query): Query<SearchQuery>) -> Response {
+    match search(&query, &server_state).await {
+        Ok(results) => Json(results).into_response(),
+        Err(e @ ChessError::MessageHandlingError(_)) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e.to_string() }))).into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn game(State(server_state): State<Arc<ServerState>>, Path(game_id): Path<u32>) -> Response {
+    match find_game(game_id, &server_state).await {
+        Some(game_arc) => Json(summary(game_id, &*game_arc.lock().await)).into_response(),
+        None => not_found(&format!("Game {} not found", game_id)),
+    }
+}
+
+async fn game_pgn(State(server_state): State<Arc<ServerState>>, Path(game_id): Path<u32>) -> Response {
+    let pgn = match find_game(game_id, &server_state).await {
+        Some(game_arc) => game_arc.lock().await.to_pgn(game_id),
+        // Games from before the last restart are only in the archive
+        None => match server_state.archive.lock().await.iter().find(|game| game.game_id == game_id) {
+            Some(game) => Ok(game.pgn.clone().unwrap_or_default()),
+            None => return not_found(&format!("Game {} not found", game_id)),
+        },
+    };
+    match pgn {
+        Ok(pgn) => ([(header::CONTENT_TYPE, "application/x-chess-pgn")], pgn).into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn find_game(game_id: u32, server_state: &Arc<ServerState>) -> Option<Arc<Mutex<Game>>> {
+    if let Some(game_arc) = server_state.games.lock().await.get(&game_id) {
+        return Some(game_arc.clone());
+    }
+    server_state.finished_games.lock().await.get(&game_id).cloned()
+}
+
+async fn summarize(mut games: Vec<(u32, Arc<Mutex<Game>>)>) -> Vec<GameSummary> {
+    games.sort_by_key(|(id, _)| *id);
+    let mut summaries = Vec::new();
+    for (game_id, game_arc) in games {
+        summaries.push(summary(game_id, &*game_arc.lock().await));
+    }
+    summaries
+}
+
+fn summary(game_id: u32, game: &Game) -> GameSummary {
+    GameSummary {
+        id: game_id,
+        white: game.white.clone(),
+        black: game.black.clone(),
+        status: format!("{:?}", game.status),
+        turn: if game.current_turn == Color::White { "white" } else { "black" },
+        fen: game.board.to_string(),
+        moves: game.moves.clone(),
+        result: game.pgn_result().to_string(),
+    }
+}
+
+fn not_found(message: &str) -> Response {
+    (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+fn internal_error(e: ChessError) -> Response {
+    error!("API request failed: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e.to_string() }))).into_response()
+}
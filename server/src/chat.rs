@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc::Sender;
+use log::info;
+
+use common::{Chat, ChatChannel, ChessError, Envelope, Message, RequestId, CHAT_PROTOCOL_VERSION, unix_millis};
+
+use crate::moderation::is_muted;
+use crate::{ServerState, identify_game, identify_opponent, protocol_version_of, send_message};
+
+pub async fn process_chat(mut chat: Chat, request_id: RequestId, username: &String, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let sender = server_state.user_connections.lock().await.get(username).cloned()
+        .ok_or(ChessError::UserNotFoundError)?;
+
+    if is_muted(username, server_state).await {
+        send_message(username, Envelope::Response { id: request_id, message: Message::Error("You are muted.".to_string()) }, &sender).await?;
+        return Err(ChessError::Muted(format!("{} is muted", username)));
+    }
+
+    // Clients don't get to pick their own name or time
+    chat.sender = username.clone();
+    chat.sent_at = unix_millis();
+    chat.text = mask_banned_words(&chat.text, &server_state.config.chat.banned_words);
+    info!("{} says in {:?}: {}", username, chat.channel, chat.text);
+
+    let error = match chat.channel.clone() {
+        ChatChannel::Lobby => {
+            let recipients: Vec<(String, Sender<Envelope>)> = server_state.user_connections.lock().await.iter()
+                .filter(|(recipient, _)| *recipient != username)
+                .map(|(recipient, sender)| (recipient.clone(), sender.clone()))
+                .collect();
+            for (recipient, recipient_sender) in recipients {
+                deliver(&recipient, &chat, &recipient_sender, server_state).await;
+            }
+            None
+        }
+        ChatChannel::Game => match identify_game(username, server_state).await {
+            Ok(game_arc) => {
+                game_arc.lock().await.chat.push(chat.clone());
+                if let Some(opponent) = identify_opponent(username.clone(), server_state).await? {
+                    if let Some(opponent_sender) = server_state.user_connections.lock().await.get(&opponent).cloned() {
+                        deliver(&opponent, &chat, &opponent_sender, server_state).await;
+                    }
+                }
+                None
+            }
+            Err(_) => Some("You are not in a game, use /chat lobby or /msg to talk to other players.".to_string()),
+        },
+        ChatChannel::Direct(recipient) => {
+            let recipient_sender = server_state.user_connections.lock().await.get(&recipient).cloned();
+            match recipient_sender {
+                Some(recipient_sender) => {
+                    deliver(&recipient, &chat, &recipient_sender, server_state).await;
+                    None
+                }
+                None => Some(format!("{} is not online.", recipient)),
+            }
+        }
+    };
+
+    if let Some(error) = error {
+        send_message(username, Envelope::Response { id: request_id, message: Message::Error(error.clone()) }, &sender).await?;
+        return Err(ChessError::UserStateError(error));
+    }
+    Ok(())
+}
+
+// Whole words only, so a banned "ass" leaves "class" alone; punctuation around a word doesn't hide it
+fn mask_banned_words(text: &str, banned_words: &[String]) -> String {
+    if banned_words.is_empty() {
+        return text.to_string();
+    }
+
+    let mut masked = String::with_capacity(text.len());
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if c.is_alphanumeric() {
+            word.push(c);
+            continue;
+        }
+        if banned_words.iter().any(|banned| banned.to_lowercase() == word.to_lowercase()) {
+            masked.extend(word.chars().map(|_| '*'));
+        } else {
+            masked.push_str(&word);
+        }
+        word.clear();
+        masked.push(c);
+    }
+    masked.pop(); // the trailing space added above
+    masked
+}
+
+// Sent to everyone in the game after they come back
+pub async fn replay_game_chat(username: &String, request_id: RequestId, sender: &Sender<Envelope>, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let game_arc = identify_game(username, server_state).await?;
+    let history = game_arc.lock().await.chat.clone();
+    if history.is_empty() {
+        return Ok(());
+    }
+
+    send_message(username, Envelope::Response { id: request_id, message: Message::Log(format!("Chat history ({} messages):", history.len())) }, sender).await?;
+    let old_client = !speaks_chat(username, server_state).await;
+    for chat in history {
+        send_message(username, Envelope::Response { id: request_id, message: chat_message(chat, old_client) }, sender).await?;
+    }
+    Ok(())
+}
+
+// Clients from before `Message::Chat` get the sender folded into a plain `Text`.
+// try_send, so one user who stopped reading doesn't hold up a lobby message for everyone.
+async fn deliver(recipient: &str, chat: &Chat, sender: &Sender<Envelope>, server_state: &Arc<ServerState>) {
+    let old_client = !speaks_chat(recipient, server_state).await;
+    let _ = sender.try_send(Envelope::Event(chat_message(chat.clone(), old_client)));
+}
+
+fn chat_message(chat: Chat, old_client: bool) -> Message {
+    if old_client {
+        Message::Text(format!("{}: {}", chat.sender, chat.text))
+    } else {
+        Message::Chat(chat)
+    }
+}
+
+async fn speaks_chat(username: &str, server_state: &Arc<ServerState>) -> bool {
+    protocol_version_of(username, server_state).await
+        .is_some_and(|protocol_version| protocol_version >= CHAT_PROTOCOL_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::mpsc::Receiver;
+
+    use common::PROTOCOL_VERSION;
+
+    use crate::config::Config;
+    use crate::tests::{connect, test_state};
+
+    fn chat(channel: ChatChannel, text: &str) -> Chat {
+        Chat { channel, sender: "someone else".to_string(), text: text.to_string(), sent_at: 0 }
+    }
+
+    fn received(receiver: &mut Receiver<Envelope>) -> Option<Message> {
+        match receiver.try_recv() {
+            Ok(Envelope::Event(message)) | Ok(Envelope::Response { message, .. }) => Some(message),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn masks_whole_words_only() {
+        let banned = vec!["darn".to_string(), "Heck".to_string()];
+        assert_eq!(mask_banned_words("darn it", &banned), "**** it");
+        assert_eq!(mask_banned_words("DARN, heck!", &banned), "****, ****!");
+        assert_eq!(mask_banned_words("darned heckler", &banned), "darned heckler");
+        assert_eq!(mask_banned_words("(darn)...", &banned), "(****)...");
+        assert_eq!(mask_banned_words("darn", &[]), "darn");
+        assert_eq!(mask_banned_words("", &banned), "");
+    }
+
+    #[test]
+    fn masks_keep_the_length_of_non_ascii_words() {
+        let banned = vec!["mist".to_string(), "schei\u{df}e".to_string()];
+        assert_eq!(mask_banned_words("so ein Mist, Schei\u{df}e", &banned), "so ein ****, *******");
+    }
+
+    #[tokio::test]
+    async fn lobby_chat_reaches_everyone_else() {
+        let server_state = test_state(Config::default()).await;
+        let mut alice = connect("alice", PROTOCOL_VERSION, &server_state).await;
+        let mut bob = connect("bob", PROTOCOL_VERSION, &server_state).await;
+        let mut carol = connect("carol", CHAT_PROTOCOL_VERSION - 1, &server_state).await;
+
+        process_chat(chat(ChatChannel::Lobby, "hello"), 1, &"alice".to_string(), &server_state).await.unwrap();
+        match received(&mut bob) {
+            Some(Message::Chat(chat)) => {
+                // The server decides who sent it and when
+                assert_eq!(chat.sender, "alice");
+                assert!(chat.sent_at > 0);
+                assert_eq!(chat.text, "hello");
+            }
+            other => panic!("expected a chat message, got {:?}", other),
+        }
+        assert!(matches!(received(&mut carol), Some(Message::Text(text)) if text == "alice: hello"));
+        assert!(received(&mut alice).is_none());
+    }
+
+    #[tokio::test]
+    async fn direct_messages() {
+        let server_state = test_state(Config::default()).await;
+        let mut alice = connect("alice", PROTOCOL_VERSION, &server_state).await;
+        let mut bob = connect("bob", PROTOCOL_VERSION, &server_state).await;
+        let mut carol = connect("carol", PROTOCOL_VERSION, &server_state).await;
+
+        process_chat(chat(ChatChannel::Direct("bob".to_string()), "psst"), 1, &"alice".to_string(), &server_state).await.unwrap();
+        assert!(matches!(received(&mut bob), Some(Message::Chat(chat)) if chat.text == "psst"));
+        assert!(received(&mut carol).is_none());
+
+        let offline = process_chat(chat(ChatChannel::Direct("dave".to_string()), "psst"), 2, &"alice".to_string(), &server_state).await;
+        assert!(offline.is_err());
+        assert!(matches!(received(&mut alice), Some(Message::Error(error)) if error == "dave is not online."));
+    }
+
+    #[tokio::test]
+    async fn game_chat_needs_a_game() {
+        let server_state = test_state(Config::default()).await;
+        let mut alice = connect("alice", PROTOCOL_VERSION, &server_state).await;
+        assert!(process_chat(chat(ChatChannel::Game, "gg"), 1, &"alice".to_string(), &server_state).await.is_err());
+        assert!(matches!(received(&mut alice), Some(Message::Error(_))));
+    }
+
+    #[tokio::test]
+    async fn muted_users_are_refused() {
+        let server_state = test_state(Config::default()).await;
+        let mut alice = connect("alice", PROTOCOL_VERSION, &server_state).await;
+        let mut bob = connect("bob", PROTOCOL_VERSION, &server_state).await;
+        server_state.mutes.lock().await.insert("alice".to_string(), None);
+
+        let result = process_chat(chat(ChatChannel::Lobby, "hello"), 1, &"alice".to_string(), &server_state).await;
+        assert!(matches!(result, Err(ChessError::Muted(_))));
+        assert!(matches!(received(&mut alice), Some(Message::Error(_))));
+        assert!(received(&mut bob).is_none());
+    }
+}
@@ -1,104 +1,183 @@
-use std::str::FromStr;
-
-use chess::{GameResult, Board, ChessMove, Color, MoveGen};
-use log::info;
-
-use common::ChessError;
-
-#[derive(Debug)]
-pub struct Game {
-    pub board: Board,
-    pub current_turn: Color,
-    pub white: Option<String>,
-    pub black: Option<String>,
-    pub status: GameStatus,
-    pub result: Option<GameResult>,
-}
-
-#[derive(Debug)]
-pub enum GameStatus {
-    Pending,
-    InProgress,
-    Finished,
-    Cancelled
-}
-
-impl Game {
-    pub fn new() -> Self {
-        Self {
-            board: Board::default(),
-            current_turn: Color::White,
-            white: None,
-            black: None,
-            status: GameStatus::Pending,
-            result: None,
-        }
-    }
-
-    pub fn make_move(&mut self, move_str: &str) -> Result<(), ChessError> {
-        match ChessMove::from_str(move_str) {
-            Ok(mov) => {
-                if self.board.legal(mov) {
-                    self.board = self.board.make_move_new(mov);
-                    self.current_turn = !self.current_turn;
-                    Ok(())
-                } else {
-                    Err(ChessError::GameStateError("Invalid move.".to_string()))
-                }
-            }
-            Err(_) => Err(ChessError::GameStateError("Couldn't parse move.".to_string())),
-        }
-    }
-
-    pub fn concede(&mut self, player: &String) -> Result<(), ChessError> {
-        info!("{:?} concedes. {:?} wins!", self.current_turn, !self.current_turn);
-        if self.white.as_ref() == Some(player) {
-            self.result = Some(GameResult::WhiteResigns);
-        } else if self.black.as_ref() == Some(player) {
-            self.result = Some(GameResult::BlackResigns);
-        } else {
-            return Err(ChessError::UserNotFoundError);
-        }
-        self.status = GameStatus::Finished;
-        Ok(())
-    }
-
-    pub fn is_check(&mut self) -> bool {
-        if self.board.checkers().popcnt() > 0 {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn is_mate(&mut self) -> bool {
-        let legal_moves = MoveGen::new_legal(&self.board);
-
-        if legal_moves.count() == 0 {
-            info!("{:?} wins by checkmate!", !self.current_turn);
-            if self.current_turn == Color::White {
-                self.result = Some(GameResult::BlackCheckmates);
-            } else {
-                self.result = Some(GameResult::WhiteCheckmates);
-            }
-            self.status = GameStatus::Finished;
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn is_drawable(&mut self) -> bool {
-        todo!() //if self.board.
-    }
-
-    pub fn check_result(&self) -> Option<GameResult> {
-        // TODO draw
-        // Some(GameResult::DrawAccepted)
-        // Some(GameResult::WhiteResigns)
-        // Some(GameResult::BlackResigns)
-        unimplemented!()
-    }
-
-    // TODO en passant
+use std::str::FromStr;
+use std::time::Instant;
+
+use chess::{GameResult, Board, ChessMove, Color};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use common::{Chat, ChessError, civil_date, unix_secs};
+use common::pgn::write_pgn;
+use common::rules::{board_result, end_reason, pgn_result};
+
+use crate::eco::classify;
+
+pub const VARIANT: &str = "standard";
+pub const TIME_CONTROL: &str = "-"; // PGN for untimed, the server has no clocks yet
+
+#[derive(Debug)]
+pub struct Game {
+    pub board: Board,
+    pub current_turn: Color,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub status: GameStatus,
+    pub result: Option<GameResult>,
+    pub moves: Vec<String>, // every move played so far, in the order they were made
+    pub created_at: Instant, // for the matchmaking wait time metric
+    pub chat: Vec<Chat>, // replayed to players who reconnect
+    pub finished_at: Option<u64>, // seconds since the Unix epoch, for the archive search
+}
+
+// What is kept of a game in progress across a server restart
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GameSnapshot {
+    pub game_id: u32,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub board: String, // FEN
+    pub moves: Vec<String>,
+    #[serde(default)]
+    pub chat: Vec<Chat>,
+}
+
+#[derive(Debug)]
+pub enum GameStatus {
+    Pending,
+    InProgress,
+    Finished,
+    Cancelled
+}
+
+impl Game {
+    pub fn new() -> Self {
+        Self {
+            board: Board::default(),
+            current_turn: Color::White,
+            white: None,
+            black: None,
+            status: GameStatus::Pending,
+            result: None,
+            moves: Vec::new(),
+            created_at: Instant::now(),
+            chat: Vec::new(),
+            finished_at: None,
+        }
+    }
+
+    pub fn snapshot(&self, game_id: u32) -> GameSnapshot {
+        GameSnapshot {
+            game_id,
+            white: self.white.clone(),
+            black: self.black.clone(),
+            board: self.board.to_string(),
+            moves: self.moves.clone(),
+            chat: self.chat.clone(),
+        }
+    }
+
+    pub fn restore(snapshot: GameSnapshot) -> Result<Self, ChessError> {
+        let board = Board::from_str(&snapshot.board)
+            .map_err(|e| ChessError::GameStateError(format!("Invalid board in game {}: {}", snapshot.game_id, e)))?;
+        Ok(Self {
+            board,
+            current_turn: board.side_to_move(),
+            white: snapshot.white,
+            black: snapshot.black,
+            status: GameStatus::InProgress,
+            result: None,
+            moves: snapshot.moves,
+            created_at: Instant::now(),
+            chat: snapshot.chat,
+            finished_at: None,
+        })
+    }
+
+    pub fn make_move(&mut self, move_str: &str) -> Result<(), ChessError> {
+        match ChessMove::from_str(move_str) {
+            Ok(mov) => {
+                if self.board.legal(mov) {
+                    self.board = self.board.make_move_new(mov);
+                    self.current_turn = !self.current_turn;
+                    self.moves.push(mov.to_string());
+                    if let Some(result) = board_result(&self.board) {
+                        info!("Game is finished: {:?}", result);
+                        self.result = Some(result);
+                        self.status = GameStatus::Finished;
+                        self.finished_at = Some(unix_secs());
+                    }
+                    Ok(())
+                } else {
+                    Err(ChessError::GameStateError("Invalid move.".to_string()))
+                }
+            }
+            Err(_) => Err(ChessError::GameStateError("Couldn't parse move.".to_string())),
+        }
+    }
+
+    pub fn concede(&mut self, player: &String) -> Result<(), ChessError> {
+        info!("{:?} concedes. {:?} wins!", self.current_turn, !self.current_turn);
+        if self.white.as_ref() == Some(player) {
+            self.result = Some(GameResult::WhiteResigns);
+        } else if self.black.as_ref() == Some(player) {
+            self.result = Some(GameResult::BlackResigns);
+        } else {
+            return Err(ChessError::UserNotFoundError);
+        }
+        self.status = GameStatus::Finished;
+        self.finished_at = Some(unix_secs());
+        Ok(())
+    }
+
+    pub fn declare_draw(&mut self) {
+        info!("Game is declared a draw.");
+        self.result = Some(GameResult::DrawDeclared);
+        self.status = GameStatus::Finished;
+        self.finished_at = Some(unix_secs());
+    }
+
+    pub fn abort(&mut self) {
+        info!("Game is aborted.");
+        self.status = GameStatus::Cancelled;
+        self.finished_at = Some(unix_secs());
+    }
+
+    pub fn pgn_result(&self) -> &'static str {
+        pgn_result(self.result)
+    }
+
+    // For `Message::GameEnd`, claims of abandoned games say so themselves
+    pub fn end_reason(&self) -> &'static str {
+        end_reason(self.result)
+    }
+
+    pub fn to_pgn(&self, game_id: u32) -> Result<String, ChessError> {
+        let tags = [
+            ("Event", "chess-rs game".to_string()),
+            ("Site", "chess-rs".to_string()),
+            ("Date", self.finished_at.map_or("????.??.??".to_string(), |finished_at| civil_date(finished_at).replace('-', "."))),
+            ("Round", game_id.to_string()),
+            ("White", self.white.clone().unwrap_or("?".to_string())),
+            ("Black", self.black.clone().unwrap_or("?".to_string())),
+            ("Result", self.pgn_result().to_string()),
+            ("ECO", classify(&self.moves).0.to_string()),
+            ("Opening", classify(&self.moves).1.to_string()),
+            ("TimeControl", TIME_CONTROL.to_string()),
+        ];
+        write_pgn(&tags, &self.moves, self.pgn_result())
+    }
+
+    pub fn is_check(&mut self) -> bool {
+        if self.board.checkers().popcnt() > 0 {
+            true
+        } else {
+            false
+        }
+    }
+
+    // The result itself is set by `make_move`
+    pub fn is_mate(&self) -> bool {
+        matches!(self.result, Some(GameResult::WhiteCheckmates) | Some(GameResult::BlackCheckmates))
+    }
+
+    // TODO en passant
 }
\ No newline at end of file
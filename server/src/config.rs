@@ -0,0 +1,476 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use log::LevelFilter;
+
+use common::{ChessError, DEFAULT_HOST, DEFAULT_HTTP_PORT, DEFAULT_PORT, DEFAULT_WS_PORT, MAX_MESSAGE_LEN};
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+const USAGE: &str = "Usage: server [--config <file>] [--host <host>] [--port <port>] [--ws-port <port>] [--no-websocket] \
+[--no-http] [--http-port <port>] [--user-file <file>] [--tls-cert <file> --tls-key <file>] [--heartbeat-interval <secs>] [--log-level <level>]";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub storage: StorageConfig,
+    pub game: GameConfig,
+    pub limits: LimitsConfig,
+    pub chat: ChatConfig,
+    pub logging: LoggingConfig,
+    pub admin: AdminConfig,
+    pub roles: RolesConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub ws_port: u16,
+    pub websocket: bool, // WebSocket gateway on `ws_port`
+    pub http_port: u16,
+    pub http: bool, // `/metrics` and the other HTTP endpoints on `http_port`
+    pub heartbeat: bool, // offer heartbeats to clients that support them
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<String>, // TLS is enabled when both are set
+    pub key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub user_file: String,
+    pub snapshot_file: String, // games in progress are saved here on shutdown and resumed on the next start
+    pub ban_file: String,
+    pub audit_log: String, // every moderation action, one per line
+    pub archive_file: String, // finished games with their PGN, one JSON line each, searched by `/search`
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub disconnect_grace_secs: u64, // how long a player may be away before the opponent can claim the game
+    pub disconnect_countdown_secs: u64, // how often the opponent is reminded during the grace period
+    pub first_move_timeout_secs: u64, // games without a first move are aborted after this
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub client_channel_size: usize, // messages queued per client before senders wait
+    pub max_message_len: usize,
+    pub heartbeat_interval_secs: u64,
+    pub max_missed_heartbeats: u32,
+    pub requests_per_second: f64, // sustained rate of requests per connection
+    pub request_burst: u32, // requests allowed at once before the rate applies
+    pub chat_per_second: f64, // chat messages have their own, stricter limit
+    pub chat_burst: u32,
+    pub flood_window_secs: u64, // dropped requests are forgotten after this long without another one
+    pub flood_mute_strikes: u32, // dropped chat messages before the sender is muted
+    pub flood_mute_minutes: u64,
+    pub flood_disconnect_strikes: u32, // dropped requests before the connection is closed
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    pub banned_words: Vec<String>, // masked with `*` in chat messages, matched case-insensitively
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String, // overridden by `RUST_LOG`
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub username: String,
+    pub password: Option<String>, // the admin panel at `/admin` is only served when this is set
+}
+
+// Usernames allowed to use the moderation commands
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RolesConfig {
+    pub admins: Vec<String>,
+    pub moderators: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT.parse().unwrap(),
+            ws_port: DEFAULT_WS_PORT.parse().unwrap(),
+            websocket: true,
+            http_port: DEFAULT_HTTP_PORT.parse().unwrap(),
+            http: true,
+            heartbeat: true,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            user_file: "database/usernames.txt".to_string(),
+            snapshot_file: "database/games.json".to_string(),
+            ban_file: "database/bans.json".to_string(),
+            audit_log: "database/audit.log".to_string(),
+            archive_file: "database/archive.jsonl".to_string(),
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            disconnect_grace_secs: 60,
+            disconnect_countdown_secs: 15,
+            first_move_timeout_secs: 60,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            client_channel_size: 100,
+            max_message_len: MAX_MESSAGE_LEN,
+            heartbeat_interval_secs: 15,
+            max_missed_heartbeats: 3,
+            requests_per_second: 10.0,
+            request_burst: 20,
+            chat_per_second: 1.0,
+            chat_burst: 5,
+            flood_window_secs: 60,
+            flood_mute_strikes: 5,
+            flood_mute_minutes: 5,
+            flood_disconnect_strikes: 20,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            username: "admin".to_string(),
+            password: None,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file (`config.toml` if it exists, or the one given with `--config`),
+    // then applies command line overrides and validates the result
+    pub fn load(args: impl Iterator<Item = String>) -> Result<Self, ChessError> {
+        let args: Vec<String> = args.collect();
+
+        let config_path = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => Some(args.get(i + 1).ok_or(config_error("--config needs a file"))?.clone()),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Some(DEFAULT_CONFIG_FILE.to_string()),
+            None => None,
+        };
+
+        let mut config = match config_path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| config_error(&format!("Failed to read {}: {}", path, e)))?;
+                toml::from_str(&contents)
+                    .map_err(|e| config_error(&format!("Failed to parse {}: {}", path, e)))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_args(&args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), ChessError> {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--no-websocket" {
+                self.server.websocket = false;
+                continue;
+            }
+            if arg == "--no-http" {
+                self.server.http = false;
+                continue;
+            }
+
+            let value = args.next()
+                .ok_or(config_error(&format!("{} needs a value\n{}", arg, USAGE)))?
+                .clone();
+            match arg.as_str() {
+                "--config" => {}, // already read
+                "--host" => self.server.host = value,
+                "--port" => self.server.port = parse_arg(arg, &value)?,
+                "--ws-port" => self.server.ws_port = parse_arg(arg, &value)?,
+                "--http-port" => self.server.http_port = parse_arg(arg, &value)?,
+                "--user-file" => self.storage.user_file = value,
+                "--tls-cert" => self.tls.cert = Some(value),
+                "--tls-key" => self.tls.key = Some(value),
+                "--heartbeat-interval" => self.limits.heartbeat_interval_secs = parse_arg(arg, &value)?,
+                "--log-level" => self.logging.level = value,
+                _ => return Err(config_error(&format!("Unknown argument {}\n{}", arg, USAGE))),
+            }
+        }
+        Ok(())
+    }
+
+    // Collects every problem at once, so they can all be fixed before the next start
+    fn validate(&self) -> Result<(), ChessError> {
+        let mut errors = Vec::new();
+
+        if self.server.websocket && self.server.port == self.server.ws_port {
+            errors.push(format!("server.port and server.ws_port are both {}", self.server.port));
+        }
+        if self.server.http && self.server.port == self.server.http_port {
+            errors.push(format!("server.port and server.http_port are both {}", self.server.port));
+        }
+        if self.server.http && self.server.websocket && self.server.ws_port == self.server.http_port {
+            errors.push(format!("server.ws_port and server.http_port are both {}", self.server.ws_port));
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            errors.push("tls.cert and tls.key must be set together".to_string());
+        }
+        for path in [self.tls.cert.as_ref(), self.tls.key.as_ref()].into_iter().flatten() {
+            if !Path::new(path).exists() {
+                errors.push(format!("TLS file {} does not exist", path));
+            }
+        }
+        if !Path::new(&self.storage.user_file).exists() {
+            errors.push(format!("storage.user_file {} does not exist", self.storage.user_file));
+        }
+        if self.game.disconnect_countdown_secs == 0 {
+            errors.push("game.disconnect_countdown_secs must be positive".to_string());
+        }
+        if self.limits.client_channel_size == 0 {
+            errors.push("limits.client_channel_size must be positive".to_string());
+        }
+        if self.limits.max_message_len == 0 {
+            errors.push("limits.max_message_len must be positive".to_string());
+        }
+        if self.limits.heartbeat_interval_secs == 0 {
+            errors.push("limits.heartbeat_interval_secs must be positive".to_string());
+        }
+        if self.limits.max_missed_heartbeats == 0 {
+            errors.push("limits.max_missed_heartbeats must be positive".to_string());
+        }
+        if self.limits.requests_per_second <= 0.0 || self.limits.chat_per_second <= 0.0 {
+            errors.push("limits.requests_per_second and limits.chat_per_second must be positive".to_string());
+        }
+        if self.limits.request_burst == 0 || self.limits.chat_burst == 0 {
+            errors.push("limits.request_burst and limits.chat_burst must be positive".to_string());
+        }
+        if self.limits.flood_mute_strikes == 0 || self.limits.flood_disconnect_strikes == 0 {
+            errors.push("limits.flood_mute_strikes and limits.flood_disconnect_strikes must be positive".to_string());
+        }
+        if self.limits.flood_mute_strikes >= self.limits.flood_disconnect_strikes {
+            errors.push(format!("limits.flood_mute_strikes ({}) must be less than limits.flood_disconnect_strikes ({}), or flooders are disconnected before they are muted",
+                self.limits.flood_mute_strikes, self.limits.flood_disconnect_strikes));
+        }
+        if self.chat.banned_words.iter().any(|word| word.trim().is_empty()) {
+            errors.push("chat.banned_words must not contain empty words".to_string());
+        }
+        if self.admin.password.as_ref().is_some_and(|password| password.is_empty()) {
+            errors.push("admin.password must not be empty".to_string());
+        }
+        // The panel's basic auth would cross the network in the clear while the game ports are encrypted
+        if self.admin.password.is_some() && self.tls_files().is_some() && self.server.http && !is_loopback(&self.server.host) {
+            errors.push(format!("admin.password needs server.host to be a loopback address when TLS is on, the admin panel is plain HTTP (server.host is {})",
+                self.server.host));
+        }
+        if LevelFilter::from_str(&self.logging.level).is_err() {
+            errors.push(format!("logging.level {} is not one of off, error, warn, info, debug, trace", self.logging.level));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(config_error(&errors.join("\n")))
+        }
+    }
+
+    pub fn tls_files(&self) -> Option<(&str, &str)> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => None,
+        }
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.limits.heartbeat_interval_secs)
+    }
+
+    pub fn disconnect_grace_period(&self) -> Duration {
+        Duration::from_secs(self.game.disconnect_grace_secs)
+    }
+
+    pub fn disconnect_countdown_step(&self) -> Duration {
+        Duration::from_secs(self.game.disconnect_countdown_secs)
+    }
+
+    pub fn first_move_timeout(&self) -> Duration {
+        Duration::from_secs(self.game.first_move_timeout_secs)
+    }
+}
+
+fn parse_arg<T: FromStr>(arg: &str, value: &str) -> Result<T, ChessError> {
+    value.parse().map_err(|_| config_error(&format!("Invalid value {} for {}", value, arg)))
+}
+
+fn is_loopback(host: &str) -> bool {
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn config_error(message: &str) -> ChessError {
+    ChessError::ConfigError(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Defaults with a user file that exists, cargo runs tests from the crate directory
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.storage.user_file = "Cargo.toml".to_string();
+        config
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn errors(config: &Config) -> String {
+        match config.validate() {
+            Err(ChessError::ConfigError(message)) => message,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        valid().validate().unwrap();
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let mut config = valid();
+        config.server.ws_port = config.server.port;
+        config.limits.heartbeat_interval_secs = 0;
+        config.logging.level = "loud".to_string();
+
+        let message = errors(&config);
+        assert_eq!(message.lines().count(), 3, "{}", message);
+        assert!(message.contains("server.ws_port"));
+        assert!(message.contains("heartbeat_interval_secs"));
+        assert!(message.contains("loud"));
+    }
+
+    #[test]
+    fn disabled_listeners_may_share_ports() {
+        let mut config = valid();
+        config.server.ws_port = config.server.port;
+        config.server.http_port = config.server.port;
+        config.server.websocket = false;
+        config.server.http = false;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn tls_needs_both_files() {
+        let mut config = valid();
+        config.tls.cert = Some("Cargo.toml".to_string());
+        assert!(errors(&config).contains("set together"));
+
+        config.tls.key = Some("no-such-key.pem".to_string());
+        let message = errors(&config);
+        assert!(!message.contains("set together"));
+        assert!(message.contains("no-such-key.pem"));
+    }
+
+    #[test]
+    fn mute_must_come_before_disconnect() {
+        let mut config = valid();
+        config.limits.flood_mute_strikes = config.limits.flood_disconnect_strikes;
+        assert!(errors(&config).contains("disconnected before they are muted"));
+    }
+
+    #[test]
+    fn rejects_empty_words_and_passwords() {
+        let mut config = valid();
+        config.chat.banned_words = vec!["fine".to_string(), " ".to_string()];
+        config.admin.password = Some(String::new());
+        let message = errors(&config);
+        assert!(message.contains("banned_words"));
+        assert!(message.contains("admin.password"));
+    }
+
+    #[test]
+    fn admin_panel_stays_local_with_tls() {
+        let mut config = valid();
+        config.tls.cert = Some("Cargo.toml".to_string());
+        config.tls.key = Some("Cargo.toml".to_string());
+        config.admin.password = Some("secret".to_string());
+        for host in ["127.0.0.1", "::1", "localhost"] {
+            config.server.host = host.to_string();
+            config.validate().unwrap();
+        }
+
+        config.server.host = "0.0.0.0".to_string();
+        assert!(errors(&config).contains("admin.password"));
+        config.server.http = false;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn args_override_the_file() {
+        let mut config = valid();
+        config.apply_args(&args(&["--config", "server.toml", "--port", "9000", "--no-http", "--tls-cert", "cert.pem", "--log-level", "debug"]))
+            .unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert!(!config.server.http);
+        assert!(config.server.websocket);
+        assert_eq!(config.tls.cert.as_deref(), Some("cert.pem"));
+        assert_eq!(config.logging.level, "debug");
+    }
+
+    #[test]
+    fn bad_args_are_errors() {
+        assert!(valid().apply_args(&args(&["--port"])).is_err());
+        assert!(valid().apply_args(&args(&["--port", "http"])).is_err());
+        assert!(valid().apply_args(&args(&["--colour", "blue"])).is_err());
+    }
+
+    #[test]
+    fn parses_a_partial_file() {
+        let config: Config = toml::from_str("[server]\nport = 9000\n\n[limits]\nchat_burst = 2\n").unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.ws_port, Config::default().server.ws_port);
+        assert_eq!(config.limits.chat_burst, 2);
+        assert!(toml::from_str::<Config>("[server]\nprot = 9000\n").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+// A short opening book for the archive: the common openings by their first moves, not the full ECO classification.
+// The longest line the game starts with wins.
+const OPENINGS: &[(&str, &str, &str)] = &[
+    ("A00", "Uncommon Opening", ""),
+    ("A02", "Bird's Opening", "f2f4"),
+    ("A04", "Reti Opening", "g1f3"),
+    ("A10", "English Opening", "c2c4"),
+    ("A40", "Queen's Pawn Game", "d2d4"),
+    ("A45", "Indian Defence", "d2d4 g8f6"),
+    ("A80", "Dutch Defence", "d2d4 f7f5"),
+    ("D00", "Queen's Pawn Game", "d2d4 d7d5"),
+    ("D06", "Queen's Gambit", "d2d4 d7d5 c2c4"),
+    ("D10", "Slav Defence", "d2d4 d7d5 c2c4 c7c6"),
+    ("D20", "Queen's Gambit Accepted", "d2d4 d7d5 c2c4 d5c4"),
+    ("D30", "Queen's Gambit Declined", "d2d4 d7d5 c2c4 e7e6"),
+    ("E00", "Indian Defence", "d2d4 g8f6 c2c4 e7e6"),
+    ("E20", "Nimzo-Indian Defence", "d2d4 g8f6 c2c4 e7e6 b1c3 f8b4"),
+    ("E60", "King's Indian Defence", "d2d4 g8f6 c2c4 g7g6"),
+    ("D80", "Grunfeld Defence", "d2d4 g8f6 c2c4 g7g6 b1c3 d7d5"),
+    ("B00", "King's Pawn Opening", "e2e4"),
+    ("B01", "Scandinavian Defence", "e2e4 d7d5"),
+    ("B02", "Alekhine's Defence", "e2e4 g8f6"),
+    ("B06", "Modern Defence", "e2e4 g7g6"),
+    ("B07", "Pirc Defence", "e2e4 d7d6"),
+    ("B10", "Caro-Kann Defence", "e2e4 c7c6"),
+    ("B20", "Sicilian Defence", "e2e4 c7c5"),
+    ("C00", "French Defence", "e2e4 e7e6"),
+    ("C20", "King's Pawn Game", "e2e4 e7e5"),
+    ("C25", "Vienna Game", "e2e4 e7e5 b1c3"),
+    ("C30", "King's Gambit", "e2e4 e7e5 f2f4"),
+    ("C40", "King's Knight Opening", "e2e4 e7e5 g1f3"),
+    ("C41", "Philidor Defence", "e2e4 e7e5 g1f3 d7d6"),
+    ("C42", "Petrov's Defence", "e2e4 e7e5 g1f3 g8f6"),
+    ("C44", "King's Pawn Game", "e2e4 e7e5 g1f3 b8c6"),
+    ("C45", "Scotch Game", "e2e4 e7e5 g1f3 b8c6 d2d4"),
+    ("C50", "Italian Game", "e2e4 e7e5 g1f3 b8c6 f1c4"),
+    ("C60", "Ruy Lopez", "e2e4 e7e5 g1f3 b8c6 f1b5"),
+];
+
+// ECO code and opening name, moves in coordinate notation as the server keeps them
+pub fn classify(moves: &[String]) -> (&'static str, &'static str) {
+    OPENINGS.iter()
+        .map(|(eco, name, line)| (eco, name, line.split_whitespace().collect::<Vec<_>>()))
+        .filter(|(_, _, line)| line.len() <= moves.len() && line.iter().zip(moves).all(|(a, b)| a == b))
+        .max_by_key(|(_, _, line)| line.len())
+        .map(|(eco, name, _)| (*eco, *name))
+        .unwrap_or((OPENINGS[0].0, OPENINGS[0].1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moves(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|mov| mov.to_string()).collect()
+    }
+
+    #[test]
+    fn longest_matching_line_wins() {
+        assert_eq!(classify(&moves("e2e4 e7e5 g1f3 b8c6 f1b5 a7a6")), ("C60", "Ruy Lopez"));
+        assert_eq!(classify(&moves("e2e4 e7e5 g1f3 b8c6")), ("C44", "King's Pawn Game"));
+        assert_eq!(classify(&moves("d2d4 g8f6 c2c4 g7g6 b1c3 d7d5")), ("D80", "Grunfeld Defence"));
+        assert_eq!(classify(&moves("e2e4")), ("B00", "King's Pawn Opening"));
+    }
+
+    #[test]
+    fn unknown_and_short_games() {
+        assert_eq!(classify(&[]), ("A00", "Uncommon Opening"));
+        assert_eq!(classify(&moves("a2a3 e7e5")), ("A00", "Uncommon Opening"));
+        // Transpositions aren't recognised, only the move order in the book
+        assert_eq!(classify(&moves("g1f3 d7d5 d2d4")), ("A04", "Reti Opening"));
+    }
+
+    #[test]
+    fn book_lines_are_legal_looking() {
+        for (eco, _, line) in OPENINGS {
+            assert_eq!(eco.len(), 3);
+            assert!(line.split_whitespace().all(|mov| mov.len() == 4), "{}", line);
+        }
+    }
+}
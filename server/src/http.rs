@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use axum::Router;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use log::error;
+
+use crate::{ServerState, admin, api};
+
+// Everything served over HTTP, next to the game ports
+pub fn router(server_state: Arc<ServerState>) -> Router {
+    let mut router = Router::new()
+        .route("/metrics", get(metrics))
+        .nest("/api", api::router());
+    if server_state.config.admin.password.is_some() {
+        router = router.nest("/admin", admin::router(server_state.clone()));
+    }
+    router.with_state(server_state)
+}
+
+async fn metrics(State(server_state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let metrics = &server_state.metrics;
+    metrics.user_connections.set(server_state.user_connections.lock().await.len() as i64);
+    metrics.anon_user_connections.set(server_state.anon_user_connections.lock().await.len() as i64);
+    metrics.active_games.set(server_state.games.lock().await.len() as i64);
+    metrics.finished_games.set(server_state.finished_games.lock().await.len() as i64);
+
+    match metrics.render() {
+        Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -1,58 +1,126 @@
 //use std::process::Command;
 
+mod admin;
+mod api;
+mod chat;
 mod chess_game;
+mod config;
+mod eco;
+mod http;
+mod metrics;
+mod moderation;
+mod rate_limit;
+mod search;
+mod transport;
 
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpListener;
 use tokio::io::AsyncWriteExt;
 use tokio::signal;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
+use tokio::sync::Notify;
 use tokio::sync::mpsc::Sender;
+use tokio_rustls::TlsAcceptor;
 use log::{info, error};
 use chess::{Board, Color};
 
-use crate::chess_game::{Game, GameStatus};
-
-use common::{DEFAULT_HOST, DEFAULT_PORT, Message, Command, ChessError, make_io_error, listen_to_messages};
-
-const USER_FILE: &str = "database/usernames.txt";
+use crate::chess_game::{Game, GameSnapshot, GameStatus};
+use crate::config::Config;
+use crate::metrics::{Metrics, message_type, command_name};
+use crate::moderation::{Ban, find_ban, load_bans, mute_user, process_moderation_command};
+use crate::rate_limit::{RateLimiter, Verdict};
+use crate::search::{archive_game, load_archive, search};
+use crate::chat::{process_chat, replay_game_chat};
+use crate::transport::{ClientReader, ClientWriter, load_tls_acceptor, secure, accept_tcp, accept_websocket};
+
+use common::{Chat, ChatChannel, GameStart, GameEnd, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, GAME_START_PROTOCOL_VERSION, GAME_END_PROTOCOL_VERSION, SEARCH_PROTOCOL_VERSION, OPPONENT_GONE_PROTOCOL_VERSION, Message, Envelope, RequestId, Command, Capability, Welcome, ArchivedGame, ChessError, make_io_error, unix_millis};
+
+const SERVER_CAPABILITIES: &[Capability] = &[Capability::Heartbeat];
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2); // how long queued messages get to reach clients before they are cut off
+
+struct ClientSession {
+    client_name: String,
+    client_version: String,
+    protocol_version: u16,
+    capabilities: Vec<Capability>,
+    last_seen: Instant, // updated on every frame received from the client
+    round_trip_time: Option<Duration>, // measured from the last answered ping, shown on the admin panel; lag compensation waits for server-side clocks
+    kick: Arc<Notify>, // closes the connection from outside, e.g. from the admin panel
+}
 
 struct ServerState {
-    user_connections: Arc<Mutex<HashMap<String, mpsc::Sender<Message>>>>, // mapping to know the channel through which to send messages to a user 
-    anon_user_connections: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Message>>>>, // kill me
+    user_connections: Arc<Mutex<HashMap<String, mpsc::Sender<Envelope>>>>, // mapping to know the channel through which to send messages to a user 
+    anon_user_connections: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Envelope>>>>, // kill me
     addr_to_user: Arc<Mutex<HashMap<SocketAddr, String>>>, // reverse mapping to identify which user the message is coming from
     games: Arc<Mutex<HashMap<u32, Arc<Mutex<Game>>>>>, // game_id to Game
     finished_games: Arc<Mutex<HashMap<u32, Arc<Mutex<Game>>>>>,
     user_to_game: Arc<Mutex<HashMap<String, u32>>>, // username to game_id
+    client_sessions: Arc<Mutex<HashMap<SocketAddr, ClientSession>>>, // what was negotiated during the handshake
+    disconnected_players: Arc<Mutex<HashMap<String, Instant>>>, // players who left a game in progress, to the end of their grace period
+    bans: Arc<Mutex<Vec<Ban>>>,
+    mutes: Arc<Mutex<HashMap<String, Option<Instant>>>>, // muted users, until when if not for good
     user_file_mutex: Arc<Mutex<Option<tokio::fs::File>>>,
+    registered_users: Arc<Mutex<BTreeSet<String>>>, // everyone in the user file, kept in step with it by `register`
+    archive: Arc<Mutex<Vec<ArchivedGame>>>, // every finished game in `storage.archive_file`, oldest first
     last_game_id: AtomicU32, 
+    shutting_down: AtomicBool, // no new games or moves once the snapshot is being taken
+    metrics: Metrics,
+    started_at: Instant,
+    config: Config,
 }
 
 impl ServerState {
-    async fn new() -> Self {
+    async fn new(config: Config) -> Result<Self, ChessError> {
         let file = tokio::fs::OpenOptions::new()
             .append(true)
-            .open(USER_FILE)
+            .open(&config.storage.user_file)
             .await
-            .expect("Failed to open user file");
-
-        Self {
+            .map_err(|e| make_io_error(e, &format!("Failed to open user file {}", config.storage.user_file)))?;
+        let bans = load_bans(&config.storage.ban_file).await?;
+        let archive = load_archive(&config.storage.archive_file).await?;
+        let registered_users = tokio::fs::read_to_string(&config.storage.user_file).await
+            .map_err(|e| make_io_error(e, &format!("Failed to read user file {}", config.storage.user_file)))?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect();
+
+        Ok(Self {
             user_connections: Arc::new(Mutex::new(HashMap::new())),
             anon_user_connections: Arc::new(Mutex::new(HashMap::new())),
             addr_to_user: Arc::new(Mutex::new(HashMap::new())),
             games: Arc::new(Mutex::new(HashMap::new())),
             finished_games: Arc::new(Mutex::new(HashMap::new())),
             user_to_game: Arc::new(Mutex::new(HashMap::new())),
+            client_sessions: Arc::new(Mutex::new(HashMap::new())),
+            disconnected_players: Arc::new(Mutex::new(HashMap::new())),
+            bans: Arc::new(Mutex::new(bans)),
+            mutes: Arc::new(Mutex::new(HashMap::new())),
             user_file_mutex: Arc::new(Mutex::new(Some(file))),
-            last_game_id: AtomicU32::new(0),
-        }
+            registered_users: Arc::new(Mutex::new(registered_users)),
+            // Game ids stay unique across restarts, the archive refers to them
+            last_game_id: AtomicU32::new(archive.iter().map(|game| game.game_id + 1).max().unwrap_or(0)),
+            archive: Arc::new(Mutex::new(archive)),
+            shutting_down: AtomicBool::new(false),
+            metrics: Metrics::new(),
+            started_at: Instant::now(),
+            config,
+        })
+    }
+
+    // Heartbeats can be switched off in the config, e.g. behind a proxy that drops idle pings
+    fn capabilities(&self) -> Vec<Capability> {
+        SERVER_CAPABILITIES.iter()
+            .filter(|&c| *c != Capability::Heartbeat || self.config.server.heartbeat)
+            .cloned()
+            .collect()
     }
 
     fn get_new_game_id(&self) -> u32 {
@@ -64,49 +132,131 @@ impl ServerState {
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    // Bad settings stop the server right away instead of failing on the first connection
+    let config = match Config::load(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let args: Vec<String> = std::env::args().collect();
-    let host = args.get(1).unwrap_or(&DEFAULT_HOST.to_string()).to_string();
-    let port = args.get(2).unwrap_or(&DEFAULT_PORT.to_string()).to_string();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.logging.level)).init();
 
-    info!("Starting server on {}:{}", host, port);
+    // TLS is enabled for both listeners when a certificate and its key are given
+    let tls_acceptor = match config.tls_files() {
+        Some((cert_path, key_path)) => match load_tls_acceptor(cert_path, key_path) {
+            Ok(tls_acceptor) => {
+                info!("TLS enabled with certificate {}", cert_path);
+                Some(tls_acceptor)
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    info!("Starting server on {}:{}", config.server.host, config.server.port);
 
     let (shutdown_sender, _) = broadcast::channel(1);
-    let server = tokio::spawn(start_server(host.clone(), port.clone(), shutdown_sender.subscribe()));
+    let server = tokio::spawn(start_server(config, tls_acceptor, shutdown_sender.subscribe()));
 
     info!("Server spawned, waiting for connections...");
 
-    let ctrl_c = async {
+    tokio::spawn(async move {
         signal::ctrl_c().await.expect("Failed to listen for ctrl_c signal");
         info!("Ctrl+C signal received, sending shutdown signal...");
         shutdown_sender.send(()).expect("Failed to send shutdown signal");
-    };
+    });
 
-    tokio::select! {
-        _ = server => info!("Server task completed."),
-        _ = ctrl_c => info!("Ctrl+C handler completed."),
+    // The server saves the games in progress before returning, so wait for it instead of exiting on Ctrl+C
+    match server.await {
+        Ok(Err(e)) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        _ => info!("Server task completed."),
     }
 
     info!("Server shutting down.");
 }
 
-async fn start_server(host: String, port: String, mut shutdown_signal: broadcast::Receiver<()>) {
-    let listener = TcpListener::bind(format!("{}:{}", host, port))
+async fn start_server(config: Config, tls_acceptor: Option<TlsAcceptor>, mut shutdown_signal: broadcast::Receiver<()>) -> Result<(), ChessError> {
+    let host = config.server.host.clone();
+    let listener = TcpListener::bind((host.as_str(), config.server.port))
         .await
-        .expect("Failed to bind to port");
-    info!("Server listening on {}:{}", host, port);
+        .map_err(|e| make_io_error(e, &format!("Failed to bind to {}:{}", host, config.server.port)))?;
+    info!("Server listening on {}:{}", host, config.server.port);
+
+    let ws_listener = if config.server.websocket {
+        let ws_listener = TcpListener::bind((host.as_str(), config.server.ws_port))
+            .await
+            .map_err(|e| make_io_error(e, &format!("Failed to bind to {}:{}", host, config.server.ws_port)))?;
+        info!("WebSocket gateway listening on {}:{}", host, config.server.ws_port);
+        Some(ws_listener)
+    } else {
+        info!("WebSocket gateway disabled");
+        None
+    };
 
-    let server_state = ServerState::new().await;
+    let server_state = ServerState::new(config).await?;
     let server_state = Arc::new(server_state);
+    restore_games(&server_state).await?;
+
+    if server_state.config.server.http {
+        let http_listener = TcpListener::bind((host.as_str(), server_state.config.server.http_port))
+            .await
+            .map_err(|e| make_io_error(e, &format!("Failed to bind to {}:{}", host, server_state.config.server.http_port)))?;
+        info!("Metrics available at http://{}:{}/metrics", host, server_state.config.server.http_port);
+        let router = http::router(server_state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(http_listener, router).await {
+                error!("HTTP server stopped: {}", e);
+            }
+        });
+    }
+    let max_message_len = server_state.config.limits.max_message_len;
 
     loop {
+        // A disabled gateway never accepts anything
+        let ws_accept = async {
+            match &ws_listener {
+                Some(ws_listener) => ws_listener.accept().await,
+                None => std::future::pending().await,
+            }
+        };
+
         tokio::select! {
-            Ok((socket, _)) = listener.accept() => {
+            Ok((socket, socket_addr)) = listener.accept() => {
                 let server_state_clone = server_state.clone();
+                let tls_acceptor = tls_acceptor.clone();
                 tokio::spawn(async move {
-                    info!("New connection: {}", socket.peer_addr().unwrap());
-                    handle_client(socket, server_state_clone).await;
+                    info!("New connection: {}", socket_addr);
+                    let accepted = match secure(socket, tls_acceptor.as_ref()).await {
+                        Ok(stream) => accept_tcp(stream, max_message_len).await,
+                        Err(e) => Err(e),
+                    };
+                    match accepted {
+                        Ok((reader, writer)) => handle_client(reader, writer, socket_addr, server_state_clone).await,
+                        Err(e) => error!("Failed to accept connection from {}: {}", socket_addr, e),
+                    }
+                });
+            }
+            Ok((socket, socket_addr)) = ws_accept => {
+                let server_state_clone = server_state.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                tokio::spawn(async move {
+                    info!("New WebSocket connection: {}", socket_addr);
+                    let accepted = match secure(socket, tls_acceptor.as_ref()).await {
+                        Ok(stream) => accept_websocket(stream, max_message_len).await,
+                        Err(e) => Err(e),
+                    };
+                    match accepted {
+                        Ok((reader, writer)) => handle_client(reader, writer, socket_addr, server_state_clone).await,
+                        Err(e) => error!("Failed to accept WebSocket connection from {}: {}", socket_addr, e),
+                    }
                 });
             }
             _ = shutdown_signal.recv() => {
@@ -115,88 +265,495 @@ async fn start_server(host: String, port: String, mut shutdown_signal: broadcast
             }
         }
     }
+
+    server_state.shutting_down.store(true, Ordering::SeqCst);
+    announce_shutdown(&server_state).await;
+    snapshot_games(&server_state).await
 }
 
-async fn handle_client(socket: TcpStream, server_state: Arc<ServerState>) {
-    let (tx, mut rx) = mpsc::channel::<Message>(100); // Channel for communication
-    let socket_addr = match socket.peer_addr() {
-        Ok(addr) => addr,
+async fn handle_client(mut reader: ClientReader, mut writer: ClientWriter, socket_addr: SocketAddr, server_state: Arc<ServerState>) {
+    let (tx, mut rx) = mpsc::channel::<Envelope>(server_state.config.limits.client_channel_size); // Channel for communication
+
+    let (heartbeat_enabled, kick) = match handshake(&mut reader, &mut writer, &server_state.capabilities()).await {
+        Ok(session) => {
+            let heartbeat_enabled = session.capabilities.contains(&Capability::Heartbeat);
+            let kick = session.kick.clone();
+            server_state.client_sessions.lock().await.insert(socket_addr, session);
+            (heartbeat_enabled, kick)
+        }
         Err(e) => {
-            error!("Failed to get user address: {}", e);
+            error!("Handshake with {} failed: {}", socket_addr, e);
             return;
         }
     };
 
+    let reply_sender = tx.clone();
+    let heartbeat_sender = tx.clone();
     server_state.anon_user_connections.lock().await.insert(socket_addr, tx);
     info!("New anon_user_connections entry added, address: {}", socket_addr);
 
-    let (mut reader, mut writer) = socket.into_split();
-
     let server_state_clone = Arc::clone(&server_state);
     
-    let read_task = tokio::spawn(async move {
-        listen_to_client_messages(&mut reader, &socket_addr, server_state_clone).await;
+    let mut read_task = tokio::spawn(async move {
+        listen_to_client_messages(&mut reader, &socket_addr, reply_sender, server_state_clone).await;
     });
 
-    let write_task = tokio::spawn(async move {
+    let mut write_task = tokio::spawn(async move {
         while let Some(message) = rx.recv().await {
-            let serialized_message = match serde_cbor::to_vec(&message) {
-                Ok(m) => m,
-                Err(e) => {
-                    error!("Failed to serialize message: {}", e);
+            match writer.write(&message).await {
+                Ok(()) => {},
+                Err(e @ ChessError::SerializationError(_)) | Err(e @ ChessError::MessageHandlingError(_)) => {
+                    error!("Failed to send message: {}", e);
                     continue;
                 }
-            };
-
-            let len_bytes = (serialized_message.len() as u32).to_be_bytes();
-            if writer.write_all(&len_bytes).await.is_err() {
-                break;
-            }
-            if writer.write_all(&serialized_message).await.is_err() {
-                break;
+                Err(_) => break,
             }
         }
     });
 
-    let _ = tokio::try_join!(read_task, write_task);
+    let server_state_clone = Arc::clone(&server_state);
+    let mut heartbeat_task = tokio::spawn(async move {
+        if heartbeat_enabled {
+            heartbeat(&socket_addr, heartbeat_sender, server_state_clone).await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    });
+
+    // Whichever task stops first means the connection is gone, don't wait for the others
+    tokio::select! {
+        _ = &mut read_task => {},
+        _ = &mut write_task => {},
+        _ = &mut heartbeat_task => {},
+        _ = kick.notified() => info!("{} was kicked", socket_addr),
+    }
+    read_task.abort();
+    write_task.abort();
+    heartbeat_task.abort();
 
     {
         let mut anon_user_connections = server_state.anon_user_connections.lock().await;
         if let Some(sender) = anon_user_connections.get(&socket_addr) {
-            let _ = sender.send(Message::Log("You have been disconnected. Bye!".to_string())).await
+            let _ = sender.send(Envelope::Event(Message::Log("You have been disconnected. Bye!".to_string()))).await
                 .map_err(|e| ChessError::MessageHandlingError(format!("Failed to send message: {}", e)));
         }
         anon_user_connections.remove(&socket_addr);
     }
     
-    {
-        let username = identify_user_by_addr(&socket_addr, &server_state).await;
-        let mut user_connections = server_state.user_connections.lock().await;
-        if let Some(username) = username {
+    let username = identify_user_by_addr(&socket_addr, &server_state).await;
+    if let Some(username) = username {
+        {
+            let mut user_connections = server_state.user_connections.lock().await;
             if let Some(sender) = user_connections.get(&username) {
-                let _ = sender.send(Message::Log("You have been disconnected. Bye!".to_string())).await
+                let _ = sender.send(Envelope::Event(Message::Log("You have been disconnected. Bye!".to_string()))).await
                     .map_err(|e| ChessError::MessageHandlingError(format!("Failed to send message: {}", e)));
             }
             user_connections.remove(&username);
         }
+        server_state.addr_to_user.lock().await.remove(&socket_addr);
+
+        if let Err(e) = handle_player_disconnect(&username, &server_state).await {
+            error!("Failed to handle disconnect of {}: {}", username, e);
+        }
     }
 
-    
+    if let Some(session) = server_state.client_sessions.lock().await.remove(&socket_addr) {
+        info!("{} disconnected ({} {}, protocol version {})", socket_addr, session.client_name, session.client_version, session.protocol_version);
+    }
+}
+
+async fn handshake(reader: &mut ClientReader, writer: &mut ClientWriter, server_capabilities: &[Capability]) -> Result<ClientSession, ChessError> {
+    let hello = reader.read().await?;
+    writer.use_json(reader.uses_json());
+
+    let hello = match hello {
+        Message::Hello(hello) => hello,
+        other => {
+            let _ = writer.write(&Message::Error("Expected Hello as the first message.".to_string())).await;
+            return Err(ChessError::ProtocolError(format!("Expected Hello, received {:?}", other)));
+        }
+    };
+
+    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+        let _ = writer.write(&Message::Error(format!("Protocol version {} is not supported anymore, please upgrade your client (version {} or newer is required).", hello.protocol_version, MIN_PROTOCOL_VERSION))).await;
+        return Err(ChessError::ProtocolError(format!("{} {} uses unsupported protocol version {}", hello.client_name, hello.client_version, hello.protocol_version)));
+    }
+
+    // Newer clients are downgraded to our version, it's up to them to decide whether they can speak it
+    let welcome = Welcome {
+        protocol_version: hello.protocol_version.min(PROTOCOL_VERSION),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: hello.capabilities.into_iter().filter(|c| server_capabilities.contains(c)).collect(),
+    };
+    writer.write(&Message::Welcome(welcome.clone())).await?;
+
+    info!("{} {} speaks protocol version {}, negotiated capabilities: {:?}", hello.client_name, hello.client_version, welcome.protocol_version, welcome.capabilities);
+    Ok(ClientSession {
+        client_name: hello.client_name,
+        client_version: hello.client_version,
+        protocol_version: welcome.protocol_version,
+        capabilities: welcome.capabilities,
+        last_seen: Instant::now(),
+        round_trip_time: None,
+        kick: Arc::new(Notify::new()),
+    })
+}
+
+async fn heartbeat(socket_addr: &SocketAddr, sender: Sender<Envelope>, server_state: Arc<ServerState>) {
+    let heartbeat_interval = server_state.config.heartbeat_interval();
+    let max_missed_heartbeats = server_state.config.limits.max_missed_heartbeats;
+    let mut interval = tokio::time::interval(heartbeat_interval);
+    let timeout = heartbeat_interval * max_missed_heartbeats;
+
+    loop {
+        interval.tick().await;
+
+        let last_seen = match server_state.client_sessions.lock().await.get(socket_addr) {
+            Some(session) => session.last_seen,
+            None => break,
+        };
+        if last_seen.elapsed() > timeout {
+            info!("{} missed {} heartbeats, dropping the connection", socket_addr, max_missed_heartbeats);
+            break;
+        }
+
+        if sender.send(Envelope::Ping(unix_millis())).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_player_disconnect(username: &String, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let game_id = match server_state.user_to_game.lock().await.get(username) {
+        Some(&game_id) => game_id,
+        None => return Ok(()),
+    };
+
+    let game_arc = identify_game(username, server_state).await?;
+    let is_pending = matches!(game_arc.lock().await.status, GameStatus::Pending);
+    if is_pending {
+        // Nobody else has joined yet, so there is nothing to wait for
+        game_arc.lock().await.abort();
+        finish_game(game_id, server_state).await;
+        info!("Pending game {} aborted, {} has disconnected", game_id, username);
+        return Ok(());
+    }
+
+    server_state.disconnected_players.lock().await.insert(username.clone(), Instant::now() + server_state.config.disconnect_grace_period());
+
+    if let Some(opponent) = identify_opponent(username.clone(), server_state).await? {
+        if let Some(sender) = server_state.user_connections.lock().await.get(&opponent) {
+            send_message(&opponent, Envelope::Event(Message::Log(format!("Your opponent {} has disconnected.", username))), sender).await?;
+        }
+        tokio::spawn(disconnect_countdown(username.clone(), opponent, server_state.clone()));
+    }
+    Ok(())
+}
+
+async fn disconnect_countdown(username: String, opponent: String, server_state: Arc<ServerState>) {
+    loop {
+        let deadline = match server_state.disconnected_players.lock().await.get(&username) {
+            Some(&deadline) => deadline,
+            None => return, // reconnected or the game was claimed
+        };
+        if !server_state.user_to_game.lock().await.contains_key(&username) {
+            return; // game ended some other way
+        }
+        let sender = match server_state.user_connections.lock().await.get(&opponent) {
+            Some(sender) => sender.clone(),
+            None => return,
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let text = if remaining.is_zero() {
+            format!("{} did not come back. Use /claim win or /claim draw to finish the game.", username)
+        } else {
+            format!("{} has {} seconds left to reconnect.", username, remaining.as_secs())
+        };
+        if send_message(&opponent, Envelope::Event(Message::Log(text)), &sender).await.is_err() {
+            return;
+        }
+        if remaining.is_zero() {
+            let _ = send_opponent_gone(&opponent, &username, &server_state).await;
+            return;
+        }
+
+        tokio::time::sleep(remaining.min(server_state.config.disconnect_countdown_step())).await;
+    }
+}
+
+async fn handle_player_reconnect(username: &String, request_id: RequestId, sender: &Sender<Envelope>, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    if server_state.disconnected_players.lock().await.remove(username).is_none() {
+        return Ok(());
+    }
+
+    let game_id = server_state.user_to_game.lock().await.get(username).cloned()
+        .ok_or(ChessError::GameStateError(format!("{} is not in a game", username)))?;
+    let game_arc = identify_game(username, server_state).await?;
+    let board_state = {
+        let game = game_arc.lock().await;
+        send_game_start(username, game_id, &game, server_state).await?;
+        game.board.to_string()
+    };
+    send_message(username, Envelope::Response { id: request_id, message: Message::Board(board_state) }, sender).await?;
+    send_message(username, Envelope::Response { id: request_id, message: Message::Log("You are back in your game.".to_string()) }, sender).await?;
+    replay_game_chat(username, request_id, sender, server_state).await?;
+
+    if let Some(opponent) = identify_opponent(username.clone(), server_state).await? {
+        if let Some(opponent_sender) = server_state.user_connections.lock().await.get(&opponent) {
+            send_message(&opponent, Envelope::Event(Message::Log(format!("{} has reconnected.", username))), opponent_sender).await?;
+        }
+        // Both may be away, e.g. after a restart, so count down for the one who is still missing
+        if server_state.disconnected_players.lock().await.contains_key(&opponent) {
+            tokio::spawn(disconnect_countdown(opponent, username.clone(), server_state.clone()));
+        }
+    }
+    Ok(())
+}
+
+async fn claim_abandoned_game(username: &String, win: bool, request_id: RequestId, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let sender = server_state.user_connections.lock().await.get(username).cloned()
+        .ok_or(ChessError::UserNotFoundError)?;
+    let opponent = identify_opponent(username.clone(), server_state).await?
+        .ok_or(ChessError::GameStateError("The game has no opponent yet".to_string()))?;
+
+    let deadline = server_state.disconnected_players.lock().await.get(&opponent).cloned();
+    let error = match deadline {
+        None => Some(format!("{} is still connected.", opponent)),
+        Some(deadline) if Instant::now() < deadline => Some(format!("{} still has {} seconds to reconnect.", opponent, deadline.saturating_duration_since(Instant::now()).as_secs())),
+        Some(_) => None,
+    };
+    if let Some(error) = error {
+        send_message(username, Envelope::Response { id: request_id, message: Message::Error(error.clone()) }, &sender).await?;
+        return Err(ChessError::GameStateError(error));
+    }
+
+    let game_id = server_state.user_to_game.lock().await.get(username).cloned()
+        .ok_or(ChessError::GameStateError("User not in game".to_string()))?;
+    let game_arc = identify_game(username, server_state).await?;
+    let result = {
+        let mut game = game_arc.lock().await;
+        if win {
+            game.concede(&opponent)?;
+        } else {
+            game.declare_draw();
+        }
+        game.result
+    };
+
+    server_state.disconnected_players.lock().await.remove(&opponent);
+    finish_game(game_id, server_state).await;
+
+    info!("{} claimed game {} abandoned by {}: {:?}", username, game_id, opponent, result);
+    send_message(username, Envelope::Response { id: request_id, message: Message::Log(format!("Game is finished. Result is: {:?}", result)) }, &sender).await?;
+    let game = game_arc.lock().await;
+    send_game_end(username, &game, "abandoned", server_state).await
+}
+
+async fn abort_if_no_first_move(game_id: u32, server_state: Arc<ServerState>) {
+    let first_move_timeout = server_state.config.first_move_timeout();
+    tokio::time::sleep(first_move_timeout).await;
+
+    let game_arc = match server_state.games.lock().await.get(&game_id) {
+        Some(game_arc) => game_arc.clone(),
+        None => return,
+    };
+    let players = {
+        let mut game = game_arc.lock().await;
+        if game.board != Board::default() || game.result.is_some() {
+            return;
+        }
+        game.abort();
+        [game.white.clone(), game.black.clone()]
+    };
+    finish_game(game_id, &server_state).await;
+    info!("Game {} aborted, no first move within {:?}", game_id, first_move_timeout);
+
+    let user_connections = server_state.user_connections.lock().await;
+    for player in players.iter().flatten() {
+        if let Some(sender) = user_connections.get(player) {
+            let _ = send_message(player, Envelope::Event(Message::Log(format!("The game was aborted because no move was made within {} seconds.", first_move_timeout.as_secs()))), sender).await;
+        }
+    }
+    drop(user_connections);
+    let game = game_arc.lock().await;
+    for player in players.iter().flatten() {
+        let _ = send_game_end(player, &game, "aborted", &server_state).await;
+    }
+}
+
+// Moves the game to `finished_games` and frees both players to start a new one
+async fn finish_game(game_id: u32, server_state: &Arc<ServerState>) {
+    let game_arc = server_state.games.lock().await.remove(&game_id);
+    if let Some(game_arc) = game_arc {
+        {
+            let game = game_arc.lock().await;
+            let mut user_to_game = server_state.user_to_game.lock().await;
+            for player in [game.white.as_ref(), game.black.as_ref()].into_iter().flatten() {
+                user_to_game.remove(player);
+            }
+        }
+        if let Err(e) = archive_game(game_id, &*game_arc.lock().await, server_state).await {
+            error!("Failed to archive game {}: {}", game_id, e);
+        }
+        server_state.finished_games.lock().await.insert(game_id, game_arc);
+    }
+}
+
+async fn announce_shutdown(server_state: &Arc<ServerState>) {
+    let senders = broadcast_notice("The server is shutting down. Games in progress are saved, log in again after the restart to continue them.", server_state).await;
+    wait_for_delivery(&senders).await;
+    info!("Notified {} clients about the shutdown", senders.len());
+}
+
+// Sends a notice to everyone connected, logged in or not
+async fn broadcast_notice(text: &str, server_state: &Arc<ServerState>) -> Vec<Sender<Envelope>> {
+    let mut senders: Vec<Sender<Envelope>> = server_state.user_connections.lock().await.values().cloned().collect();
+    senders.extend(server_state.anon_user_connections.lock().await.values().cloned());
+
+    // try_send, a client that stopped reading must not hold up everyone else
+    for sender in &senders {
+        let _ = sender.try_send(Envelope::Event(Message::Log(text.to_string())));
+    }
+    senders
+}
+
+// Gives the write tasks a moment to empty the queues before the connections are closed
+async fn wait_for_delivery(senders: &[Sender<Envelope>]) {
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    while Instant::now() < deadline && senders.iter().any(|sender| sender.capacity() < sender.max_capacity()) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn snapshot_games(server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let games: Vec<(u32, Arc<Mutex<Game>>)> = server_state.games.lock().await.iter()
+        .map(|(&game_id, game_arc)| (game_id, game_arc.clone()))
+        .collect();
+
+    let mut snapshots = Vec::new();
+    for (game_id, game_arc) in games {
+        let game = game_arc.lock().await;
+        if matches!(game.status, GameStatus::InProgress) {
+            snapshots.push(game.snapshot(game_id));
+        }
+    }
+    if snapshots.is_empty() {
+        return Ok(());
+    }
+
+    // Written next to the real file first, so a crash halfway doesn't leave a broken snapshot behind
+    let path = &server_state.config.storage.snapshot_file;
+    let temp_path = format!("{}.tmp", path);
+    let contents = serde_json::to_string_pretty(&snapshots)
+        .map_err(|e| ChessError::SerializationError(e.to_string()))?;
+    tokio::fs::write(&temp_path, contents).await
+        .map_err(|e| make_io_error(e, &format!("Failed to write {}", temp_path)))?;
+    tokio::fs::rename(&temp_path, path).await
+        .map_err(|e| make_io_error(e, &format!("Failed to write {}", path)))?;
+
+    info!("Saved {} games in progress to {}", snapshots.len(), path);
+    Ok(())
+}
+
+// Restored games wait for both players to log in again, with the usual grace period to come back
+async fn restore_games(server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let path = &server_state.config.storage.snapshot_file;
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(make_io_error(e, &format!("Failed to read {}", path))),
+    };
+    let snapshots: Vec<GameSnapshot> = serde_json::from_str(&contents)
+        .map_err(|e| ChessError::DeserializationError(format!("Failed to parse {}: {}", path, e)))?;
+
+    let deadline = Instant::now() + server_state.config.disconnect_grace_period();
+    let mut restored = 0;
+    for snapshot in snapshots {
+        let game_id = snapshot.game_id;
+        let game = match Game::restore(snapshot) {
+            Ok(game) => game,
+            Err(e) => {
+                error!("Skipping game {}: {}", game_id, e);
+                continue;
+            }
+        };
+
+        for player in [game.white.as_ref(), game.black.as_ref()].into_iter().flatten() {
+            server_state.user_to_game.lock().await.insert(player.clone(), game_id);
+            server_state.disconnected_players.lock().await.insert(player.clone(), deadline);
+        }
+        server_state.games.lock().await.insert(game_id, Arc::new(Mutex::new(game)));
+        server_state.last_game_id.fetch_max(game_id + 1, Ordering::SeqCst);
+        restored += 1;
+    }
+
+    // The games live in memory from now on, the next shutdown writes a fresh snapshot
+    tokio::fs::remove_file(path).await
+        .map_err(|e| make_io_error(e, &format!("Failed to remove {}", path)))?;
+    info!("Restored {} games in progress from {}", restored, path);
+    Ok(())
 }
 
-async fn listen_to_client_messages(reader: &mut OwnedReadHalf, socket_addr: &SocketAddr, server_state: Arc<ServerState>) {
+async fn listen_to_client_messages(reader: &mut ClientReader, socket_addr: &SocketAddr, reply_sender: Sender<Envelope>, server_state: Arc<ServerState>) {
+    let mut rate_limiter = RateLimiter::new(&server_state.config.limits);
     loop {
-        match listen_to_messages(reader).await {
-            Ok(message) => {
-                match process_message(message, socket_addr, server_state.clone()).await {
-                    Ok(_) => {},
+        let envelope = reader.read().await;
+
+        if envelope.is_ok() {
+            if let Some(session) = server_state.client_sessions.lock().await.get_mut(socket_addr) {
+                session.last_seen = Instant::now();
+            }
+        }
+
+        match envelope {
+            Ok(Envelope::Request { id, message }) => {
+                server_state.metrics.messages.with_label_values(&[message_type(&message)]).inc();
+                let is_chat = matches!(message, Message::Chat(_) | Message::Text(_));
+                let (result, disconnect) = match rate_limiter.check(is_chat) {
+                    Verdict::Allowed => (process_message(message, id, socket_addr, server_state.clone()).await, false),
+                    verdict => {
+                        server_state.metrics.rate_limited.inc();
+                        rate_limited(verdict, socket_addr, &server_state).await
+                    }
+                };
+                let (error, code) = match result {
+                    Ok(_) => (None, None),
                     Err(e) => {
                         error!("Error while processing messages: {}", e);
+                        (Some(e.to_string()), e.code())
                     }
+                };
+                if let Err(e) = reply_sender.send(Envelope::Ack { id, error, code }).await {
+                    error!("Failed to acknowledge request {}: {}", id, e);
+                    break;
+                }
+                if disconnect {
+                    // Let the writer flush the Ack before the connection goes away
+                    wait_for_delivery(&[reply_sender.clone()]).await;
+                    break;
+                }
+            }
+            Ok(Envelope::Ping(timestamp)) => {
+                if reply_sender.send(Envelope::Pong(timestamp)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Envelope::Pong(timestamp)) => {
+                let round_trip_time = Duration::from_millis(unix_millis().saturating_sub(timestamp));
+                if let Some(session) = server_state.client_sessions.lock().await.get_mut(socket_addr) {
+                    session.round_trip_time = Some(round_trip_time);
                 }
+                info!("Round-trip time to {}: {:?}", socket_addr, round_trip_time);
+            }
+            Ok(other) => {
+                error!("Expected Request, received {:?}", other);
             }
             
             Err(e) => {
+                if let ChessError::DeserializationError(_) = e {
+                    server_state.metrics.deserialization_errors.inc();
+                }
                 error!("Error while listening to messages: {}", e);
                 break;
             }
@@ -204,45 +761,61 @@ async fn listen_to_client_messages(reader: &mut OwnedReadHalf, socket_addr: &Soc
     }
 }
 
-async fn process_message(message: Message, socket_addr: &SocketAddr, server_state: Arc<ServerState>) -> Result<(), ChessError> {
+// The request is dropped either way, the result becomes its Ack; true when the client should be disconnected
+async fn rate_limited(verdict: Verdict, socket_addr: &SocketAddr, server_state: &Arc<ServerState>) -> (Result<(), ChessError>, bool) {
+    match verdict {
+        Verdict::Allowed => (Ok(()), false),
+        Verdict::Limited => (Err(ChessError::RateLimited("slow down".to_string())), false),
+        Verdict::Mute => {
+            let minutes = server_state.config.limits.flood_mute_minutes;
+            if let Some(username) = identify_user_by_addr(socket_addr, server_state).await {
+                if let Err(e) = mute_user(&username, Some(minutes), "server", server_state).await {
+                    error!("Failed to mute {} for flooding: {}", username, e);
+                }
+            }
+            (Err(ChessError::Muted(format!("muted for {} minutes for flooding the chat", minutes))), false)
+        }
+        Verdict::Disconnect => {
+            info!("Disconnecting {} for flooding", socket_addr);
+            (Err(ChessError::Flooding("too many requests, closing the connection".to_string())), true)
+        }
+    }
+}
+
+async fn process_message(message: Message, request_id: RequestId, socket_addr: &SocketAddr, server_state: Arc<ServerState>) -> Result<(), ChessError> {
     match message {
-        Message::Command(command) => process_command(command, socket_addr, server_state).await,
+        Message::Command(command) => {
+            let _timer = server_state.metrics.command_latency.with_label_values(&[command_name(&command)]).start_timer();
+            process_command(command, request_id, socket_addr, server_state.clone()).await
+        },
         Message::Move(player_move) => { 
-            // TODO: refactor - we identify game twice while processing move
             if let Some(username) = identify_user_by_addr(socket_addr, &server_state).await {
-                if let Err(err) = process_move(player_move, &username, &server_state).await {
-                    // Handle move processing error (e.g., invalid move)
-                    return Err(err);
-                }
-        
-                match identify_game(&username, &server_state).await {
-                    Ok(game) => {
-                        send_game_state(&mut *game.lock().await, &server_state).await?;
-                        Ok(())
-                    },
-                    Err(err) => {
-                        Err(err)
-                    }
-                }
+                process_move(player_move, request_id, &username, &server_state).await
             } else {
                 Err(ChessError::UserNotFoundError)
             }
         },
         Message::Text(text) => {
-            info!("Received the following text message: {}", text);
+            // Older clients only know game chat
             let username = identify_user_by_addr(socket_addr, &server_state).await
                 .ok_or(ChessError::UserStateError("User not found".to_string()))?;
-            if let Some(opponent) = identify_opponent(username, &server_state).await? {
-                if let Some(sender) = server_state.user_connections.lock().await.get(&opponent) {
-                    sender.send(Message::Text(text.clone())).await
-                        .map_err(|e| ChessError::MessageHandlingError(format!("Failed to send message: {}", e)))?;
-                }
-            }
-            Ok(())
+            let chat = Chat { channel: ChatChannel::Game, sender: username.clone(), text, sent_at: 0 };
+            process_chat(chat, request_id, &username, &server_state).await
         },
-        Message::Board(_) => panic!("Expected Command, Move or Text, received Board"),
-        Message::Error(_) => panic!("Expected Command, Move or Text, received Error"),
-        Message::Log(_) => panic!("Expected Command, Move or Text, received Log"),
+        Message::Chat(chat) => {
+            let username = identify_user_by_addr(socket_addr, &server_state).await
+                .ok_or(ChessError::UserStateError("Log in to chat".to_string()))?;
+            process_chat(chat, request_id, &username, &server_state).await
+        },
+        Message::Board(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received Board".to_string())),
+        Message::GameStart(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received GameStart".to_string())),
+        Message::GameEnd(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received GameEnd".to_string())),
+        Message::OpponentGone(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received OpponentGone".to_string())),
+        Message::SearchResults(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received SearchResults".to_string())),
+        Message::Error(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received Error".to_string())),
+        Message::Log(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received Log".to_string())),
+        Message::Hello(_) => Err(ChessError::ProtocolError("Handshake has already been completed".to_string())),
+        Message::Welcome(_) => Err(ChessError::ProtocolError("Expected Command, Move, Text or Chat, received Welcome".to_string())),
     }
 }
 
@@ -254,33 +827,37 @@ async fn send_game_state(game: &mut Game, server_state: &Arc<ServerState>) -> Re
 
     let board_state = &game.board.to_string();
 
-    let white_sender = user_connections.get(white_player).ok_or(ChessError::UserNotFoundError)?;
-    let black_sender = user_connections.get(black_player).ok_or(ChessError::UserNotFoundError)?;
+    let white_sender = user_connections.get(white_player).cloned().ok_or(ChessError::UserNotFoundError)?;
+    let black_sender = user_connections.get(black_player).cloned().ok_or(ChessError::UserNotFoundError)?;
+    drop(user_connections);
 
-    white_sender.send(Message::Board(board_state.clone())).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
-    black_sender.send(Message::Board(board_state.to_string())).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+    white_sender.send(Envelope::Event(Message::Board(board_state.clone()))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+    black_sender.send(Envelope::Event(Message::Board(board_state.to_string()))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
 
     if game.current_turn == Color::White {
-        white_sender.send(Message::Log(format!("Your turn, white player {white_player}!"))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+        white_sender.send(Envelope::Event(Message::Log(format!("Your turn, white player {white_player}!")))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
     } else {
-        black_sender.send(Message::Log(format!("Your turn, black player {black_player}!"))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+        black_sender.send(Envelope::Event(Message::Log(format!("Your turn, black player {black_player}!")))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
     }
 
     if game.result == None {
         if game.is_check() {
-            white_sender.send(Message::Log(format!("Check!"))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
-            black_sender.send(Message::Log(format!("Check!"))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+            white_sender.send(Envelope::Event(Message::Log(format!("Check!")))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+            black_sender.send(Envelope::Event(Message::Log(format!("Check!")))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
         }
         return Ok(());
     } 
     
     if game.is_mate() {
-        white_sender.send(Message::Log(format!("Mate!"))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
-        black_sender.send(Message::Log(format!("Mate!"))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+        white_sender.send(Envelope::Event(Message::Log(format!("Mate!")))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+        black_sender.send(Envelope::Event(Message::Log(format!("Mate!")))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
     }
 
-    white_sender.send(Message::Log(format!("Game is finished. Result is: {:?}", game.result))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
-    black_sender.send(Message::Log(format!("Game is finished. Result is: {:?}", game.result))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?; 
+    white_sender.send(Envelope::Event(Message::Log(format!("Game is finished. Result is: {:?}", game.result)))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?;
+    black_sender.send(Envelope::Event(Message::Log(format!("Game is finished. Result is: {:?}", game.result)))).await.map_err(|e| ChessError::MessageHandlingError(e.to_string()))?; 
+    for player in [game.white.clone(), game.black.clone()].into_iter().flatten() {
+        send_game_end(&player, game, game.end_reason(), server_state).await?;
+    }
 
     Ok(())
 }
@@ -326,10 +903,29 @@ async fn identify_user_by_addr(socket_addr: &SocketAddr, server_state: &Arc<Serv
     return server_state.addr_to_user.lock().await.get(&socket_addr).cloned()
 }
 
-async fn process_command(command: Command, socket_addr: &SocketAddr, server_state: Arc<ServerState>) -> Result<(), ChessError> {
+async fn identify_addr_by_user(username: &str, server_state: &Arc<ServerState>) -> Option<SocketAddr> {
+    server_state.addr_to_user.lock().await.iter()
+        .find(|(_, user)| user.as_str() == username)
+        .map(|(&socket_addr, _)| socket_addr)
+}
+
+// What was agreed on in the handshake, None if the user isn't connected
+async fn protocol_version_of(username: &str, server_state: &Arc<ServerState>) -> Option<u16> {
+    let socket_addr = identify_addr_by_user(username, server_state).await?;
+    server_state.client_sessions.lock().await.get(&socket_addr)
+        .map(|session| session.protocol_version)
+}
+
+async fn process_command(command: Command, request_id: RequestId, socket_addr: &SocketAddr, server_state: Arc<ServerState>) -> Result<(), ChessError> {
     match command {
         Command::LogIn(username) => {
-            if authenticate(&username).await? {
+            if let Some(ban) = find_ban(&username, Some(socket_addr.ip()), &server_state).await {
+                if let Some(sender) = server_state.anon_user_connections.lock().await.get(socket_addr).cloned() {
+                    send_message(&username, Envelope::Response { id: request_id, message: Message::Error(format!("You are banned from this server {}.", ban.describe())) }, &sender).await?;
+                }
+                return Err(ChessError::UserStateError(format!("{} is banned", username)));
+            }
+            if authenticate(&username, &server_state.config.storage.user_file).await? {
                 let sender = {
                     let mut anon_connections = server_state.anon_user_connections.lock().await;
                     anon_connections.remove(&socket_addr)
@@ -339,13 +935,16 @@ async fn process_command(command: Command, socket_addr: &SocketAddr, server_stat
                     user_connections.insert(username.clone(), sender.clone());
                     let mut addr_user = server_state.addr_to_user.lock().await;
                     addr_user.insert(socket_addr.clone(), username.clone());
-                    let _ = send_message(&username, Message::Log(format!("Authenticated successfully. Welcome back, {}.", username)), &sender).await?;
-                    Ok(())
+                    drop(user_connections);
+                    drop(addr_user);
+                    let _ = send_message(&username, Envelope::Response { id: request_id, message: Message::Log(format!("Authenticated successfully. Welcome back, {}.", username)) }, &sender).await?;
+                    handle_player_reconnect(&username, request_id, &sender, &server_state).await
                 } else {
                     Err(ChessError::SenderNotFoundError(format!("Sender not found for socket address: {:?}", socket_addr)))
                 }
             } else {
-                let _ = register(&username).await?;
+                let _ = register(&username, &server_state.config.storage.user_file).await?;
+                server_state.registered_users.lock().await.insert(username.clone());
                 let sender = {
                     let mut anon_connections = server_state.anon_user_connections.lock().await;
                     anon_connections.remove(&socket_addr)
@@ -356,7 +955,7 @@ async fn process_command(command: Command, socket_addr: &SocketAddr, server_stat
                     user_connections.insert(username.clone(), sender.clone());
                     let mut addr_user = server_state.addr_to_user.lock().await;
                     addr_user.insert(socket_addr.clone(), username.clone());
-                    let _ = send_message(&username, Message::Log(format!("Registered a new user. Welcome, {}! Hope you are going to enjoy our chess server. Use /play to start your first game!", username)), &sender).await?;
+                    let _ = send_message(&username, Envelope::Response { id: request_id, message: Message::Log(format!("Registered a new user. Welcome, {}! Hope you are going to enjoy our chess server. Use /play to start your first game!", username)) }, &sender).await?;
                     Ok(())
                 } else {
                     Err(ChessError::SenderNotFoundError(format!("Tried registering. Sender not found for socket address: {:?}", socket_addr)))
@@ -371,58 +970,86 @@ async fn process_command(command: Command, socket_addr: &SocketAddr, server_stat
                     drop(user_to_game);
                     let user_connections = server_state.user_connections.lock().await;
                     if let Some(sender) = user_connections.get(&username).cloned() {
-                        send_message(&username, Message::Error("You cannot start a new game until this one is finished!".to_string()), &sender).await?;
+                        send_message(&username, Envelope::Response { id: request_id, message: Message::Error("You cannot start a new game until this one is finished!".to_string()) }, &sender).await?;
                     }
                     return Err(ChessError::UserStateError("User already in a game.".to_string()));
                 }
 
                 drop(user_to_game); 
+                if server_state.shutting_down.load(Ordering::SeqCst) {
+                    if let Some(sender) = server_state.user_connections.lock().await.get(&username).cloned() {
+                        send_message(&username, Envelope::Response { id: request_id, message: Message::Error("The server is shutting down, no new games can be started.".to_string()) }, &sender).await?;
+                    }
+                    return Err(ChessError::GameStateError("Server is shutting down.".to_string()));
+                }
                 info!("Assigning to a game");
-                assign_to_game(username, server_state.clone()).await
+                assign_to_game(username, request_id, server_state.clone()).await
             } else {
-                let sender = {
-                    let mut anon_connections = server_state.anon_user_connections.lock().await;
-                    anon_connections.remove(&socket_addr)
-                };
+                let sender = server_state.anon_user_connections.lock().await.get(&socket_addr).cloned();
                 if let Some(sender) = sender {
-                    let _ = sender.send(Message::Error("Anonymous users cannot start games. Please use /log in.".to_string())).await;
+                    let _ = sender.send(Envelope::Response { id: request_id, message: Message::Error("Anonymous users cannot start games. Please use /log in.".to_string()) }).await;
                 }
                 Err(ChessError::UserStateError("Failed to get username from the server state (unregistered player tried to play).".to_string()))
             }
         },
         Command::Concede => {
             if let Some(username) = identify_user_by_addr(socket_addr, &server_state).await {
-                match identify_game(&username, &server_state).await {
-                    Ok(game_arc) => {
-                        let mut game = game_arc.lock().await;
-                        game.concede(&username).unwrap_or_else(|e| error!("Error during concession: {}", e));
-        
-                        let mut user_to_game = server_state.user_to_game.lock().await;
-                        if let Some(player) = game.white.as_ref() {
-                            user_to_game.remove(player);
-                        }
-                        if let Some(player) = game.black.as_ref() {
-                            user_to_game.remove(player);
-                        }
-        
-                        send_game_state(&mut game, &server_state).await?;
-        
+                let game_id = server_state.user_to_game.lock().await.get(&username).copied();
+                match (game_id, identify_game(&username, &server_state).await) {
+                    (Some(game_id), Ok(game_arc)) => {
+                        game_arc.lock().await.concede(&username).unwrap_or_else(|e| error!("Error during concession: {}", e));
+                        finish_game(game_id, &server_state).await;
+
+                        send_game_state(&mut *game_arc.lock().await, &server_state).await?;
+
                         Ok(())
                     },
-                    Err(err) => Err(err),
+                    (_, Err(err)) => Err(err),
+                    (None, Ok(_)) => Err(ChessError::GameStateError("User not in game".to_string())),
                 }
             } else {
                 Err(ChessError::UserNotFoundError)
             }
         }
-        Command::Stats => unimplemented!("TODO stats"),
+        Command::ClaimWin | Command::ClaimDraw => {
+            if let Some(username) = identify_user_by_addr(socket_addr, &server_state).await {
+                claim_abandoned_game(&username, matches!(command, Command::ClaimWin), request_id, &server_state).await
+            } else {
+                Err(ChessError::UserNotFoundError)
+            }
+        }
+        Command::Stats => Err(ChessError::MessageHandlingError("Stats are not available yet".to_string())), // TODO stats
+        Command::Kick(_) | Command::Ban(..) | Command::Unban(_) | Command::Mute(..) | Command::Unmute(_) | Command::EndGame(_) => {
+            let username = identify_user_by_addr(&socket_addr, &server_state).await
+                .ok_or(ChessError::UserNotFoundError)?;
+            process_moderation_command(command, request_id, &username, &server_state).await
+        },
+        Command::Search(query) => {
+            // Open to anonymous users too, the archive is public through the JSON API anyway
+            let protocol_version = server_state.client_sessions.lock().await.get(socket_addr).map(|session| session.protocol_version);
+            if protocol_version.is_none_or(|protocol_version| protocol_version < SEARCH_PROTOCOL_VERSION) {
+                return Err(ChessError::ProtocolError(format!("Search needs protocol version {}", SEARCH_PROTOCOL_VERSION)));
+            }
+            let sender = match identify_user_by_addr(socket_addr, &server_state).await {
+                Some(username) => server_state.user_connections.lock().await.get(&username).cloned(),
+                None => server_state.anon_user_connections.lock().await.get(socket_addr).cloned(),
+            }.ok_or(ChessError::SenderNotFoundError(format!("Sender not found for socket address: {:?}", socket_addr)))?;
+            let message = match search(&query, &server_state).await {
+                Ok(results) => Message::SearchResults(results),
+                Err(e) => {
+                    send_message(&socket_addr.to_string(), Envelope::Response { id: request_id, message: Message::Error(e.to_string()) }, &sender).await?;
+                    return Err(e);
+                }
+            };
+            send_message(&socket_addr.to_string(), Envelope::Response { id: request_id, message }, &sender).await
+        },
         _ => unreachable!("Unexpected command {command}")
     }
 }
 
-async fn authenticate(username: &str) -> Result<bool, ChessError> {
+async fn authenticate(username: &str, user_file: &str) -> Result<bool, ChessError> {
     info!("Trying to authenticate {username}...");
-    match tokio::fs::read_to_string(USER_FILE)
+    match tokio::fs::read_to_string(user_file)
     .await {
         Ok(file_contents) => {
             let result = file_contents.lines().any(|line| line == username);
@@ -436,11 +1063,11 @@ async fn authenticate(username: &str) -> Result<bool, ChessError> {
     }
 }
 
-async fn register(username: &str) -> Result<bool, ChessError>  {
+async fn register(username: &str, user_file: &str) -> Result<bool, ChessError>  {
     info!("Trying to register {username}...");
     match tokio::fs::OpenOptions::new()
         .append(true)
-        .open(USER_FILE)
+        .open(user_file)
         .await {
             Ok(mut file) => {
                 let content = format!("{}\n", username);
@@ -458,7 +1085,15 @@ async fn register(username: &str) -> Result<bool, ChessError>  {
     }
 }
 
-async fn process_move(user_move: String, username: &String, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+async fn process_move(user_move: String, request_id: RequestId, username: &String, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    // The board is being saved, a move now would be lost
+    if server_state.shutting_down.load(Ordering::SeqCst) {
+        if let Some(sender) = server_state.user_connections.lock().await.get(username).cloned() {
+            send_message(username, Envelope::Response { id: request_id, message: Message::Error("The server is shutting down, your game will continue after the restart.".to_string()) }, &sender).await?;
+        }
+        return Err(ChessError::GameStateError("Server is shutting down.".to_string()));
+    }
+
     let user_to_game = server_state.user_to_game.lock().await;
     if let Some(&game_id) = user_to_game.get(username) {
         drop(user_to_game);
@@ -469,48 +1104,37 @@ async fn process_move(user_move: String, username: &String, server_state: &Arc<S
 
             if game.white.is_none() || game.black.is_none() {
                 if let Some(sender) = server_state.user_connections.lock().await.get(username) {
-                    sender.send(Message::Error("The game has not started yet. We are waiting for a second player to join.".to_string())).await
+                    sender.send(Envelope::Response { id: request_id, message: Message::Error("The game has not started yet. We are waiting for a second player to join.".to_string()) }).await
                         .map_err(|e| ChessError::MessageHandlingError(format!("Failed to send message: {}", e)))?;
                 }
                 return Err(ChessError::GameStateError("The game has not started yet. We are waiting for a second player to join.".to_string()));
             } else if !(game.current_turn == Color::Black && game.black.as_ref() == Some(username) || game.current_turn == Color::White && game.white.as_ref() == Some(username)) {
                 if let Some(sender) = server_state.user_connections.lock().await.get(username) {
-                    sender.send(Message::Error("It's not your turn.".to_string())).await
+                    sender.send(Envelope::Response { id: request_id, message: Message::Error("It's not your turn.".to_string()) }).await
                         .map_err(|e| ChessError::MessageHandlingError(format!("Failed to send message: {}", e)))?;
                 }
                 return Err(ChessError::GameStateError("It's not your turn.".to_string()));
             }
 
             game.make_move(&user_move)?;
+            server_state.metrics.moves.inc();
             info!("Move made: {}", user_move);
             let game_is_finished: bool = game.result.is_some();
 
-            let white_player = game.white.clone();
-            let black_player = game.black.clone();
-
             drop(game);
+            let game_arc = game_arc.clone();
+            drop(games);
 
             if game_is_finished {
-                let mut finished_games = server_state.finished_games.lock().await;
-                let mut games = server_state.games.lock().await;
-
-                if let Some(game_arc) = games.remove(&game_id) {
-                    finished_games.insert(game_id, game_arc);
-                }
-
-                let mut user_to_game = server_state.user_to_game.lock().await;
-                if let Some(player) = white_player {
-                    user_to_game.remove(&player);
-                }
-                if let Some(player) = black_player {
-                    user_to_game.remove(&player);
-                }
+                finish_game(game_id, server_state).await;
             }
 
+            send_game_state(&mut *game_arc.lock().await, server_state).await?;
+
             Ok(())
         } else {
             if let Some(sender) = server_state.user_connections.lock().await.get(username) {
-                sender.send(Message::Error("You are not in a game. Start a game using /play.".to_string())).await
+                sender.send(Envelope::Response { id: request_id, message: Message::Error("You are not in a game. Start a game using /play.".to_string()) }).await
                     .map_err(|e| ChessError::MessageHandlingError(format!("Failed to send message: {}", e)))?;
             }
             Err(ChessError::GameStateError("Game not found".to_string()))
@@ -520,18 +1144,58 @@ async fn process_move(user_move: String, username: &String, server_state: &Arc<S
     }
 }
 
-async fn start_game (game: &mut Game, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+async fn start_game (game_id: u32, game: &mut Game, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
     let white_player = game.white.as_ref().ok_or(ChessError::GameStateError("White player missing".to_string()))?;
     let black_player = game.black.as_ref().ok_or(ChessError::GameStateError("Black player missing".to_string()))?;
 
     game.status = GameStatus::InProgress;
 
     info!("Starting a new game: {} as whites, {} as blacks.", white_player, black_player);
+    for player in [white_player, black_player] {
+        send_game_start(player, game_id, game, server_state).await?;
+    }
     send_game_state(game, server_state).await?;
     Ok(())
 }
 
-async fn assign_to_game(username: String, server_state: Arc<ServerState>) -> Result<(), ChessError> {
+// Lets bots claim the game without parsing the `Log`, skipped for clients that predate it
+async fn send_opponent_gone(username: &str, opponent: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    if protocol_version_of(username, server_state).await.is_none_or(|protocol_version| protocol_version < OPPONENT_GONE_PROTOCOL_VERSION) {
+        return Ok(());
+    }
+    if let Some(sender) = server_state.user_connections.lock().await.get(username) {
+        send_message(username, Envelope::Event(Message::OpponentGone(opponent.to_string())), sender).await?;
+    }
+    Ok(())
+}
+
+// Lets the client show the board from its own side, skipped for clients that predate it
+async fn send_game_start(username: &str, game_id: u32, game: &Game, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    if protocol_version_of(username, server_state).await.is_none_or(|protocol_version| protocol_version < GAME_START_PROTOCOL_VERSION) {
+        return Ok(());
+    }
+    let (Some(white), Some(black)) = (game.white.clone(), game.black.clone()) else {
+        return Ok(()); // still waiting for an opponent
+    };
+    if let Some(sender) = server_state.user_connections.lock().await.get(username) {
+        send_message(username, Envelope::Event(Message::GameStart(GameStart { game_id, white, black })), sender).await?;
+    }
+    Ok(())
+}
+
+// Lets scripts and bots tell how the game ended without parsing the `Log`, skipped for clients that predate it
+async fn send_game_end(username: &str, game: &Game, reason: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    if protocol_version_of(username, server_state).await.is_none_or(|protocol_version| protocol_version < GAME_END_PROTOCOL_VERSION) {
+        return Ok(());
+    }
+    let game_end = GameEnd { result: game.pgn_result().to_string(), reason: reason.to_string() };
+    if let Some(sender) = server_state.user_connections.lock().await.get(username) {
+        send_message(username, Envelope::Event(Message::GameEnd(game_end)), sender).await?;
+    }
+    Ok(())
+}
+
+async fn assign_to_game(username: String, request_id: RequestId, server_state: Arc<ServerState>) -> Result<(), ChessError> {
     //info!("Getting games");
     let mut games = server_state.games.lock().await;
     let mut user_game_assigned = false;
@@ -552,7 +1216,9 @@ async fn assign_to_game(username: String, server_state: Arc<ServerState>) -> Res
             user_game_assigned = true;
             assigned_game_id = game_id;
             info!("{} is now black in game {}", username, game_id);
-            let _ = start_game(&mut game, &server_state).await?;
+            server_state.metrics.matchmaking_wait.observe(game.created_at.elapsed().as_secs_f64());
+            let _ = start_game(game_id, &mut game, &server_state).await?;
+            tokio::spawn(abort_if_no_first_move(game_id, server_state.clone()));
             break;
         }
     }
@@ -568,6 +1234,10 @@ async fn assign_to_game(username: String, server_state: Arc<ServerState>) -> Res
             black: None,
             status: chess_game::GameStatus::Pending,
             result: None,
+            moves: Vec::new(),
+            created_at: Instant::now(),
+            chat: Vec::new(),
+            finished_at: None,
         };
         games.insert(new_game_id, Arc::new(Mutex::new(new_game)));
         assigned_game_id = new_game_id;
@@ -578,14 +1248,14 @@ async fn assign_to_game(username: String, server_state: Arc<ServerState>) -> Res
 
     info!("Informing the user that they are in a game.");
     if let Some(sender) = server_state.user_connections.lock().await.get(&username) {
-        send_message(&username, Message::Log(format!("You're in a game now!")), sender).await?;
+        send_message(&username, Envelope::Response { id: request_id, message: Message::Log(format!("You're in a game now!")) }, sender).await?;
         Ok(())
     } else {
         Err(ChessError::UserNotFoundError)
     }
 }
 
-async fn send_message(username: &str, message: Message, sender: &Sender<Message>) -> Result<(), ChessError> {
+async fn send_message(username: &str, message: Envelope, sender: &Sender<Envelope>) -> Result<(), ChessError> {
     info!("Trying to send message {:?} to {username}", message);
 
     if let Err(e) = sender.send(message).await {
@@ -594,4 +1264,166 @@ async fn send_message(username: &str, message: Message, sender: &Sender<Message>
         info!("Successfully sent message to {username}");
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+
+    // A server state whose files live in a fresh temporary directory
+    pub(crate) async fn test_state(mut config: Config) -> Arc<ServerState> {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("chess-server-test-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        config.storage.user_file = path("usernames.txt");
+        config.storage.snapshot_file = path("games.json");
+        config.storage.ban_file = path("bans.json");
+        config.storage.audit_log = path("audit.log");
+        config.storage.archive_file = path("archive.jsonl");
+        std::fs::write(&config.storage.user_file, "").unwrap();
+        Arc::new(ServerState::new(config).await.unwrap())
+    }
+
+    fn session(last_seen: Instant) -> ClientSession {
+        ClientSession {
+            client_name: "test".to_string(),
+            client_version: "0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Heartbeat],
+            last_seen,
+            round_trip_time: None,
+            kick: Arc::new(Notify::new()),
+        }
+    }
+
+    // A logged in user on its own address, with the receiving end of what the server sends it
+    pub(crate) async fn connect(username: &str, protocol_version: u16, server_state: &Arc<ServerState>) -> mpsc::Receiver<Envelope> {
+        let socket_addr: SocketAddr = format!("127.0.0.1:{}", 40001 + server_state.addr_to_user.lock().await.len()).parse().unwrap();
+        let (sender, receiver) = mpsc::channel(10);
+        server_state.user_connections.lock().await.insert(username.to_string(), sender);
+        server_state.addr_to_user.lock().await.insert(socket_addr, username.to_string());
+        server_state.client_sessions.lock().await.insert(socket_addr, ClientSession { protocol_version, ..session(Instant::now()) });
+        receiver
+    }
+
+    async fn heartbeat_state() -> (Arc<ServerState>, SocketAddr) {
+        let mut config = Config::default();
+        config.limits.heartbeat_interval_secs = 1;
+        config.limits.max_missed_heartbeats = 2;
+        (test_state(config).await, "127.0.0.1:40000".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn heartbeat_pings_a_live_client() {
+        let (server_state, addr) = heartbeat_state().await;
+        server_state.client_sessions.lock().await.insert(addr, session(Instant::now()));
+        let (sender, mut receiver) = mpsc::channel(10);
+
+        let still_running = tokio::time::timeout(Duration::from_millis(200), heartbeat(&addr, sender, server_state.clone())).await.is_err();
+        assert!(still_running);
+        assert!(matches!(receiver.try_recv(), Ok(Envelope::Ping(_))));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_gives_up_on_a_silent_client() {
+        let (server_state, addr) = heartbeat_state().await;
+        // Nothing heard for longer than two missed heartbeats
+        server_state.client_sessions.lock().await.insert(addr, session(Instant::now() - Duration::from_secs(3)));
+        let (sender, mut receiver) = mpsc::channel(10);
+
+        tokio::time::timeout(Duration::from_millis(200), heartbeat(&addr, sender, server_state.clone())).await
+            .expect("the heartbeat should end by itself");
+        assert!(receiver.try_recv().is_err(), "no ping for a dead client");
+    }
+
+    #[tokio::test]
+    async fn heartbeat_stops_with_the_connection() {
+        // The session is gone once the connection is cleaned up
+        let (server_state, addr) = heartbeat_state().await;
+        let (sender, _receiver) = mpsc::channel(10);
+        tokio::time::timeout(Duration::from_millis(200), heartbeat(&addr, sender, server_state.clone())).await
+            .expect("no session, no heartbeat");
+
+        // The writer task is gone and nobody reads the pings
+        server_state.client_sessions.lock().await.insert(addr, session(Instant::now()));
+        let (sender, receiver) = mpsc::channel(10);
+        drop(receiver);
+        tokio::time::timeout(Duration::from_millis(200), heartbeat(&addr, sender, server_state.clone())).await
+            .expect("closed channel, no heartbeat");
+    }
+
+    fn snapshot(game_id: u32) -> GameSnapshot {
+        GameSnapshot {
+            game_id,
+            white: Some("alice".to_string()),
+            black: Some("bob".to_string()),
+            board: "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1".to_string(),
+            moves: vec!["e2e4".to_string()],
+            chat: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_users_are_loaded_once() {
+        let server_state = test_state(Config::default()).await;
+        std::fs::write(&server_state.config.storage.user_file, "alice\n\nbob\n").unwrap();
+
+        let restarted = ServerState::new(server_state.config.clone()).await.unwrap();
+        let users: Vec<String> = restarted.registered_users.lock().await.iter().cloned().collect();
+        assert_eq!(users, ["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_a_restart() {
+        let server_state = test_state(Config::default()).await;
+        server_state.games.lock().await.insert(7, Arc::new(Mutex::new(Game::restore(snapshot(7)).unwrap())));
+        snapshot_games(&server_state).await.unwrap();
+
+        let restarted = Arc::new(ServerState::new(server_state.config.clone()).await.unwrap());
+        restore_games(&restarted).await.unwrap();
+        let game = restarted.games.lock().await.get(&7).unwrap().clone();
+        let game = game.lock().await;
+        assert_eq!(game.moves, ["e2e4"]);
+        assert_eq!(game.current_turn, Color::Black);
+        assert_eq!(restarted.user_to_game.lock().await.get("alice"), Some(&7));
+        assert!(restarted.disconnected_players.lock().await.contains_key("bob"));
+        assert_eq!(restarted.last_game_id.load(Ordering::SeqCst), 8);
+        // Resumed games are written afresh on the next shutdown
+        assert!(!Path::new(&restarted.config.storage.snapshot_file).exists());
+    }
+
+    #[tokio::test]
+    async fn only_games_in_progress_are_saved() {
+        let server_state = test_state(Config::default()).await;
+        server_state.games.lock().await.insert(1, Arc::new(Mutex::new(Game::new())));
+        snapshot_games(&server_state).await.unwrap();
+        assert!(!Path::new(&server_state.config.storage.snapshot_file).exists());
+    }
+
+    #[tokio::test]
+    async fn restore_skips_broken_games() {
+        let server_state = test_state(Config::default()).await;
+        let mut broken = snapshot(2);
+        broken.board = "not a board".to_string();
+        let contents = serde_json::to_string(&vec![broken, snapshot(3)]).unwrap();
+        std::fs::write(&server_state.config.storage.snapshot_file, contents).unwrap();
+
+        restore_games(&server_state).await.unwrap();
+        let games = server_state.games.lock().await;
+        assert!(!games.contains_key(&2));
+        assert!(games.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn restore_without_a_snapshot() {
+        let server_state = test_state(Config::default()).await;
+        restore_games(&server_state).await.unwrap();
+        assert!(server_state.games.lock().await.is_empty());
+
+        std::fs::write(&server_state.config.storage.snapshot_file, "not json").unwrap();
+        assert!(matches!(restore_games(&server_state).await, Err(ChessError::DeserializationError(_))));
+    }
+}
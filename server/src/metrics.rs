@@ -0,0 +1,147 @@
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use common::{ChessError, Command, Message};
+
+// Connection and game gauges are filled from `ServerState` on every scrape instead of being tracked by hand
+pub struct Metrics {
+    registry: Registry,
+    pub user_connections: IntGauge,
+    pub anon_user_connections: IntGauge,
+    pub active_games: IntGauge,
+    pub finished_games: IntGauge,
+    pub moves: IntCounter, // moves per second is `rate(chess_moves_total[1m])`
+    pub messages: IntCounterVec,
+    pub deserialization_errors: IntCounter,
+    pub rate_limited: IntCounter,
+    pub matchmaking_wait: Histogram, // from creating a game until the second player joins
+    pub command_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("chess".to_string()), None)
+            .expect("Invalid metrics prefix");
+
+        let user_connections = IntGauge::new("user_connections", "Logged in users").unwrap();
+        let anon_user_connections = IntGauge::new("anon_user_connections", "Connected users who haven't logged in yet").unwrap();
+        let active_games = IntGauge::new("active_games", "Games waiting for a player or in progress").unwrap();
+        let finished_games = IntGauge::new("finished_games", "Games finished since the server started").unwrap();
+        let moves = IntCounter::new("moves_total", "Moves played").unwrap();
+        let messages = IntCounterVec::new(Opts::new("messages_total", "Requests received, by message type"), &["type"]).unwrap();
+        let deserialization_errors = IntCounter::new("deserialization_errors_total", "Frames that couldn't be decoded").unwrap();
+        let rate_limited = IntCounter::new("rate_limited_total", "Requests refused by the rate limiter").unwrap();
+        let matchmaking_wait = Histogram::with_opts(HistogramOpts::new("matchmaking_wait_seconds", "Time until an opponent joins")
+            .buckets(vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0])).unwrap();
+        let command_latency = HistogramVec::new(HistogramOpts::new("command_duration_seconds", "Time to process a command"), &["command"]).unwrap();
+
+        registry.register(Box::new(user_connections.clone())).unwrap();
+        registry.register(Box::new(anon_user_connections.clone())).unwrap();
+        registry.register(Box::new(active_games.clone())).unwrap();
+        registry.register(Box::new(finished_games.clone())).unwrap();
+        registry.register(Box::new(moves.clone())).unwrap();
+        registry.register(Box::new(messages.clone())).unwrap();
+        registry.register(Box::new(deserialization_errors.clone())).unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry.register(Box::new(matchmaking_wait.clone())).unwrap();
+        registry.register(Box::new(command_latency.clone())).unwrap();
+
+        Self {
+            registry,
+            user_connections,
+            anon_user_connections,
+            active_games,
+            finished_games,
+            moves,
+            messages,
+            deserialization_errors,
+            rate_limited,
+            matchmaking_wait,
+            command_latency,
+        }
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> Result<String, ChessError> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| ChessError::SerializationError(e.to_string()))?;
+        String::from_utf8(buffer)
+            .map_err(|e| ChessError::SerializationError(e.to_string()))
+    }
+}
+
+pub fn message_type(message: &Message) -> &'static str {
+    match message {
+        Message::Command(_) => "command",
+        Message::Move(_) => "move",
+        Message::Text(_) => "text",
+        Message::Chat(_) => "chat",
+        Message::Board(_) => "board",
+        Message::GameStart(_) => "game_start",
+        Message::GameEnd(_) => "game_end",
+        Message::SearchResults(_) => "search_results",
+        Message::OpponentGone(_) => "opponent_gone",
+        Message::Error(_) => "error",
+        Message::Log(_) => "log",
+        Message::Hello(_) => "hello",
+        Message::Welcome(_) => "welcome",
+    }
+}
+
+pub fn command_name(command: &Command) -> &'static str {
+    match command {
+        Command::LogIn(_) => "log_in",
+        Command::Play => "play",
+        Command::Concede => "concede",
+        Command::ClaimWin => "claim_win",
+        Command::ClaimDraw => "claim_draw",
+        Command::Stats => "stats",
+        Command::Kick(_) => "kick",
+        Command::Ban(..) => "ban",
+        Command::Unban(_) => "unban",
+        Command::Mute(..) => "mute",
+        Command::Unmute(_) => "unmute",
+        Command::EndGame(_) => "end_game",
+        Command::Search(_) => "search",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prefixed_metrics() {
+        let metrics = Metrics::new();
+        metrics.moves.inc();
+        metrics.moves.inc();
+        metrics.active_games.set(3);
+        metrics.messages.with_label_values(&[message_type(&Message::Move("e2e4".to_string()))]).inc();
+        metrics.command_latency.with_label_values(&[command_name(&Command::Play)]).observe(0.01);
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains("chess_moves_total 2"), "{}", text);
+        assert!(text.contains("chess_active_games 3"), "{}", text);
+        assert!(text.contains("chess_messages_total{type=\"move\"} 1"), "{}", text);
+        assert!(text.contains("chess_command_duration_seconds_count{command=\"play\"} 1"), "{}", text);
+    }
+
+    #[test]
+    fn instances_do_not_share_counts() {
+        // Each server state, and each test, gets its own registry
+        let first = Metrics::new();
+        let second = Metrics::new();
+        first.rate_limited.inc();
+        assert_eq!(first.rate_limited.get(), 1);
+        assert_eq!(second.rate_limited.get(), 0);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(message_type(&Message::Command(Command::Concede)), "command");
+        assert_eq!(message_type(&Message::Text("hi".to_string())), "text");
+        assert_eq!(command_name(&Command::LogIn("alice".to_string())), "log_in");
+        assert_eq!(command_name(&Command::Ban("alice".to_string(), Some(5))), "ban");
+        assert_eq!(command_name(&Command::Search(common::SearchQuery::default())), "search");
+    }
+}
@@ -0,0 +1,350 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use log::{info, error};
+
+use common::{ChessError, Command, Envelope, Message, RequestId, make_io_error, unix_secs};
+
+use crate::chess_game::GameStatus;
+use crate::config::Config;
+use crate::{ServerState, finish_game, identify_addr_by_user, send_game_end, send_message, wait_for_delivery};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Player,
+    Moderator, // kicks, mutes, temporary bans and ending games
+    Admin, // everything, including permanent bans and unbans
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    pub username: String,
+    pub ip: Option<IpAddr>, // where the user was connected from when banned
+    pub until: Option<u64>, // unix seconds, permanent when missing
+    pub by: String,
+}
+
+impl Ban {
+    pub fn is_active(&self) -> bool {
+        self.until.is_none_or(|until| until > unix_secs())
+    }
+
+    pub fn describe(&self) -> String {
+        match self.until {
+            Some(until) => format!("for another {} minutes", until.saturating_sub(unix_secs()).div_ceil(60)),
+            None => "permanently".to_string(),
+        }
+    }
+}
+
+pub fn role_of(username: &str, config: &Config) -> Role {
+    if config.roles.admins.iter().any(|admin| admin == username) {
+        Role::Admin
+    } else if config.roles.moderators.iter().any(|moderator| moderator == username) {
+        Role::Moderator
+    } else {
+        Role::Player
+    }
+}
+
+// Kept as JSON since bans carry more than a name, expired ones are dropped on the next save
+pub async fn load_bans(ban_file: &str) -> Result<Vec<Ban>, ChessError> {
+    match tokio::fs::read_to_string(ban_file).await {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| ChessError::DeserializationError(format!("Failed to parse {}: {}", ban_file, e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(make_io_error(e, &format!("Failed to read ban file {}", ban_file))),
+    }
+}
+
+async fn save_bans(bans: &[Ban], server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let ban_file = &server_state.config.storage.ban_file;
+    let contents = serde_json::to_string_pretty(bans)
+        .map_err(|e| ChessError::SerializationError(e.to_string()))?;
+    tokio::fs::write(ban_file, contents).await
+        .map_err(|e| make_io_error(e, &format!("Failed to write to {}", ban_file)))
+}
+
+pub async fn find_ban(username: &str, ip: Option<IpAddr>, server_state: &Arc<ServerState>) -> Option<Ban> {
+    server_state.bans.lock().await.iter()
+        .find(|ban| ban.is_active() && (ban.username == username || (ip.is_some() && ban.ip == ip)))
+        .cloned()
+}
+
+pub async fn is_muted(username: &str, server_state: &Arc<ServerState>) -> bool {
+    let mut mutes = server_state.mutes.lock().await;
+    match mutes.get(username) {
+        Some(Some(until)) if *until <= Instant::now() => {
+            mutes.remove(username);
+            false
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+// Entry point for the in-protocol commands, the admin panel calls the actions below directly
+pub async fn process_moderation_command(command: Command, request_id: RequestId, moderator: &String, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let sender = server_state.user_connections.lock().await.get(moderator).cloned()
+        .ok_or(ChessError::UserNotFoundError)?;
+
+    let role = role_of(moderator, &server_state.config);
+    let target = match &command {
+        Command::Kick(target) | Command::Ban(target, _) | Command::Unban(target)
+        | Command::Mute(target, _) | Command::Unmute(target) | Command::EndGame(target) => target.clone(),
+        _ => unreachable!("Unexpected moderation command {command}"),
+    };
+    let denied = if role == Role::Player {
+        Some("Only moderators and admins can do that.".to_string())
+    } else if role_of(&target, &server_state.config) >= role {
+        Some(format!("You cannot moderate {}.", target))
+    } else if role != Role::Admin && matches!(command, Command::Ban(_, None) | Command::Unban(_)) {
+        Some("Only admins can ban permanently or lift bans.".to_string())
+    } else {
+        None
+    };
+    if let Some(denied) = denied {
+        send_message(moderator, Envelope::Response { id: request_id, message: Message::Error(denied.clone()) }, &sender).await?;
+        return Err(ChessError::UserStateError(denied));
+    }
+
+    let result = match command {
+        Command::Kick(_) => kick_user(&target, "You have been kicked by a moderator.", moderator, server_state).await
+            .map(|_| format!("{} has been kicked.", target)),
+        Command::Ban(_, minutes) => ban_user(&target, minutes, moderator, server_state).await
+            .map(|ban| format!("{} has been banned {}.", target, ban.describe())),
+        Command::Unban(_) => unban_user(&target, moderator, server_state).await
+            .map(|_| format!("{} has been unbanned.", target)),
+        Command::Mute(_, minutes) => mute_user(&target, minutes, moderator, server_state).await
+            .map(|_| format!("{} has been muted.", target)),
+        Command::Unmute(_) => unmute_user(&target, moderator, server_state).await
+            .map(|_| format!("{} has been unmuted.", target)),
+        Command::EndGame(_) => end_game_of(&target, moderator, server_state).await
+            .map(|game_id| format!("Game {} of {} has been ended.", game_id, target)),
+        _ => unreachable!(),
+    };
+
+    let message = match &result {
+        Ok(text) => Message::Log(text.clone()),
+        Err(e) => Message::Error(e.to_string()),
+    };
+    send_message(moderator, Envelope::Response { id: request_id, message }, &sender).await?;
+    result.map(|_| ())
+}
+
+// Tells the user why, then closes the connection; the usual disconnect handling takes it from there
+pub async fn kick_user(username: &str, reason: &str, actor: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let sender = server_state.user_connections.lock().await.get(username).cloned()
+        .ok_or(ChessError::UserNotFoundError)?;
+    let socket_addr = identify_addr_by_user(username, server_state).await
+        .ok_or(ChessError::UserNotFoundError)?;
+
+    let _ = sender.try_send(Envelope::Event(Message::Error(reason.to_string())));
+    wait_for_delivery(&[sender]).await;
+    if let Some(session) = server_state.client_sessions.lock().await.get(&socket_addr) {
+        session.kick.notify_one();
+    }
+    audit(actor, "kick", username, server_state).await;
+    Ok(())
+}
+
+pub async fn ban_user(username: &str, minutes: Option<u64>, actor: &str, server_state: &Arc<ServerState>) -> Result<Ban, ChessError> {
+    // Loopback is left out, otherwise banning a local account would lock out everyone on the machine
+    let ip = identify_addr_by_user(username, server_state).await
+        .map(|socket_addr| socket_addr.ip())
+        .filter(|ip| !ip.is_loopback());
+    let ban = Ban {
+        username: username.to_string(),
+        ip,
+        until: minutes.map(|minutes| unix_secs() + minutes * 60),
+        by: actor.to_string(),
+    };
+
+    {
+        let mut bans = server_state.bans.lock().await;
+        bans.retain(|existing| existing.is_active() && existing.username != username);
+        bans.push(ban.clone());
+        save_bans(&bans, server_state).await?;
+    }
+    audit(actor, "ban", &format!("{} ip={:?} {}", username, ip, ban.describe()), server_state).await;
+
+    if server_state.user_connections.lock().await.contains_key(username) {
+        kick_user(username, &format!("You have been banned from this server {}.", ban.describe()), actor, server_state).await?;
+    }
+    Ok(ban)
+}
+
+pub async fn unban_user(username: &str, actor: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    {
+        let mut bans = server_state.bans.lock().await;
+        let before = bans.len();
+        bans.retain(|ban| ban.is_active() && ban.username != username);
+        if bans.len() == before {
+            return Err(ChessError::UserStateError(format!("{} is not banned", username)));
+        }
+        save_bans(&bans, server_state).await?;
+    }
+    audit(actor, "unban", username, server_state).await;
+    Ok(())
+}
+
+pub async fn mute_user(username: &str, minutes: Option<u64>, actor: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let until = minutes.map(|minutes| Instant::now() + Duration::from_secs(minutes * 60));
+    server_state.mutes.lock().await.insert(username.to_string(), until);
+    audit(actor, "mute", &format!("{} minutes={:?}", username, minutes), server_state).await;
+
+    if let Some(sender) = server_state.user_connections.lock().await.get(username) {
+        let text = match minutes {
+            Some(minutes) => format!("You have been muted for {} minutes.", minutes),
+            None => "You have been muted.".to_string(),
+        };
+        let _ = sender.send(Envelope::Event(Message::Log(text))).await;
+    }
+    Ok(())
+}
+
+pub async fn unmute_user(username: &str, actor: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    if server_state.mutes.lock().await.remove(username).is_none() {
+        return Err(ChessError::UserStateError(format!("{} is not muted", username)));
+    }
+    audit(actor, "unmute", username, server_state).await;
+
+    if let Some(sender) = server_state.user_connections.lock().await.get(username) {
+        let _ = sender.send(Envelope::Event(Message::Log("You can chat again.".to_string()))).await;
+    }
+    Ok(())
+}
+
+async fn end_game_of(username: &str, actor: &str, server_state: &Arc<ServerState>) -> Result<u32, ChessError> {
+    let game_id = server_state.user_to_game.lock().await.get(username).cloned()
+        .ok_or(ChessError::GameStateError(format!("{} is not in a game", username)))?;
+    end_game(game_id, actor, server_state).await?;
+    Ok(game_id)
+}
+
+pub async fn end_game(game_id: u32, actor: &str, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let game_arc = server_state.games.lock().await.get(&game_id).cloned()
+        .ok_or(ChessError::GameStateError(format!("Game {} not found", game_id)))?;
+    let players: Vec<String> = {
+        let mut game = game_arc.lock().await;
+        if !matches!(game.status, GameStatus::Pending | GameStatus::InProgress) {
+            return Err(ChessError::GameStateError(format!("Game {} is already over", game_id)));
+        }
+        game.abort();
+        [game.white.clone(), game.black.clone()].into_iter().flatten().collect()
+    };
+    finish_game(game_id, server_state).await;
+
+    for player in &players {
+        server_state.disconnected_players.lock().await.remove(player);
+        if let Some(sender) = server_state.user_connections.lock().await.get(player) {
+            let _ = sender.send(Envelope::Event(Message::Log("Your game was ended by a moderator.".to_string()))).await;
+        }
+        let _ = send_game_end(player, &*game_arc.lock().await, "aborted", server_state).await;
+    }
+    audit(actor, "end_game", &game_id.to_string(), server_state).await;
+    Ok(())
+}
+
+// One line per action: unix seconds, who, what, details
+async fn audit(actor: &str, action: &str, details: &str, server_state: &Arc<ServerState>) {
+    info!("{} {}: {}", actor, action, details);
+
+    let audit_log = &server_state.config.storage.audit_log;
+    let line = format!("{} {} {} {}\n", unix_secs(), actor, action, details);
+    let written = match tokio::fs::OpenOptions::new().create(true).append(true).open(audit_log).await {
+        Ok(mut file) => file.write_all(line.as_bytes()).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        error!("Failed to write to audit log {}: {}", audit_log, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::test_state;
+
+    fn ban(until: Option<u64>) -> Ban {
+        Ban { username: "mallory".to_string(), ip: None, until, by: "admin".to_string() }
+    }
+
+    #[test]
+    fn bans_expire() {
+        assert!(ban(None).is_active());
+        assert!(ban(Some(unix_secs() + 60)).is_active());
+        assert!(!ban(Some(unix_secs())).is_active());
+        assert!(!ban(Some(unix_secs() - 60)).is_active());
+    }
+
+    #[test]
+    fn ban_descriptions_round_up() {
+        assert_eq!(ban(None).describe(), "permanently");
+        assert_eq!(ban(Some(unix_secs() + 61)).describe(), "for another 2 minutes");
+        assert_eq!(ban(Some(unix_secs() - 60)).describe(), "for another 0 minutes");
+    }
+
+    #[test]
+    fn roles() {
+        let mut config = Config::default();
+        config.roles.admins = vec!["alice".to_string()];
+        config.roles.moderators = vec!["bob".to_string(), "alice".to_string()];
+        assert_eq!(role_of("alice", &config), Role::Admin);
+        assert_eq!(role_of("bob", &config), Role::Moderator);
+        assert_eq!(role_of("carol", &config), Role::Player);
+    }
+
+    #[tokio::test]
+    async fn bans_are_saved_and_lifted() {
+        let server_state = test_state(Config::default()).await;
+        ban_user("mallory", Some(10), "admin", &server_state).await.unwrap();
+        assert!(find_ban("mallory", None, &server_state).await.is_some());
+        assert!(find_ban("alice", None, &server_state).await.is_none());
+
+        let saved = load_bans(&server_state.config.storage.ban_file).await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].by, "admin");
+
+        unban_user("mallory", "admin", &server_state).await.unwrap();
+        assert!(find_ban("mallory", None, &server_state).await.is_none());
+        assert!(load_bans(&server_state.config.storage.ban_file).await.unwrap().is_empty());
+        assert!(unban_user("mallory", "admin", &server_state).await.is_err());
+
+        let audit_log = std::fs::read_to_string(&server_state.config.storage.audit_log).unwrap();
+        let actions: Vec<&str> = audit_log.lines().map(|line| line.split(' ').nth(2).unwrap()).collect();
+        assert_eq!(actions, ["ban", "unban"]);
+    }
+
+    #[tokio::test]
+    async fn ip_bans_and_expired_bans() {
+        let server_state = test_state(Config::default()).await;
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        server_state.bans.lock().await.push(Ban { ip: Some(ip), ..ban(None) });
+        server_state.bans.lock().await.push(Ban { username: "eve".to_string(), ..ban(Some(unix_secs() - 1)) });
+
+        // A new name from the same address is still banned
+        assert!(find_ban("mallory2", Some(ip), &server_state).await.is_some());
+        assert!(find_ban("mallory2", Some("203.0.113.8".parse().unwrap()), &server_state).await.is_none());
+        assert!(find_ban("eve", None, &server_state).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mutes_expire() {
+        let server_state = test_state(Config::default()).await;
+        mute_user("mallory", None, "admin", &server_state).await.unwrap();
+        assert!(is_muted("mallory", &server_state).await);
+
+        mute_user("eve", Some(0), "admin", &server_state).await.unwrap();
+        assert!(!is_muted("eve", &server_state).await);
+        // Expired mutes are forgotten, so there is nothing left to lift
+        assert!(unmute_user("eve", "admin", &server_state).await.is_err());
+
+        unmute_user("mallory", "admin", &server_state).await.unwrap();
+        assert!(!is_muted("mallory", &server_state).await);
+    }
+}
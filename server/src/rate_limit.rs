@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+use crate::config::LimitsConfig;
+
+// Holds up to `burst` tokens and refills `per_second` of them, every request takes one
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self {
+            capacity: burst as f64,
+            tokens: burst as f64,
+            per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Limited, // drop this request
+    Mute, // drop it and mute the user for a while
+    Disconnect, // drop it and close the connection
+}
+
+// One per connection: every request goes through `requests`, chat also through the stricter `chat` bucket.
+// Dropped requests are strikes; enough of them within the window escalate to a mute, then a disconnect.
+pub struct RateLimiter {
+    requests: TokenBucket,
+    chat: TokenBucket,
+    strikes: u32,
+    last_strike: Instant,
+    muted: bool, // already muted in this flood, chat that doesn't hit the mute exactly still gets it once
+    flood_window: Duration,
+    mute_strikes: u32,
+    disconnect_strikes: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &LimitsConfig) -> Self {
+        Self {
+            requests: TokenBucket::new(limits.requests_per_second, limits.request_burst),
+            chat: TokenBucket::new(limits.chat_per_second, limits.chat_burst),
+            strikes: 0,
+            last_strike: Instant::now(),
+            muted: false,
+            flood_window: Duration::from_secs(limits.flood_window_secs),
+            mute_strikes: limits.flood_mute_strikes,
+            disconnect_strikes: limits.flood_disconnect_strikes,
+        }
+    }
+
+    pub fn check(&mut self, is_chat: bool) -> Verdict {
+        // Both buckets are charged, so chat also counts towards the overall rate
+        let allowed = self.requests.try_take() & (!is_chat || self.chat.try_take());
+        if allowed {
+            return Verdict::Allowed;
+        }
+
+        if self.last_strike.elapsed() > self.flood_window {
+            self.strikes = 0;
+            self.muted = false;
+        }
+        self.strikes += 1;
+        self.last_strike = Instant::now();
+
+        if self.strikes >= self.disconnect_strikes {
+            Verdict::Disconnect
+        } else if is_chat && !self.muted && self.strikes >= self.mute_strikes {
+            self.muted = true;
+            Verdict::Mute
+        } else {
+            Verdict::Limited
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> LimitsConfig {
+        LimitsConfig {
+            requests_per_second: 2.0,
+            request_burst: 10,
+            chat_per_second: 1.0,
+            chat_burst: 1,
+            flood_window_secs: 60,
+            flood_mute_strikes: 3,
+            flood_disconnect_strikes: 6,
+            ..LimitsConfig::default()
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let mut bucket = TokenBucket::new(2.0, 3);
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+
+        // Half a second at two per second is one more request
+        bucket.last_refill -= Duration::from_millis(500);
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_the_burst() {
+        let mut bucket = TokenBucket::new(2.0, 3);
+        bucket.last_refill -= Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.try_take()).count(), 3);
+    }
+
+    #[test]
+    fn chat_floods_are_limited_then_muted_then_disconnected() {
+        let mut limiter = RateLimiter::new(&limits());
+        assert_eq!(limiter.check(true), Verdict::Allowed);
+        let verdicts: Vec<Verdict> = (0..6).map(|_| limiter.check(true)).collect();
+        assert_eq!(verdicts, [Verdict::Limited, Verdict::Limited, Verdict::Mute, Verdict::Limited, Verdict::Limited, Verdict::Disconnect]);
+    }
+
+    #[test]
+    fn mute_reached_by_other_requests_still_fires_on_chat() {
+        let mut limiter = RateLimiter::new(&LimitsConfig { request_burst: 1, ..limits() });
+        assert_eq!(limiter.check(false), Verdict::Allowed);
+        // Strikes past the mute threshold, none of them chat
+        for _ in 0..4 {
+            assert_eq!(limiter.check(false), Verdict::Limited);
+        }
+        assert_eq!(limiter.check(true), Verdict::Mute);
+        assert_eq!(limiter.check(false), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn strikes_are_forgotten_after_the_window() {
+        let mut limiter = RateLimiter::new(&limits());
+        limiter.check(true);
+        for _ in 0..3 {
+            limiter.check(true);
+        }
+        limiter.last_strike -= Duration::from_secs(61);
+        assert_eq!(limiter.check(true), Verdict::Limited);
+        assert_eq!(limiter.check(true), Verdict::Limited);
+        // A new flood is muted again
+        assert_eq!(limiter.check(true), Verdict::Mute);
+    }
+
+    #[test]
+    fn other_requests_are_not_charged_for_chat() {
+        let mut limiter = RateLimiter::new(&limits());
+        assert_eq!(limiter.check(true), Verdict::Allowed);
+        assert_eq!(limiter.check(true), Verdict::Limited);
+        assert_eq!(limiter.check(false), Verdict::Allowed);
+    }
+}
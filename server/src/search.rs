@@ -0,0 +1,301 @@
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+
+use common::{ArchivedGame, ChessError, SearchQuery, SearchResults, make_io_error};
+
+use crate::ServerState;
+use crate::chess_game::{Game, TIME_CONTROL, VARIANT};
+use crate::eco::classify;
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// The archive file is append-only JSON lines, one finished game each, so a crash loses at most the line being written
+pub async fn load_archive(archive_file: &str) -> Result<Vec<ArchivedGame>, ChessError> {
+    let contents = match tokio::fs::read_to_string(archive_file).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(make_io_error(e, &format!("Failed to read archive {}", archive_file))),
+    };
+    contents.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line)
+            .map_err(|e| ChessError::DeserializationError(format!("Failed to parse {}: {}", archive_file, e))))
+        .collect()
+}
+
+// Called for every game that ends, games nobody joined are left out
+pub async fn archive_game(game_id: u32, game: &Game, server_state: &Arc<ServerState>) -> Result<(), ChessError> {
+    let (Some(white), Some(black)) = (game.white.clone(), game.black.clone()) else {
+        return Ok(());
+    };
+    let (eco, opening) = classify(&game.moves);
+    let archived = ArchivedGame {
+        game_id,
+        white,
+        black,
+        result: game.pgn_result().to_string(),
+        reason: game.end_reason().to_string(),
+        finished_at: game.finished_at.unwrap_or(0),
+        eco: eco.to_string(),
+        opening: opening.to_string(),
+        variant: VARIANT.to_string(),
+        time_control: TIME_CONTROL.to_string(),
+        moves: game.moves.len(),
+        pgn: Some(game.to_pgn(game_id)?),
+    };
+    let mut line = serde_json::to_string(&archived)
+        .map_err(|e| ChessError::SerializationError(e.to_string()))?;
+    line.push('\n');
+
+    // Held while writing, so lines from two games ending at once don't interleave
+    let mut archive = server_state.archive.lock().await;
+    let archive_file = &server_state.config.storage.archive_file;
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(archive_file).await
+        .map_err(|e| make_io_error(e, &format!("Failed to open archive {}", archive_file)))?;
+    file.write_all(line.as_bytes()).await
+        .map_err(|e| make_io_error(e, &format!("Failed to write to archive {}", archive_file)))?;
+    archive.push(archived);
+    Ok(())
+}
+
+// Finished games that match `query`, most recent first, one page of them
+pub async fn search(query: &SearchQuery, server_state: &Arc<ServerState>) -> Result<SearchResults, ChessError> {
+    let from = query.from.as_deref().map(parse_date).transpose()?;
+    let to = query.to.as_deref().map(parse_date).transpose()?;
+    if query.player.is_none() && matches!(query.result.as_deref(), Some("win") | Some("loss") | Some("draw")) {
+        return Err(ChessError::MessageHandlingError("Searching for wins, losses or draws needs a player".to_string()));
+    }
+    let page_size = match query.page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => page_size.min(MAX_PAGE_SIZE),
+    };
+
+    let mut matches: Vec<ArchivedGame> = server_state.archive.lock().await.iter()
+        .filter(|game| matches(query, game, from, to))
+        .cloned()
+        .collect();
+    matches.sort_by(|a, b| b.finished_at.cmp(&a.finished_at).then(b.game_id.cmp(&a.game_id)));
+    let total = matches.len();
+    let games = matches.into_iter()
+        .skip(query.page as usize * page_size as usize)
+        .take(page_size as usize)
+        .map(|game| ArchivedGame { pgn: game.pgn.filter(|_| query.pgn), ..game })
+        .collect();
+    Ok(SearchResults { games, total, page: query.page, page_size })
+}
+
+// `from` and `to` are days since the Unix epoch, both included
+fn matches(query: &SearchQuery, game: &ArchivedGame, from: Option<u64>, to: Option<u64>) -> bool {
+    let (white, black, result) = (&game.white, &game.black, game.result.as_str());
+    let day = game.finished_at / SECS_PER_DAY;
+
+    // Which side `player` played, if it was asked for
+    let player_side = query.player.as_ref().map(|player| (player == white, player == black));
+    let players_match = !matches!(player_side, Some((false, false)))
+        && query.opponent.as_ref().is_none_or(|opponent| match player_side {
+            Some((true, _)) => opponent == black,
+            Some((_, true)) => opponent == white,
+            _ => opponent == white || opponent == black,
+        });
+    let result_matches = query.result.as_deref().is_none_or(|wanted| match (wanted, player_side) {
+        ("win", Some((true, _))) | ("loss", Some((_, true))) => result == "1-0",
+        ("win", Some((_, true))) | ("loss", Some((true, _))) => result == "0-1",
+        ("draw", _) => result == "1/2-1/2",
+        (wanted, _) => wanted == result,
+    });
+    players_match
+        && result_matches
+        && from.is_none_or(|from| day >= from)
+        && to.is_none_or(|to| day <= to)
+        && query.eco.as_deref().is_none_or(|wanted| game.eco.starts_with(&wanted.to_uppercase()))
+        && query.variant.as_deref().is_none_or(|variant| variant.eq_ignore_ascii_case(&game.variant))
+        && query.time_control.as_deref().is_none_or(|time_control| time_control == game.time_control)
+}
+
+// Days since the Unix epoch of a `YYYY-MM-DD` date
+fn parse_date(date: &str) -> Result<u64, ChessError> {
+    let invalid = || ChessError::MessageHandlingError(format!("Invalid date {}, expected YYYY-MM-DD", date));
+    let parts: Vec<i64> = date.split('-').map(|part| part.parse::<i64>()).collect::<Result<_, _>>().map_err(|_| invalid())?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return Err(invalid());
+    }
+
+    // Howard Hinnant's `days_from_civil`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok((era * 146_097 + day_of_era - 719_468) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ServerState;
+    use crate::chess_game::GameSnapshot;
+    use crate::config::Config;
+    use crate::tests::test_state;
+
+    const DAY: u64 = SECS_PER_DAY;
+
+    fn archived(game_id: u32, white: &str, black: &str, result: &str, day: u64, eco: &str) -> ArchivedGame {
+        ArchivedGame {
+            game_id,
+            white: white.to_string(),
+            black: black.to_string(),
+            result: result.to_string(),
+            reason: "resignation".to_string(),
+            finished_at: day * DAY + 3600,
+            eco: eco.to_string(),
+            opening: String::new(),
+            variant: VARIANT.to_string(),
+            time_control: TIME_CONTROL.to_string(),
+            moves: 10,
+            pgn: Some(format!("[Round \"{}\"]", game_id)),
+        }
+    }
+
+    fn query(fill: impl FnOnce(&mut SearchQuery)) -> SearchQuery {
+        let mut query = SearchQuery::default();
+        fill(&mut query);
+        query
+    }
+
+    fn found(query: &SearchQuery, games: &[ArchivedGame]) -> Vec<u32> {
+        let from = query.from.as_deref().map(parse_date).transpose().unwrap();
+        let to = query.to.as_deref().map(parse_date).transpose().unwrap();
+        games.iter().filter(|game| matches(query, game, from, to)).map(|game| game.game_id).collect()
+    }
+
+    fn games() -> Vec<ArchivedGame> {
+        vec![
+            archived(1, "alice", "bob", "1-0", 19_000, "C60"),
+            archived(2, "bob", "alice", "1-0", 19_001, "B20"),
+            archived(3, "alice", "carol", "1/2-1/2", 19_002, "C42"),
+            archived(4, "bob", "carol", "0-1", 19_003, "D06"),
+        ]
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 11_017);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 19_782);
+        for invalid in ["1969-12-31", "2024-13-01", "2024-00-10", "2024-01-32", "2024-01", "2024-01-01-01", "yesterday", ""] {
+            assert!(matches!(parse_date(invalid), Err(ChessError::MessageHandlingError(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn players_and_opponents() {
+        let games = games();
+        assert_eq!(found(&query(|q| q.player = Some("alice".to_string())), &games), [1, 2, 3]);
+        assert_eq!(found(&query(|q| q.opponent = Some("carol".to_string())), &games), [3, 4]);
+        assert_eq!(found(&query(|q| {
+            q.player = Some("alice".to_string());
+            q.opponent = Some("bob".to_string());
+        }), &games), [1, 2]);
+        assert!(found(&query(|q| q.player = Some("dave".to_string())), &games).is_empty());
+    }
+
+    #[test]
+    fn results_as_seen_by_the_player() {
+        let games = games();
+        let for_alice = |result: &str| query(|q| {
+            q.player = Some("alice".to_string());
+            q.result = Some(result.to_string());
+        });
+        assert_eq!(found(&for_alice("win"), &games), [1]);
+        assert_eq!(found(&for_alice("loss"), &games), [2]);
+        assert_eq!(found(&for_alice("draw"), &games), [3]);
+        assert_eq!(found(&query(|q| q.result = Some("1-0".to_string())), &games), [1, 2]);
+    }
+
+    #[test]
+    fn dates_openings_and_variants() {
+        let games = games();
+        // Day 19001 is 2022-01-09
+        assert_eq!(found(&query(|q| q.from = Some("2022-01-09".to_string())), &games), [2, 3, 4]);
+        assert_eq!(found(&query(|q| q.to = Some("2022-01-09".to_string())), &games), [1, 2]);
+        assert_eq!(found(&query(|q| q.eco = Some("c".to_string())), &games), [1, 3]);
+        assert_eq!(found(&query(|q| q.eco = Some("C6".to_string())), &games), [1]);
+        assert_eq!(found(&query(|q| q.variant = Some("STANDARD".to_string())), &games).len(), 4);
+        assert!(found(&query(|q| q.variant = Some("chess960".to_string())), &games).is_empty());
+        assert!(found(&query(|q| q.time_control = Some("300+5".to_string())), &games).is_empty());
+    }
+
+    #[tokio::test]
+    async fn pages_come_most_recent_first() {
+        let server_state = test_state(Config::default()).await;
+        server_state.archive.lock().await.extend(games());
+
+        let first = search(&query(|q| q.page_size = 3), &server_state).await.unwrap();
+        assert_eq!(first.total, 4);
+        assert_eq!(first.games.iter().map(|game| game.game_id).collect::<Vec<_>>(), [4, 3, 2]);
+        assert!(first.games.iter().all(|game| game.pgn.is_none()), "PGN only when asked for");
+
+        let second = search(&query(|q| {
+            q.page_size = 3;
+            q.page = 1;
+            q.pgn = true;
+        }), &server_state).await.unwrap();
+        assert_eq!(second.games.len(), 1);
+        assert_eq!(second.games[0].pgn.as_deref(), Some("[Round \"1\"]"));
+
+        assert_eq!(search(&SearchQuery::default(), &server_state).await.unwrap().page_size, DEFAULT_PAGE_SIZE);
+        assert_eq!(search(&query(|q| q.page_size = 1000), &server_state).await.unwrap().page_size, MAX_PAGE_SIZE);
+        assert!(search(&query(|q| q.result = Some("win".to_string())), &server_state).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn archive_is_read_back_on_start() {
+        let server_state = test_state(Config::default()).await;
+        let lines: Vec<String> = games().iter().map(|game| serde_json::to_string(game).unwrap()).collect();
+        std::fs::write(&server_state.config.storage.archive_file, lines.join("\n") + "\n\n").unwrap();
+
+        let restarted = ServerState::new(server_state.config.clone()).await.unwrap();
+        assert_eq!(restarted.archive.lock().await.len(), 4);
+        // New games don't reuse archived ids
+        assert_eq!(restarted.last_game_id.load(std::sync::atomic::Ordering::SeqCst), 5);
+
+        std::fs::write(&server_state.config.storage.archive_file, "{not json\n").unwrap();
+        assert!(load_archive(&server_state.config.storage.archive_file).await.is_err());
+        assert!(load_archive("no-such-archive.jsonl").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finished_games_are_archived() {
+        let server_state = test_state(Config::default()).await;
+        let mut game = Game::restore(GameSnapshot {
+            game_id: 7,
+            white: Some("alice".to_string()),
+            black: Some("bob".to_string()),
+            board: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1".to_string(),
+            moves: Vec::new(),
+            chat: Vec::new(),
+        }).unwrap();
+        game.make_move("e2e4").unwrap();
+        game.concede(&"alice".to_string()).unwrap();
+        archive_game(7, &game, &server_state).await.unwrap();
+
+        let archive = load_archive(&server_state.config.storage.archive_file).await.unwrap();
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].eco, "B00");
+        assert!(archive[0].pgn.as_deref().is_some_and(|pgn| pgn.contains("1. e4")));
+        assert_eq!(server_state.archive.lock().await.len(), 1);
+
+        // Nobody joined, nothing to archive
+        let mut empty = Game::new();
+        empty.abort();
+        archive_game(8, &empty, &server_state).await.unwrap();
+        assert_eq!(server_state.archive.lock().await.len(), 1);
+    }
+}
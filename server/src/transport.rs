@@ -0,0 +1,260 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader as StdBufReader;
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::{SplitSink, SplitStream};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncBufReadExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use log::info;
+
+use common::{ChessError, make_io_error};
+use common::codec::{Codec, FrameReader, FrameWriter};
+
+// Plain TCP or TLS, the rest of the server doesn't care which
+pub trait ClientIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientIo for T {}
+pub type ClientStream = Box<dyn ClientIo>;
+
+type WsStream = WebSocketStream<ClientStream>;
+
+// Both transports carry the same `Message`s and `Envelope`s, only the framing differs:
+// TCP uses a 4-byte length prefix with CBOR or JSON lines, WebSocket uses binary frames with CBOR or text frames with JSON.
+pub enum ClientReader {
+    Tcp(FrameReader<BufReader<ReadHalf<ClientStream>>>),
+    WebSocket { stream: SplitStream<WsStream>, json: bool },
+}
+
+pub enum ClientWriter {
+    Tcp(FrameWriter<WriteHalf<ClientStream>>),
+    WebSocket { sink: SplitSink<WsStream, WsMessage>, json: bool },
+}
+
+pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, ChessError> {
+    let cert_file = File::open(cert_path)
+        .map_err(|e| make_io_error(e, &format!("Failed to open TLS certificate {}", cert_path)))?;
+    let certs = rustls_pemfile::certs(&mut StdBufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| make_io_error(e, &format!("Failed to parse TLS certificate {}", cert_path)))?;
+
+    let key_file = File::open(key_path)
+        .map_err(|e| make_io_error(e, &format!("Failed to open TLS key {}", key_path)))?;
+    let key = rustls_pemfile::private_key(&mut StdBufReader::new(key_file))
+        .map_err(|e| make_io_error(e, &format!("Failed to parse TLS key {}", key_path)))?
+        .ok_or(ChessError::TlsError(format!("No private key found in {}", key_path)))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| ChessError::TlsError(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn secure(socket: TcpStream, tls_acceptor: Option<&TlsAcceptor>) -> Result<ClientStream, ChessError> {
+    match tls_acceptor {
+        Some(tls_acceptor) => {
+            let stream = tls_acceptor.accept(socket).await
+                .map_err(|e| ChessError::TlsError(format!("TLS handshake failed: {}", e)))?;
+            Ok(Box::new(stream))
+        }
+        None => Ok(Box::new(socket)),
+    }
+}
+
+// The codec is picked per connection from the first byte the client sends:
+// a JSON document starts with `{`, while a CBOR frame starts with its length prefix.
+pub async fn accept_tcp(stream: ClientStream, max_message_len: usize) -> Result<(ClientReader, ClientWriter), ChessError> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    let first_byte = reader.fill_buf().await
+        .map_err(|e| make_io_error(e, "Failed to read the first message"))?
+        .first()
+        .copied();
+
+    let codec = if first_byte == Some(b'{') { Codec::JsonLines } else { Codec::Cbor };
+    info!("Client speaks {:?}", codec);
+
+    Ok((ClientReader::Tcp(FrameReader::with_max_frame_len(reader, codec, max_message_len)),
+        ClientWriter::Tcp(FrameWriter::with_max_frame_len(writer, codec, max_message_len))))
+}
+
+pub async fn accept_websocket(stream: ClientStream, max_message_len: usize) -> Result<(ClientReader, ClientWriter), ChessError> {
+    let config = WebSocketConfig {
+        max_message_size: Some(max_message_len),
+        max_frame_size: Some(max_message_len),
+        ..WebSocketConfig::default()
+    };
+    let websocket = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await
+        .map_err(|e| ChessError::NetworkError(format!("WebSocket handshake failed: {}", e)))?;
+    let (sink, stream) = websocket.split();
+    Ok((ClientReader::WebSocket { stream, json: false }, ClientWriter::WebSocket { sink, json: false }))
+}
+
+impl ClientReader {
+    pub async fn read<T: DeserializeOwned + fmt::Debug>(&mut self) -> Result<T, ChessError> {
+        match self {
+            ClientReader::Tcp(reader) => reader.read().await,
+            ClientReader::WebSocket { stream, json } => loop {
+                let frame = stream.next().await
+                    .ok_or(ChessError::NetworkError("WebSocket closed".to_string()))?
+                    .map_err(|e| ChessError::NetworkError(e.to_string()))?;
+
+                let message = match frame {
+                    WsMessage::Binary(bytes) => {
+                        *json = false;
+                        Codec::Cbor.decode(&bytes)?
+                    }
+                    WsMessage::Text(text) => {
+                        *json = true;
+                        Codec::JsonLines.decode(text.as_bytes())?
+                    }
+                    WsMessage::Close(_) => return Err(ChessError::NetworkError("WebSocket closed".to_string())),
+                    _ => continue, // WebSocket pings are answered by tungstenite itself
+                };
+                info!("Received message: {:?}", message);
+                return Ok(message);
+            },
+        }
+    }
+
+    // Whether the client sends JSON text frames, replies should use the same format
+    pub fn uses_json(&self) -> bool {
+        match self {
+            ClientReader::Tcp(reader) => reader.codec() == Codec::JsonLines,
+            ClientReader::WebSocket { json, .. } => *json,
+        }
+    }
+}
+
+impl ClientWriter {
+    pub async fn write<T: Serialize>(&mut self, message: &T) -> Result<(), ChessError> {
+        match self {
+            ClientWriter::Tcp(writer) => writer.write(message).await?,
+            ClientWriter::WebSocket { sink, json } => {
+                let frame = if *json {
+                    let bytes = Codec::JsonLines.encode(message)?;
+                    WsMessage::Text(String::from_utf8(bytes)
+                        .map_err(|e| ChessError::SerializationError(e.to_string()))?)
+                } else {
+                    WsMessage::Binary(Codec::Cbor.encode(message)?)
+                };
+                sink.send(frame).await
+                    .map_err(|e| ChessError::NetworkError(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn use_json(&mut self, use_json: bool) {
+        if let ClientWriter::WebSocket { json, .. } = self {
+            *json = use_json;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Envelope, MAX_MESSAGE_LEN, Message};
+
+    // The client side writes one `Ping`, the server side sniffs the codec from it
+    async fn sniff(codec: Codec) -> (ClientReader, Envelope) {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut writer = FrameWriter::new(client, codec);
+        writer.write(&Envelope::Ping(42)).await.unwrap();
+
+        let (mut reader, _writer) = accept_tcp(Box::new(server), MAX_MESSAGE_LEN).await.unwrap();
+        let envelope = reader.read().await.unwrap();
+        (reader, envelope)
+    }
+
+    #[tokio::test]
+    async fn brace_means_json_lines() {
+        let (reader, envelope) = sniff(Codec::JsonLines).await;
+        assert!(reader.uses_json());
+        assert!(matches!(envelope, Envelope::Ping(42)));
+    }
+
+    #[tokio::test]
+    async fn length_prefix_means_cbor() {
+        let (reader, envelope) = sniff(Codec::Cbor).await;
+        assert!(!reader.uses_json());
+        assert!(matches!(envelope, Envelope::Ping(42)));
+    }
+
+    #[tokio::test]
+    async fn replies_use_the_sniffed_codec() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client_read, client_write) = tokio::io::split(client);
+        let mut client_writer = FrameWriter::new(client_write, Codec::JsonLines);
+        client_writer.write(&Envelope::Ping(1)).await.unwrap();
+
+        let (mut reader, mut writer) = accept_tcp(Box::new(server), MAX_MESSAGE_LEN).await.unwrap();
+        let _: Envelope = reader.read().await.unwrap();
+        writer.write(&Envelope::Event(Message::Log("hi".to_string()))).await.unwrap();
+
+        // Raw bytes, so a CBOR reply would fail to parse as a JSON line
+        let mut client_reader = FrameReader::new(client_read, Codec::JsonLines);
+        let reply: Envelope = client_reader.read().await.unwrap();
+        assert!(matches!(reply, Envelope::Event(Message::Log(text)) if text == "hi"));
+    }
+
+    // A WebSocket client on the other end of an in-memory pipe, and the server's side of it
+    async fn websocket_pair() -> (WebSocketStream<tokio::io::DuplexStream>, ClientReader, ClientWriter) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::client_async("ws://localhost/", client),
+            accept_websocket(Box::new(server), MAX_MESSAGE_LEN),
+        );
+        let (client, _) = client.unwrap();
+        let (reader, writer) = server.unwrap();
+        (client, reader, writer)
+    }
+
+    #[tokio::test]
+    async fn websocket_binary_frames_are_cbor() {
+        let (mut client, mut reader, mut writer) = websocket_pair().await;
+        client.send(WsMessage::Binary(Codec::Cbor.encode(&Envelope::Ping(7)).unwrap())).await.unwrap();
+        assert!(matches!(reader.read().await.unwrap(), Envelope::Ping(7)));
+        assert!(!reader.uses_json());
+
+        writer.use_json(reader.uses_json());
+        writer.write(&Envelope::Pong(7)).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            WsMessage::Binary(bytes) => assert!(matches!(Codec::Cbor.decode(&bytes).unwrap(), Envelope::Pong(7))),
+            other => panic!("Expected a binary frame, received {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_text_frames_are_json() {
+        let (mut client, mut reader, mut writer) = websocket_pair().await;
+        client.send(WsMessage::Text("{\"Ping\":8}".to_string())).await.unwrap();
+        assert!(matches!(reader.read().await.unwrap(), Envelope::Ping(8)));
+        assert!(reader.uses_json());
+
+        writer.use_json(reader.uses_json());
+        writer.write(&Envelope::Pong(8)).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            WsMessage::Text(text) => assert_eq!(text, "{\"Pong\":8}"),
+            other => panic!("Expected a text frame, received {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_close_ends_reading() {
+        let (mut client, mut reader, _writer) = websocket_pair().await;
+        client.close(None).await.unwrap();
+        let closed: Result<Envelope, _> = reader.read().await;
+        assert!(matches!(closed, Err(ChessError::NetworkError(_))));
+    }
+}